@@ -0,0 +1,266 @@
+//! Places the eye so that it sees what's interesting about a scene.
+
+use super::vec::{Vector, RFloat};
+use super::primitive::Aabb;
+use super::transform::Transform;
+
+/// How image positions map to the directions of primary rays
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Projection {
+    /// Through a flat image plane, see `Camera::fov`
+    Perspective,
+    /// The image x maps to the longitude and y to the latitude, covering all directions
+    /// around the eye. The image center looks forward, its left and right edges backward.
+    Equirectangular,
+    /// All rays look forward and start on the image plane around the eye, which shows
+    /// `scale` units of the scene along the shorter side of the image. Things don't
+    /// shrink with their distance, which suits technical drawings.
+    Orthographic { scale: RFloat },
+}
+
+/// Which way the camera looks, with x pointing right and y up in the image
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub enum Handedness {
+    /// The camera looks along +z, like in DirectX or Unity
+    #[default]
+    Left,
+    /// The camera looks along -z, like in OpenGL or Blender's camera space
+    Right,
+}
+
+impl Handedness {
+    /// The direction an unrotated camera looks into
+    pub fn forward(&self) -> Vector {
+        Vector {
+            z: match *self {
+                Handedness::Left => 1.0,
+                Handedness::Right => -1.0,
+            },
+            ..Vector::ZERO
+        }
+    }
+}
+
+/// Where the eye is, where it looks and how much it sees.
+/// Scenes can be rendered from any camera, see `RenderOptions::camera`.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Camera {
+    pub eye: Vector,
+    /// The point in the center of the image
+    pub look_at: Vector,
+    /// The direction which is up in the image. It must not be parallel to the direction
+    /// we look into, but doesn't need to be perpendicular to it.
+    pub up: Vector,
+    /// The field of view of the perspective projection in radians, along the shorter
+    /// side of the image
+    pub fov: RFloat,
+    pub projection: Projection,
+    /// The radius of the lens. Rays start anywhere on it, which blurs everything not at the
+    /// focus distance. 0 is a pinhole, which keeps everything sharp.
+    pub aperture: RFloat,
+    /// How far the sharp plane is in front of the eye, along the direction it looks into
+    pub focus_distance: RFloat,
+}
+
+impl Default for Camera {
+    /// The camera of the default scene, looking at the origin along +z
+    fn default() -> Camera {
+        Camera {
+            eye: Vector {
+                z: -4.0,
+                ..Vector::ZERO
+            },
+            look_at: Vector::ZERO,
+            up: Vector {
+                y: 1.0,
+                ..Vector::ZERO
+            },
+            // The image plane is as far away as the shorter image side is long
+            fov: 2.0 * (0.5 as RFloat).atan(),
+            projection: Projection::Perspective,
+            aperture: 0.0,
+            focus_distance: 4.0,
+        }
+    }
+}
+
+impl Camera {
+    /// Returns a camera looking at the center of bounds along +z, just far enough away
+    /// for the sphere around the whole box to fit into the given field of view.
+    pub fn fit(bounds: &Aabb, fov: RFloat) -> Camera {
+        let radius = bounds.size().len() / 2.0;
+        let distance = radius / (fov / 2.0).sin();
+        Camera {
+            eye: bounds.center() -
+                 Vector {
+                x: 0.0,
+                y: 0.0,
+                z: distance,
+            },
+            look_at: bounds.center(),
+            fov,
+            focus_distance: distance,
+            ..Default::default()
+        }
+    }
+
+    /// Returns a camera whose eye, look_at and up were transformed by t
+    pub fn transformed(&self, t: &Transform) -> Camera {
+        Camera {
+            eye: t.transform_point(&self.eye),
+            look_at: t.transform_point(&self.look_at),
+            up: t.transform_vector(&self.up),
+            ..*self
+        }
+    }
+
+    /// Returns a camera orbiting the origin by the given angles in degrees, which rotate about
+    /// x first, then y, then z, see `Transform::from_euler_degrees()`. This is what the
+    /// --rotate-x, --rotate-y and --rotate-z options of rtrace do.
+    pub fn orbited(&self, x: RFloat, y: RFloat, z: RFloat) -> Camera {
+        self.transformed(&Transform::from_euler_degrees(x, y, z))
+    }
+
+    /// Returns the normalized (right, up, forward) directions of the image as seen from
+    /// the eye, or None if they are undefined, e.g. as the eye is at look_at or an
+    /// orthographic projection has no positive scale. Lenses need a positive focus distance.
+    pub fn basis(&self, handedness: Handedness) -> Option<(Vector, Vector, Vector)> {
+        if !(self.eye.is_finite() && self.look_at.is_finite() && self.up.is_finite()) {
+            return None;
+        }
+        if !(self.aperture >= 0.0 && self.aperture.is_finite()) ||
+           self.aperture > 0.0 && !(self.focus_distance > 0.0 && self.focus_distance.is_finite()) {
+            return None;
+        }
+        if let Projection::Orthographic { scale } = self.projection {
+            if !(scale > 0.0 && scale.is_finite()) {
+                return None;
+            }
+        }
+        let forward = (self.look_at - self.eye).try_normalized()?;
+        let right = match handedness {
+                Handedness::Left => self.up.cross(&forward),
+                Handedness::Right => forward.cross(&self.up),
+            }
+            .try_normalized()?;
+        let up = match handedness {
+            Handedness::Left => forward.cross(&right),
+            Handedness::Right => right.cross(&forward),
+        };
+        Some((right, up, forward))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f32;
+    use group::{Pair, SphericalGroup};
+    use primitive::{BoundingBox, Sphere};
+    use render::Scene;
+
+    fn spheres(g: &SphericalGroup, out: &mut Vec<Sphere>) {
+        for child in g.children.iter() {
+            match *child {
+                Pair::Item(ref s) => out.push(*s),
+                Pair::Group(ref g) => spheres(g, out),
+            }
+        }
+    }
+
+    #[test]
+    fn basis() {
+        let (right, up, forward) = Camera::default().basis(Handedness::Left).unwrap();
+        assert_eq!(right, Vector { x: 1.0, ..Vector::ZERO });
+        assert_eq!(up, Vector { y: 1.0, ..Vector::ZERO });
+        assert_eq!(forward, Vector { z: 1.0, ..Vector::ZERO });
+        // Right-handed cameras looking the other way see the same right
+        let behind = Camera {
+            eye: Vector { z: 4.0, ..Vector::ZERO },
+            ..Default::default()
+        };
+        assert_eq!(behind.basis(Handedness::Right).unwrap().0, right);
+
+        // up only needs to be roughly up
+        let tilted = Camera {
+            up: Vector {
+                y: 1.0,
+                z: -1.0,
+                ..Vector::ZERO
+            },
+            ..Default::default()
+        };
+        assert_eq!(tilted.basis(Handedness::Left).unwrap().1, up);
+
+        let at_eye = Camera {
+            look_at: Camera::default().eye,
+            ..Default::default()
+        };
+        assert!(at_eye.basis(Handedness::Left).is_none());
+        let up_is_forward = Camera {
+            up: forward,
+            ..Default::default()
+        };
+        assert!(up_is_forward.basis(Handedness::Left).is_none());
+        for &scale in &[0.0, -1.0, f32::NAN] {
+            let flat = Camera {
+                projection: Projection::Orthographic { scale },
+                ..Default::default()
+            };
+            assert!(flat.basis(Handedness::Left).is_none());
+        }
+        let lens = |aperture, focus_distance| {
+            Camera {
+                    aperture,
+                    focus_distance,
+                    ..Default::default()
+                }
+                .basis(Handedness::Left)
+                .is_some()
+        };
+        assert!(lens(0.1, 4.0) && lens(0.0, 0.0));
+        assert!(!lens(-0.1, 4.0) && !lens(f32::INFINITY, 4.0) && !lens(0.1, 0.0));
+    }
+
+    #[test]
+    fn orbited() {
+        let near = |a: Vector, b: Vector| (a - b).len() < 1e-5;
+        let camera = Camera::default();
+        assert_eq!(camera.orbited(0.0, 0.0, 0.0), camera);
+
+        // Turning right by 90 degrees looks along +x, from the left of the origin
+        let right = camera.orbited(0.0, 90.0, 0.0);
+        let (_, up, forward) = right.basis(Handedness::Left).unwrap();
+        assert!(near(forward, Vector { x: 1.0, ..Vector::ZERO }), "{:?}", forward);
+        assert!(near(up, Vector { y: 1.0, ..Vector::ZERO }), "{:?}", up);
+        assert!(near(right.eye, Vector { x: -4.0, ..Vector::ZERO }), "{:?}", right.eye);
+        assert_eq!(right.look_at, Vector::ZERO);
+    }
+
+    #[test]
+    fn fit() {
+        const W: RFloat = 64.0;
+        let group = Scene::default().group;
+        let mut all = Vec::new();
+        spheres(&group, &mut all);
+
+        for &degrees in &[30.0, 60.0, 90.0] {
+            let camera = Camera::fit(&group.bounds(), degrees * f32::consts::PI / 180.0);
+            // Just like the renderer projects a square image
+            let focal = W / (2.0 * (camera.fov / 2.0).tan());
+            for s in all.iter() {
+                let d = s.center - camera.eye;
+                assert!(d.z > 0.0);
+                let x = W / 2.0 + d.x / d.z * focal;
+                let y = W / 2.0 - d.y / d.z * focal;
+                assert!((0.0..=W).contains(&x) && (0.0..=W).contains(&y),
+                        "{:?} projects to {},{} at {} degrees",
+                        s.center,
+                        x,
+                        y,
+                        degrees);
+            }
+        }
+    }
+}
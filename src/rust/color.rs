@@ -0,0 +1,232 @@
+//! The color carried by rays, which is kept apart from vectors so that the renderer
+//! doesn't need to know how it is represented.
+
+use std::ops::Add;
+use super::vec::{Vector, RFloat};
+use super::rng::Rng;
+
+/// Linear RGB for now, which might become a spectrum one day.
+/// Only the conversions assume RGB, everything else treats it as opaque.
+#[derive(PartialEq, Clone, Copy, Default, Debug)]
+pub struct Color(Vector);
+
+impl Color {
+    pub fn from_linear_rgb(rgb: Vector) -> Color {
+        Color(rgb)
+    }
+
+    pub fn to_linear_rgb(&self) -> Vector {
+        self.0
+    }
+
+    /// Decodes 8 bit sRGB components, as found in images, see `srgb_to_linear()`
+    pub fn from_srgb8(rgb: [u8; 3]) -> Color {
+        Color(Vector {
+            x: srgb_to_linear(rgb[0]),
+            y: srgb_to_linear(rgb[1]),
+            z: srgb_to_linear(rgb[2]),
+        })
+    }
+
+    /// The inverse of from_srgb8(), with all components clamped to [0, 1]
+    pub fn to_srgb8(&self) -> [u8; 3] {
+        [linear_to_srgb(self.0.x), linear_to_srgb(self.0.y), linear_to_srgb(self.0.z)]
+    }
+
+    /// A grey of the given intensity
+    pub fn grey(v: RFloat) -> Color {
+        Color(Vector {
+            x: v,
+            y: v,
+            z: v,
+        })
+    }
+
+    pub fn mulfed(&self, m: RFloat) -> Color {
+        Color(self.0.mulfed(m))
+    }
+}
+
+/// Converts an 8 bit sRGB component into linear space, where light adds up
+pub fn srgb_to_linear(v: u8) -> RFloat {
+    let v = v as RFloat / 255.0;
+    if v <= 0.04045 {
+        v / 12.92
+    } else {
+        ((v + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+/// Converts a linear component into 8 bit sRGB, clamping it to [0, 1] first
+pub fn linear_to_srgb(v: RFloat) -> u8 {
+    quantize(encode_srgb(v), 0.0)
+}
+
+// The sRGB transfer function, on v clamped to [0, 1]
+fn encode_srgb(v: RFloat) -> RFloat {
+    let v = v.clamp(0.0, 1.0);
+    if v <= 0.003_130_8 {
+        v * 12.92
+    } else {
+        1.055 * v.powf(2.4f32.recip()) - 0.055
+    }
+}
+
+// Rounds v in [0, 1] to the nearest of 256 levels, after adding offset in levels.
+// Values outside of [0, 1] are clamped, NaN becomes 0.
+fn quantize(v: RFloat, offset: RFloat) -> u8 {
+    (0.5 + offset + 255.0 * v).clamp(0.0, 255.0) as u8
+}
+
+/// How colors are turned into 8 bit pixels, see `color_to_rgba()`
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct ColorOpts {
+    /// If true, pixels are sRGB-encoded like most images are expected to be, see
+    /// `linear_to_srgb()`. Otherwise they are encoded with `gamma`.
+    pub srgb: bool,
+    /// Unless the pixels are sRGB-encoded, each component v is written as v^(1/gamma).
+    /// 1 writes the linear colors as they are.
+    pub gamma: RFloat,
+    /// Brightens the color by this many stops, i.e. scales it by 2^exposure
+    pub exposure: RFloat,
+    /// If true, colors are offset by up to half a level before rounding, which breaks up
+    /// the bands of smooth gradients. The offset is derived from the color itself.
+    pub dither: bool,
+}
+
+impl Default for ColorOpts {
+    fn default() -> ColorOpts {
+        ColorOpts {
+            srgb: false,
+            gamma: 1.0,
+            exposure: 0.0,
+            dither: false,
+        }
+    }
+}
+
+/// Converts a linear color and its coverage into RGBA bytes, applying exposure, encoding,
+/// dithering and clamping as configured. Alpha isn't a color, and is only clamped.
+pub fn color_to_rgba(c: &Vector, alpha: RFloat, opts: &ColorOpts) -> [u8; 4] {
+    let c = if opts.exposure != 0.0 {
+        c.mulfed(opts.exposure.exp2())
+    } else {
+        *c
+    };
+    // Brighter colors are as white as it gets, no matter the encoding
+    let c = c.clamp(0.0, 1.0);
+    let mut rng = if opts.dither {
+        let bits = (c.x.to_bits() as u64) << 32 | c.y.to_bits() as u64;
+        Some(Rng::new(bits ^ (c.z.to_bits() as u64).rotate_left(16)))
+    } else {
+        None
+    };
+    let mut channel = |v: RFloat| {
+        let offset = rng.as_mut().map_or(0.0, |r| r.next_float() - 0.5);
+        let v = if opts.srgb {
+            encode_srgb(v)
+        } else if opts.gamma != 1.0 {
+            v.powf(opts.gamma.recip())
+        } else {
+            v
+        };
+        quantize(v, offset)
+    };
+    [channel(c.x), channel(c.y), channel(c.z), quantize(alpha, 0.0)]
+}
+
+/// The inverse of `color_to_rgba()` up to rounding, which decodes RGBA bytes into a linear
+/// color and its coverage. Exposure and dithering can't be undone, and are ignored.
+pub fn rgba_to_color(rgba: &[u8], opts: &ColorOpts) -> (Vector, RFloat) {
+    let channel = |v: u8| if opts.srgb {
+        srgb_to_linear(v)
+    } else {
+        (v as RFloat / 255.0).powf(opts.gamma)
+    };
+    (Vector {
+         x: channel(rgba[0]),
+         y: channel(rgba[1]),
+         z: channel(rgba[2]),
+     },
+     rgba[3] as RFloat / 255.0)
+}
+
+impl Add for Color {
+    type Output = Color;
+
+    #[inline(always)]
+    fn add(self, rhs: Color) -> Color {
+        Color(self.0 + rhs.0)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let rgb = Vector {
+            x: 0.25,
+            y: 1.5,
+            z: -0.0,
+        };
+        assert_eq!(Color::from_linear_rgb(rgb).to_linear_rgb(), rgb);
+        assert_eq!((Color::grey(0.5) + Color::grey(0.25)).mulfed(2.0), Color::grey(1.5));
+    }
+
+    #[test]
+    fn srgb() {
+        let grey = srgb_to_linear(128);
+        assert!((grey - 0.216).abs() < 1e-3, "{}", grey);
+        assert_eq!(linear_to_srgb(grey), 128);
+        for v in 0..=255u8 {
+            assert_eq!(linear_to_srgb(srgb_to_linear(v)), v);
+        }
+        assert_eq!(Color::from_srgb8([0, 128, 255]).to_srgb8(), [0, 128, 255]);
+        assert_eq!(Color::grey(2.0).to_srgb8(), [255, 255, 255]);
+        assert_eq!(Color::grey(-1.0).to_srgb8(), [0, 0, 0]);
+    }
+
+    #[test]
+    fn rgba() {
+        let opts: ColorOpts = Default::default();
+        let rgb = |x, y, z| Vector { x, y, z };
+        assert_eq!(color_to_rgba(&rgb(0.0, 0.5, 1.0), 1.0, &opts), [0, 128, 255, 255]);
+        assert_eq!(color_to_rgba(&rgb(-1.0, 2.0, RFloat::NAN), 0.5, &opts), [0, 255, 0, 128]);
+        assert_eq!(color_to_rgba(&rgb(0.1, 0.2, 0.3), 0.0, &opts), [26, 51, 77, 0]);
+
+        let srgb = ColorOpts { srgb: true, ..opts };
+        assert_eq!(color_to_rgba(&rgb(0.216, 0.0, 1.0), 0.5, &srgb), [128, 0, 255, 128]);
+        let exposed = ColorOpts { exposure: 1.0, ..opts };
+        assert_eq!(color_to_rgba(&rgb(0.25, 0.5, 1.0), 0.25, &exposed), [128, 255, 255, 64]);
+
+        // Mid grey is much brighter with the gamma of displays, and what's brighter than
+        // white is still white
+        let gamma = ColorOpts { gamma: 2.2, ..opts };
+        assert_eq!(color_to_rgba(&rgb(0.5, 2.0, 0.0), 1.0, &gamma), [186, 255, 0, 255]);
+        // sRGB encoding takes precedence
+        let both = ColorOpts { srgb: true, ..gamma };
+        assert_eq!(color_to_rgba(&rgb(0.216, 0.0, 1.0), 0.5, &both), [128, 0, 255, 128]);
+
+        for opts in &[opts, srgb, gamma] {
+            for &rgba in &[[0u8, 64, 128, 0], [186, 255, 1, 255]] {
+                let (c, alpha) = rgba_to_color(&rgba, opts);
+                assert_eq!(color_to_rgba(&c, alpha, opts), rgba);
+            }
+        }
+        assert_eq!(rgba_to_color(&[186, 0, 0, 51], &gamma).1, 0.2);
+
+        // Dithering never moves a color by more than a level, but does move some
+        let dithered = ColorOpts { dither: true, ..opts };
+        let moved = (0..256).filter(|&i| {
+            let v = i as RFloat / 256.0;
+            let (a, b) = (color_to_rgba(&rgb(v, v, v), 1.0, &opts),
+                          color_to_rgba(&rgb(v, v, v), 1.0, &dithered));
+            assert!(a.iter().zip(b.iter()).all(|(&a, &b)| (a as i32 - b as i32).abs() <= 1));
+            a != b
+        });
+        assert!(moved.count() > 0);
+    }
+}
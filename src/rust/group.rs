@@ -2,7 +2,9 @@
 
 use super::vec::{Vector, RFloat};
 use std::default::Default;
-use super::primitive::{DistanceMeasure, Intersectable, Ray, Sphere, Hit};
+use std::sync::Arc;
+use super::primitive::{DistanceMeasure, Intersectable, BoundingBox, Aabb, Ray, Sphere, Hit};
+use super::transform::Transform;
 
 pub enum Pair<I, G> {
     Item(I),
@@ -11,6 +13,12 @@ pub enum Pair<I, G> {
 
 pub type TypedGroupPair<B, I> = Pair<I, TypedGroup<B, I>>;
 
+/// Groups with fewer children don't test their bound, as it costs about as much as
+/// testing the children directly.
+/// The pyramid's deepest groups have 5 children, which still benefit from their bound:
+/// skipping it for them made rendering the default scene many times slower.
+const MIN_CHILDREN_FOR_BOUND: usize = 3;
+
 /// A group with static dispatch on intersect calls, but dynamically allocated
 /// array of items.
 #[derive(Default)]
@@ -19,7 +27,88 @@ pub struct TypedGroup<B, I> {
     pub children: Vec<TypedGroupPair<B, I>>,
 }
 
-// }
+impl<B, I> TypedGroup<B, I> {
+    /// Returns (num_groups, num_items) of this group and all its subgroups, where the
+    /// items are our actual payload.
+    ///
+    /// ```
+    /// use sphere_tracer::Scene;
+    ///
+    /// assert_eq!(Scene::default().group.count(), (5461, 21845));
+    /// ```
+    pub fn count(&self) -> (usize, usize) {
+        let mut ng = 1usize;
+        let mut ni = 0usize;
+        for item in self.children.iter() {
+            match *item {
+                Pair::Item(_) => ni += 1,
+                Pair::Group(ref g) => {
+                    let (gng, gni) = g.count();
+                    ng += gng;
+                    ni += gni;
+                }
+            }
+        }
+        (ng, ni)
+    }
+
+    /// Returns all items of this group and its subgroups, depth first
+    pub fn items(&self) -> Vec<&I> {
+        let mut items = Vec::new();
+        self.push_items(&mut items);
+        items
+    }
+
+    fn push_items<'a>(&'a self, out: &mut Vec<&'a I>) {
+        for item in self.children.iter() {
+            match *item {
+                Pair::Item(ref i) => out.push(i),
+                Pair::Group(ref g) => g.push_items(out),
+            }
+        }
+    }
+
+    /// Returns the bound intersect() tests, or None if we are too small to test it, see
+    /// MIN_CHILDREN_FOR_BOUND. The bound of such groups needn't contain their children.
+    pub fn tested_bound(&self) -> Option<&B> {
+        if self.children.len() >= MIN_CHILDREN_FOR_BOUND {
+            Some(&self.bound)
+        } else {
+            None
+        }
+    }
+
+    /// Returns the first item of this group or its subgroups for which the predicate is true
+    pub fn find_item<P>(&self, predicate: &mut P) -> Option<&I>
+        where P: FnMut(&I) -> bool
+    {
+        for item in self.children.iter() {
+            let found = match *item {
+                Pair::Item(ref i) if predicate(i) => Some(i),
+                Pair::Item(_) => None,
+                Pair::Group(ref g) => g.find_item(predicate),
+            };
+            if found.is_some() {
+                return found;
+            }
+        }
+        None
+    }
+}
+
+impl<B, I> BoundingBox for TypedGroup<B, I>
+    where I: BoundingBox
+{
+    /// The box around all items, which is tighter than our bound
+    fn bounds(&self) -> Aabb {
+        self.children.iter().fold(Aabb::empty(), |b, item| {
+            match *item {
+                Pair::Item(ref i) => b.union(&i.bounds()),
+                Pair::Group(ref g) => b.union(&g.bounds()),
+            }
+        })
+    }
+}
 
 /// It's interesting that 'type' is indeed a new type, and not a type-def ! At least
 /// when used in this situation !!!
@@ -29,6 +118,7 @@ impl SphericalGroup {
         let s = Sphere {
             center: *p,
             radius: r,
+            material: None,
         };
         if level == 1 {
             return Pair::Item(s);
@@ -43,12 +133,7 @@ impl SphericalGroup {
         let rn: RFloat = 3.0 * r / 12.0f32.sqrt();
         for dz in [-1i32, 1].iter().cloned() {
             for dx in [-1i32, 1].iter().cloned() {
-                let np = *p +
-                         Vector {
-                    x: dx as RFloat * rn,
-                    y: rn,
-                    z: dz as RFloat * rn,
-                };
+                let np = *p + Vector::new(dx as RFloat * rn, rn, dz as RFloat * rn);
                 g.children.push(SphericalGroup::pyramid_recursive(level - 1, &np, r * 0.5));
             }
         }
@@ -58,9 +143,40 @@ impl SphericalGroup {
     pub fn pyramid(level: u32, origin: &Vector, radius: RFloat) -> SphericalGroup {
         assert!(level > 1,
                 "Levels equal or smaller than one cause empty groups");
-        match SphericalGroup::pyramid_recursive(level, origin, radius) {
+        let g = match SphericalGroup::pyramid_recursive(level, origin, radius) {
             Pair::Group(g) => g,
             _ => unreachable!(),
+        };
+        g.debug_assert_bound_contains_children();
+        g
+    }
+
+    /// In debug builds, panics if a child of this group or any subgroup sticks out of its
+    /// bound, as intersect() would miss it where it does. Subgroups are taken to be as large
+    /// as their bound. Groups too small to test their bound aren't checked themselves, see
+    /// MIN_CHILDREN_FOR_BOUND.
+    pub fn debug_assert_bound_contains_children(&self) {
+        if !cfg!(debug_assertions) {
+            return;
+        }
+        // Allows for the rounding errors of bounds computed to fit exactly
+        const TOLERANCE: RFloat = 1e-5;
+        for child in &self.children {
+            let s = match *child {
+                Pair::Item(ref s) => s,
+                Pair::Group(ref g) => {
+                    g.debug_assert_bound_contains_children();
+                    &g.bound
+                }
+            };
+            let extent = (s.center - self.bound.center).len() + s.radius;
+            assert!(self.children.len() < MIN_CHILDREN_FOR_BOUND ||
+                    extent <= self.bound.radius * (1.0 + TOLERANCE),
+                    "Child at {:?} reaches {} from the center of its group, which is beyond its \
+                     bound of radius {}",
+                    s.center,
+                    extent,
+                    self.bound.radius);
         }
     }
 }
@@ -70,7 +186,8 @@ impl<B, I> Intersectable for TypedGroup<B, I>
           I: Intersectable
 {
     fn intersect(&self, hit: &mut Hit, ray: &Ray) {
-        if self.bound.distance_from_ray(&ray) >= hit.distance {
+        if self.children.len() >= MIN_CHILDREN_FOR_BOUND &&
+           self.bound.entry_distance(ray) >= hit.distance {
             return;
         }
 
@@ -81,44 +198,188 @@ impl<B, I> Intersectable for TypedGroup<B, I>
             };
         }
     }
+
+    fn occluder_distance(&self, ray: &Ray, max_distance: RFloat) -> Option<RFloat> {
+        if self.children.len() >= MIN_CHILDREN_FOR_BOUND &&
+           self.bound.entry_distance(ray) >= max_distance {
+            return None;
+        }
+
+        self.children
+            .iter()
+            .filter_map(|item| match *item {
+                Pair::Item(ref v) => v.occluder_distance(ray, max_distance),
+                Pair::Group(ref g) => g.occluder_distance(ray, max_distance),
+            })
+            .next()
+    }
 }
 
 pub type SphericalGroup = TypedGroup<Sphere, Sphere>;
 
+/// Builds a SphericalGroup one item at a time, and computes its bounds when done.
+///
+/// ```
+/// use sphere_tracer::{GroupBuilder, Vector};
+///
+/// let origin = Vector { x: 0.0, y: 0.0, z: 0.0 };
+/// let group = GroupBuilder::new()
+///     .sphere(origin, 1.0)
+///     .subgroup(|b| b.sphere(Vector { x: 2.0, ..origin }, 0.5))
+///     .build();
+/// assert_eq!(group.children.len(), 2);
+/// ```
+#[derive(Default)]
+pub struct GroupBuilder {
+    children: Vec<TypedGroupPair<Sphere, Sphere>>,
+}
 
-#[cfg(test)]
-mod tests {
-    extern crate test;
+impl GroupBuilder {
+    pub fn new() -> GroupBuilder {
+        Default::default()
+    }
 
-    impl<B, I> TypedGroup<B, I> {
-        /// Returns (num_groups, num_items), where the items are our actual payload
-        fn count(&self) -> (usize, usize) {
-            let mut ng = 1usize;
-            let mut ni = 0usize;
-            for item in self.children.iter() {
-                match *item {
-                    Pair::Item(_) => ni += 1,
-                    Pair::Group(ref g) => {
-                        let (gng, gni) = g.count();
-                        ng += gng;
-                        ni += gni;
-                    }
-                }
+    pub fn sphere(mut self, center: Vector, radius: RFloat) -> GroupBuilder {
+        self.children.push(Pair::Item(Sphere {
+            center,
+            radius,
+            material: None,
+        }));
+        self
+    }
+
+    /// Adds a nested group, built by the given function
+    pub fn subgroup<F>(mut self, f: F) -> GroupBuilder
+        where F: FnOnce(GroupBuilder) -> GroupBuilder
+    {
+        self.children.push(Pair::Group(f(GroupBuilder::new()).build()));
+        self
+    }
+
+    /// The bound is a sphere around the centroid of all children, large enough to
+    /// contain each of them. It's not the tightest possible one, but close for
+    /// evenly distributed children.
+    pub fn build(self) -> SphericalGroup {
+        let bounds: Vec<&Sphere> = self.children
+            .iter()
+            .map(|c| match *c {
+                Pair::Item(ref s) => s,
+                Pair::Group(ref g) => &g.bound,
+            })
+            .collect();
+        let mut bound = Sphere {
+            center: Default::default(),
+            radius: 0.0,
+            material: None,
+        };
+        if !bounds.is_empty() {
+            for b in &bounds {
+                bound.center += b.center;
+            }
+            bound.center.mulf((bounds.len() as RFloat).recip());
+            for b in &bounds {
+                bound.radius = bound.radius.max((b.center - bound.center).len() + b.radius);
             }
-            (ng, ni)
         }
+
+        let g = TypedGroup {
+            bound,
+            children: self.children,
+        };
+        g.debug_assert_bound_contains_children();
+        g
+    }
+}
+
+/// Places a shared group into the world using a transform, which allows to render
+/// many copies of a group without duplicating its items.
+pub struct Instance<G> {
+    pub transform: Transform,
+    pub group: Arc<G>,
+    // Cached, as both are needed for every intersection
+    inverse: Transform,
+    normal_transform: Transform,
+}
+
+impl<G> Instance<G> {
+    /// Returns None if the transform is singular, e.g. has a scale of zero, as rays can't
+    /// be brought into the space of the group then
+    pub fn new(transform: Transform, group: Arc<G>) -> Option<Instance<G>> {
+        transform.inverse().map(|inverse| {
+            Instance {
+                inverse,
+                normal_transform: transform.normal_transform(),
+                transform,
+                group,
+            }
+        })
+    }
+}
+
+impl<G> Instance<G> {
+    // Our items expect a normalized direction, which the transform may have scaled.
+    // Returns the ray in our space, and the scale to apply to world-space distances to
+    // keep them comparable to local ones.
+    fn local_ray(&self, ray: &Ray) -> (Ray, RFloat) {
+        let dir = self.inverse.transform_vector(&ray.dir);
+        let scale = dir.len();
+        let local_ray = Ray {
+            pos: self.inverse.transform_point(&ray.pos),
+            dir: dir.mulfed(scale.recip()),
+            // footprints are computed in world space
+            differentials: None,
+            t_min: ray.t_min * scale,
+            t_max: ray.t_max * scale,
+        };
+        (local_ray, scale)
+    }
+}
+
+impl<G> Intersectable for Instance<G>
+    where G: Intersectable
+{
+    fn intersect(&self, hit: &mut Hit, ray: &Ray) {
+        let (local_ray, scale) = self.local_ray(ray);
+        let max_distance = hit.distance * scale;
+        let mut local_hit = Hit::missed();
+        local_hit.distance = max_distance;
+        self.group.intersect(&mut local_hit, &local_ray);
+        if local_hit.distance >= max_distance {
+            return;
+        }
+        hit.distance = local_hit.distance / scale;
+        hit.normal = self.normal_transform.transform_vector(&local_hit.normal).normalized();
+        hit.point = self.transform.transform_point(&local_hit.point);
+        hit.scale = local_hit.scale / scale;
+        hit.material = local_hit.material;
     }
 
+    fn occluder_distance(&self, ray: &Ray, max_distance: RFloat) -> Option<RFloat> {
+        let (local_ray, scale) = self.local_ray(ray);
+        self.group.occluder_distance(&local_ray, max_distance * scale).map(|d| d / scale)
+    }
+}
+
+pub type SphericalInstance = Instance<SphericalGroup>;
+
+
+#[cfg(test)]
+mod tests {
+    extern crate test;
+
     use super::*;
     use super::super::primitive::Intersectable;
     use super::super::vec::Vector;
     use super::super::primitive::{Sphere, Ray, Hit};
+    use super::super::transform::Transform;
     use std::default::Default;
+    use std::sync::Arc;
 
     fn setup_group() -> (Ray, Ray, Ray, SphericalGroup) {
         let s1 = Sphere {
             center: Default::default(),
             radius: 1.0,
+            material: None,
         };
         let mut s2: Sphere = Default::default();
         s2.center.z = s2.radius * 2.0;
@@ -160,8 +421,8 @@ mod tests {
             g.intersect(&mut h, &ray);
             assert!(!h.has_missed());
             assert_eq!(h.distance, 1.0);
-            assert_eq!(h.pos.x, 1.0);
-            assert_eq!(h.pos.z, 0.0);
+            assert_eq!(h.normal.x, 1.0);
+            assert_eq!(h.normal.z, 0.0);
         }
 
         let mut h = Hit::missed();
@@ -169,6 +430,135 @@ mod tests {
         assert!(h.has_missed());
     }
 
+    #[test]
+    fn builder() {
+        let (r1, r2, r3, g) = setup_group();
+        let built = GroupBuilder::new()
+            .sphere(Default::default(), 1.0)
+            .subgroup(|b| {
+                b.sphere(Vector {
+                             x: 0.0,
+                             y: 0.0,
+                             z: 2.0,
+                         },
+                         1.0)
+            })
+            .build();
+        assert_eq!(built.bound.center.z, 1.0);
+        assert_eq!(built.bound.radius, 2.0);
+
+        let mut r4 = r1;
+        r4.dir = Vector {
+                x: -1.0,
+                y: 0.1,
+                z: 0.5,
+            }
+            .normalized();
+        for ray in &[r1, r2, r3, r4] {
+            let mut expected = Hit::missed();
+            g.intersect(&mut expected, ray);
+            let mut h = Hit::missed();
+            built.intersect(&mut h, ray);
+            assert_eq!(h.distance, expected.distance);
+            assert_eq!(h.normal, expected.normal);
+        }
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "beyond its bound")]
+    fn bound_too_small() {
+        let mut g = GroupBuilder::new();
+        for x in 0..MIN_CHILDREN_FOR_BOUND {
+            g = g.sphere(Vector { x: x as RFloat * 2.0, ..Vector::ZERO }, 0.5);
+        }
+        let mut g = g.build();
+        g.debug_assert_bound_contains_children();
+        g.bound.radius *= 0.9;
+        g.debug_assert_bound_contains_children();
+    }
+
+    #[test]
+    fn box_bound() {
+        let spheres: Vec<Sphere> = (0..MIN_CHILDREN_FOR_BOUND)
+            .map(|i| {
+                Sphere {
+                    center: Vector::new(i as RFloat * 3.0, 0.0, 0.0),
+                    ..Default::default()
+                }
+            })
+            .collect();
+        let boxed: TypedGroup<Aabb, Sphere> = TypedGroup {
+            bound: spheres.iter().fold(Aabb::empty(), |b, s| b.union(&s.bounds())),
+            children: spheres.iter().map(|&s| Pair::Item(s)).collect(),
+        };
+        for s in &spheres {
+            let ray = Ray::new(s.center + Vector::new(0.0, 0.5, -4.0), Vector::new(0.0, 0.0, 1.0));
+            let mut h = Hit::missed();
+            boxed.intersect(&mut h, &ray);
+            assert_eq!(h.distance, s.distance_from_ray(&ray));
+        }
+        // Passing just above the box, but within the bounding sphere of the spheres
+        let ray = Ray::new(Vector::new(3.0, 1.01, -4.0), Vector::new(0.0, 0.0, 1.0));
+        assert_eq!(boxed.bound.distance_from_ray(&ray), f32::INFINITY);
+        let mut h = Hit::missed();
+        boxed.intersect(&mut h, &ray);
+        assert!(h.has_missed());
+    }
+
+    #[test]
+    fn t_range() {
+        // A row of spheres along z, the first being hit at 3
+        let mut g = GroupBuilder::new();
+        for z in 0..MIN_CHILDREN_FOR_BOUND {
+            g = g.sphere(Vector::new(0.0, 0.0, z as RFloat * 3.0), 1.0);
+        }
+        let g = Arc::new(g.build());
+        let ray = Ray::new(Vector::new(0.0, 0.0, -4.0), Vector::new(0.0, 0.0, 1.0));
+        let distance = |item: &dyn Intersectable, t_min, t_max| {
+            let mut h = Hit::missed();
+            item.intersect(&mut h, &Ray { t_min, t_max, ..ray });
+            h.distance
+        };
+        let inf = f32::INFINITY;
+        assert_eq!(distance(&*g, 0.0, inf), 3.0);
+        assert_eq!(distance(&*g, 0.0, 2.999), inf);
+        assert_eq!(distance(&*g, 3.5, inf), 5.0);
+        assert_eq!(distance(&*g, 5.5, 6.001), 6.0);
+
+        // Instances scale the range into their space
+        let scaled = Instance::new(Transform::scale(&Vector::splat(2.0)), g.clone()).unwrap();
+        assert_eq!(distance(&scaled, 0.0, inf), 2.0);
+        assert_eq!(distance(&scaled, 0.0, 1.999), inf);
+        assert_eq!(distance(&scaled, 2.001, inf), 6.0);
+    }
+
+    #[test]
+    fn small_groups_skip_bound() {
+        let (r1, r2, r3, g) = setup_group();
+        // A bound far away from the children would make them invisible if it was tested
+        let mut unbounded: SphericalGroup = Default::default();
+        unbounded.bound.center.y = 100.0;
+        unbounded.bound.radius = 0.0;
+        for child in g.children.iter() {
+            if let Pair::Item(s) = *child {
+                unbounded.children.push(Pair::Item(s));
+            }
+        }
+        assert!(unbounded.children.len() < MIN_CHILDREN_FOR_BOUND);
+        assert!(unbounded.tested_bound().is_none());
+        assert!(g.tested_bound().is_none());
+
+        for ray in &[r1, r2, r3] {
+            let mut expected = Hit::missed();
+            g.intersect(&mut expected, ray);
+            let mut h = Hit::missed();
+            unbounded.intersect(&mut h, ray);
+            assert_eq!(h.distance, expected.distance);
+            assert_eq!(h.normal, expected.normal);
+        }
+    }
+
     #[test]
     fn pyramid() {
         let g = SphericalGroup::pyramid(8,
@@ -181,10 +571,60 @@ mod tests {
 
         assert_eq!(g.children.len(), 5);
         assert_eq!(g.count(), (5461, 21845));
+
+        // The base sphere is the largest one, and sits at the bottom
+        let b = g.bounds();
+        assert_eq!(b.min.y, -2.0);
+        assert!(b.max.y > 0.0);
+        assert_eq!(b.center().x, 1.0);
+        assert_eq!(b.center().z, 0.0);
+    }
+
+    #[test]
+    fn instances() {
+        let (r1, _, _, g) = setup_group();
+        let g = Arc::new(g);
+        let offset = Vector {
+            x: 0.0,
+            y: 10.0,
+            z: 0.0,
+        };
+        let instances = [Instance::new(Transform::identity(), g.clone()).unwrap(),
+                         Instance::new(Transform::translation(&offset), g.clone()).unwrap()];
+
+        for (instance, y) in instances.iter().zip([0.0, offset.y].iter()) {
+            let mut ray = r1;
+            ray.pos.y = *y;
+            let mut h = Hit::missed();
+            instance.intersect(&mut h, &ray);
+            assert!(!h.has_missed());
+            assert_eq!(h.distance, 1.0);
+            let world_pos = ray.pos + ray.dir.mulfed(h.distance);
+            assert_eq!(world_pos,
+                       Vector {
+                           x: 1.0,
+                           y: *y,
+                           z: 0.0,
+                       });
+            assert_eq!(h.normal.x, 1.0);
+        }
+
+        // The translated instance is not where the original group is
+        let mut h = Hit::missed();
+        instances[1].intersect(&mut h, &r1);
+        assert!(h.has_missed());
+
+        // Rays can't be brought into the space of a flattened group
+        let flat = Vector {
+            y: 0.0,
+            ..Vector::splat(1.0)
+        };
+        assert!(Instance::new(Transform::scale(&flat), g.clone()).is_none());
     }
 
     const ITERATIONS: usize = 10000;
 
+    // The group is small enough to skip its bound, see MIN_CHILDREN_FOR_BOUND
     #[bench]
     fn bench_intersect(b: &mut test::Bencher) {
         let (r1, r2, r3, g) = setup_group();
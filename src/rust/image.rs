@@ -0,0 +1,162 @@
+//! Reads information about existing image files from their headers, without decoding
+//! their pixels.
+
+use std::io::{self, Read};
+use std::fmt;
+use std::error::Error;
+
+/// Enough for the PNG header, and PPM headers with a comment or two
+const MAX_HEADER_SIZE: u64 = 1024;
+const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+
+#[derive(Debug)]
+pub enum ImageHeaderError {
+    Io(io::Error),
+    /// The image is neither a PPM nor a PNG file
+    UnknownFormat,
+    /// The header is truncated or otherwise invalid, with a description of what's wrong
+    Malformed(String),
+    /// The image is larger than we can render
+    TooLarge { width: u32, height: u32 },
+}
+
+impl fmt::Display for ImageHeaderError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ImageHeaderError::Io(ref err) => write!(f, "Could not read image header: {}", err),
+            ImageHeaderError::UnknownFormat => write!(f, "Only PPM and PNG images are supported"),
+            ImageHeaderError::Malformed(ref what) => write!(f, "Malformed image header: {}", what),
+            ImageHeaderError::TooLarge { width, height } => {
+                write!(f,
+                       "An image of {}x{} exceeds the maximum of {}x{}",
+                       width,
+                       height,
+                       u16::MAX,
+                       u16::MAX)
+            }
+        }
+    }
+}
+
+impl Error for ImageHeaderError {}
+
+impl From<io::Error> for ImageHeaderError {
+    fn from(err: io::Error) -> ImageHeaderError {
+        ImageHeaderError::Io(err)
+    }
+}
+
+/// Returns (width, height) of the PPM (P3 or P6) or PNG image read from r
+pub fn read_dimensions<R: Read>(r: R) -> Result<(u16, u16), ImageHeaderError> {
+    let mut header = Vec::new();
+    r.take(MAX_HEADER_SIZE).read_to_end(&mut header)?;
+
+    let (width, height) = if header.starts_with(PNG_SIGNATURE) {
+        png_dimensions(&header)?
+    } else if header.starts_with(b"P6") || header.starts_with(b"P3") {
+        ppm_dimensions(&header)?
+    } else {
+        return Err(ImageHeaderError::UnknownFormat);
+    };
+
+    if width == 0 || height == 0 {
+        return Err(ImageHeaderError::Malformed(format!("empty image of {}x{}", width, height)));
+    }
+    if width > u16::MAX as u32 || height > u16::MAX as u32 {
+        return Err(ImageHeaderError::TooLarge { width, height });
+    }
+    Ok((width as u16, height as u16))
+}
+
+// The IHDR chunk must come first, right after the signature
+fn png_dimensions(header: &[u8]) -> Result<(u32, u32), ImageHeaderError> {
+    let ihdr = &header[PNG_SIGNATURE.len()..];
+    if ihdr.len() < 16 || &ihdr[4..8] != b"IHDR" {
+        return Err(ImageHeaderError::Malformed("missing IHDR chunk".to_string()));
+    }
+    let be_u32 = |b: &[u8]| {
+        (b[0] as u32) << 24 | (b[1] as u32) << 16 | (b[2] as u32) << 8 | b[3] as u32
+    };
+    Ok((be_u32(&ihdr[8..12]), be_u32(&ihdr[12..16])))
+}
+
+// "P6 <width> <height> <maxval>", separated by whitespace, with comments from # to the
+// end of the line
+fn ppm_dimensions(header: &[u8]) -> Result<(u32, u32), ImageHeaderError> {
+    let mut fields = Vec::with_capacity(3);
+    let mut field = String::new();
+    let mut in_comment = false;
+    for &b in &header[2..] {
+        let c = b as char;
+        if in_comment {
+            in_comment = c != '\n';
+            continue;
+        }
+        if c == '#' || c.is_ascii_whitespace() {
+            in_comment = c == '#';
+            if !field.is_empty() {
+                fields.push(field.clone());
+                field.clear();
+            }
+            // After maxval, a single whitespace separates the header from the pixels
+            if fields.len() == 3 {
+                break;
+            }
+        } else if c.is_ascii_digit() {
+            field.push(c);
+        } else {
+            return Err(ImageHeaderError::Malformed(format!("unexpected character {:?}", c)));
+        }
+    }
+    if fields.len() < 3 {
+        return Err(ImageHeaderError::Malformed("truncated PPM header".to_string()));
+    }
+
+    let parse = |f: &str| {
+        f.parse::<u32>()
+            .map_err(|_| ImageHeaderError::Malformed(format!("'{}' is out of range", f)))
+    };
+    Ok((parse(&fields[0])?, parse(&fields[1])?))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ppm() {
+        let header = b"P6\n640 480\n255\n\x00\x01\x02";
+        assert_eq!(read_dimensions(&header[..]).unwrap(), (640, 480));
+
+        let commented = b"P6 # written by rtrace\n640\t480 # size\n255 ";
+        assert_eq!(read_dimensions(&commented[..]).unwrap(), (640, 480));
+
+        for malformed in &[&b"P6\n640\n"[..], b"P6\n640 x480\n255\n", b"P6\n640 0 255\n"] {
+            match read_dimensions(*malformed) {
+                Err(ImageHeaderError::Malformed(_)) => {}
+                res => panic!("{:?} must be malformed, got {:?}", malformed, res),
+            }
+        }
+        match read_dimensions(&b"P6 70000 1 255\n"[..]) {
+            Err(ImageHeaderError::TooLarge { width: 70000, height: 1 }) => {}
+            res => panic!("expected TooLarge, got {:?}", res),
+        }
+    }
+
+    #[test]
+    fn png() {
+        let mut header = PNG_SIGNATURE.to_vec();
+        header.extend_from_slice(b"\x00\x00\x00\x0dIHDR\x00\x00\x02\x80\x00\x00\x01\xe0\x08\x06");
+        assert_eq!(read_dimensions(&header[..]).unwrap(), (640, 480));
+
+        match read_dimensions(&header[..12]) {
+            Err(ImageHeaderError::Malformed(_)) => {}
+            res => panic!("expected a malformed header, got {:?}", res),
+        }
+        match read_dimensions(&b"GIF89a"[..]) {
+            Err(ImageHeaderError::UnknownFormat) => {}
+            res => panic!("expected an unknown format, got {:?}", res),
+        }
+    }
+}
@@ -1,8 +1,44 @@
+//! A toy raytracer rendering scenes made of spheres.
+//!
+//! ```
+//! use sphere_tracer::{RFloat, Vector, Sphere, RenderOptions};
+//!
+//! let radius: RFloat = 0.5;
+//! let s = Sphere {
+//!     center: Vector { x: 0.0, y: 1.0, z: 0.0 },
+//!     radius,
+//!     material: None,
+//! };
+//! let o = RenderOptions { width: 64, height: 64, ..Default::default() };
+//! assert_eq!(s.center.y, 1.0);
+//! assert_eq!(o.samples_per_axis, 1);
+//! ```
 #![cfg_attr(test, feature(test))]
 
 mod vec;
 mod primitive;
 mod group;
 mod render;
+mod transform;
+mod image;
+mod rng;
+mod camera;
+mod color;
+mod scene_file;
 
-pub use render::{Scene, Renderer, RenderOptions, PPMStdoutRGBABufferWriter, FileOrAnyWriter};
+pub use render::{pixel_rng, MAX_SAMPLES_PER_AXIS, Scene, Ground, BackgroundFn, Renderer,
+                 RenderOptions, RenderTarget, Palette, TileOrder, EdgeMode, PostAA, Refinement,
+                 RenderError, SceneError, RenderSummary, BounceStats, RenderControl, ImageRegion,
+                 RGBABuffer, PixelSource, RGBABufferWriter, write_ppm, write_tga,
+                 PPMStdoutRGBABufferWriter, TgaRGBABufferWriter, AlphaOverRGBABufferWriter,
+                 ImageOutput};
+pub use group::{GroupBuilder, Instance, SphericalInstance};
+pub use transform::Transform;
+pub use vec::{Vector, RFloat, Float, ParseVectorError};
+pub use primitive::{Sphere, Material, Cutout, AlphaMask, Plane, Triangle, Lattice, Ray,
+                    RayDifferentials, Aabb, BoundingBox};
+pub use image::{read_dimensions, ImageHeaderError};
+pub use rng::Rng;
+pub use camera::{Camera, Projection, Handedness};
+pub use color::{Color, ColorOpts, color_to_rgba, rgba_to_color, srgb_to_linear, linear_to_srgb};
+pub use scene_file::{read_scene, write_scene, SceneFileError};
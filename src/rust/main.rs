@@ -3,24 +3,48 @@
 extern crate sphere_tracer;
 extern crate threadpool;
 extern crate clap;
+extern crate ctrlc;
 
 
-use sphere_tracer::{Scene, Renderer, RenderOptions, PPMStdoutRGBABufferWriter, FileOrAnyWriter};
+use sphere_tracer::{Scene, Camera, Projection, Renderer, RenderOptions, RenderTarget,
+                    RenderControl, RenderSummary, RenderError, PostAA, RGBABufferWriter,
+                    PPMStdoutRGBABufferWriter, TgaRGBABufferWriter, ImageOutput, Vector,
+                    ImageHeaderError, SceneFileError, read_dimensions, read_scene, write_scene};
 
 use std::default::Default;
 use std::env;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::ffi::OsStr;
-use std::{io, fs};
+use std::{io, fs, cmp};
+use std::io::{IsTerminal, Write};
 use std::path::Path;
 use std::process;
 
 use threadpool::ThreadPool;
 use clap::{App, Arg};
 
+/// The exit code of renders cancelled with Ctrl-C, as if the signal had terminated us
+const EXIT_CANCELLED: i32 = 130;
+/// The length of the longer side of thumbnails
+const THUMBNAIL_SIZE: u16 = 128;
+
+// Draws a bar like [#####-----] 5/10 tiles, overwriting the previous one
+fn print_progress(completed: usize, total: usize) {
+    const WIDTH: usize = 40;
+    let done = completed * WIDTH / cmp::max(total, 1);
+    eprint!("\r[{}{}] {}/{} tiles",
+            "#".repeat(done),
+            "-".repeat(WIDTH - done),
+            completed,
+            total);
+    if completed == total {
+        eprintln!();
+    }
+}
+
 #[allow(dead_code)]
 fn main() {
-    let s: Arc<Scene> = Arc::new(Default::default());
     let nc_from_env = env::var("RTRACEMAXPROCS")
         .ok()
         .unwrap_or("1".to_string())
@@ -36,8 +60,63 @@ fn main() {
                             \
                           --height=[Y] 'The height of the output image [default: 1024]'
                             \
-                          [ssp] --samples-per-pixel=[SAMPLES]  'Amount of samples per pixel. 4 \
-                          means 16 over-samples [default: 1]'")
+                          [ssp] --samples-per-pixel=[SAMPLES]  'Amount of samples along each \
+                          axis of a pixel. 4 means 16 samples per pixel [default: 1]'
+                            \
+                          --samples=[TOTAL] 'Total amount of samples per pixel, rounded to \
+                          the nearest square. Overrides --samples-per-pixel'
+                            \
+                          --rotate-x=[DEGREES] 'Rotation of the camera about the x axis, \
+                          applied first [default: 0]'
+                            \
+                          --rotate-y=[DEGREES] 'Rotation of the camera about the y axis, \
+                          applied second [default: 0]'
+                            \
+                          --rotate-z=[DEGREES] 'Rotation of the camera about the z axis, \
+                          applied last [default: 0]'
+                            \
+                          --eye=[X,Y,Z] 'The position of the camera [default: 0,0,-4]'
+                            \
+                          --orthographic=[SIZE] 'Use a parallel projection, showing SIZE \
+                          units of the scene along the shorter side of the image'
+                            \
+                          --aperture=[RADIUS] 'The radius of the lens, which blurs \
+                          everything not at the focus distance [default: 0]'
+                            \
+                          --focus-distance=[DISTANCE] 'How far in front of the camera \
+                          things are sharp [default: 4]'
+                            \
+                          --light=[X,Y,Z] 'The direction the light shines into \
+                          [default: -1,-3,2]'
+                            \
+                          --light-size=[DEGREES] 'The angular radius of the light, which \
+                          softens shadows the more the farther they fall [default: 0]'
+                            \
+                          --match-resolution=[IMAGE] 'Use the width and height of the given \
+                          PPM or PNG image, overriding --width and --height'
+                            \
+                          --unlit 'Only render the colors of surfaces, without any lighting'
+                            \
+                          --coverage 'Only render how much of each pixel is covered by \
+                          geometry, as grayscale matte'
+                            \
+                          --gamma=[GAMMA] 'The gamma the colors are encoded with, where 1 \
+                          writes them linearly [default: 2.2]'
+                            \
+                          --post-aa 'Smooth the edges of the finished image, which is much \
+                          cheaper than more samples per pixel'
+                            \
+                          --denoise 'Smooth the noise of the finished image, keeping the \
+                          edges of objects'
+                            \
+                          --thumbnail=[PATH] 'Also write the image scaled down to 128 pixels \
+                          along its longer side as PPM to the given file'
+                            \
+                          --scene=[FILE] 'Render the scene read from the given file instead \
+                          of the built-in one, see --dump-scene'
+                            \
+                          --dump-scene=[FILE] 'Write the built-in scene to the given file as \
+                          a starting point for --scene, and exit without rendering'")
         .arg(Arg::with_name("numcores")
             .long("num-cores")
             .takes_value(true)
@@ -49,10 +128,34 @@ fn main() {
                                      \
                    The commandline always overrides environment variables."))
         .arg(Arg::with_name("output")
-            .required(true)
+            .required_unless("dump-scene")
             .empty_values(false)
             .help("Either a file with .tga extension, or - to write file to stdout"))
         .get_matches();
+
+    if let Some(path) = args.value_of("dump-scene") {
+        let written = fs::File::create(path).map(io::BufWriter::new).and_then(|mut f| {
+            write_scene(&mut f, &Scene::default())?;
+            f.flush()
+        });
+        if let Err(err) = written {
+            eprintln!("Could not write the scene to '{}': {}", path, err);
+            process::exit(1);
+        }
+        process::exit(0);
+    }
+    let mut scene: Scene = match args.value_of("scene") {
+        Some(path) => {
+            fs::File::open(path)
+                .map_err(SceneFileError::from)
+                .and_then(|f| read_scene(io::BufReader::new(f)))
+                .unwrap_or_else(|err| {
+                    eprintln!("Invalid --scene '{}': {}", path, err);
+                    process::exit(1);
+                })
+        }
+        None => Default::default(),
+    };
     let num_cores: usize = args.value_of("numcores").unwrap_or("1").parse().unwrap();
     let pool: ThreadPool = ThreadPool::new(if num_cores > 1 {
         num_cores
@@ -61,7 +164,7 @@ fn main() {
     });
 
     let output_file = args.value_of("output").unwrap();
-    let mut output = if output_file != "-" {
+    let mut output: Box<dyn ImageOutput> = if output_file != "-" {
         let p = Path::new(&output_file);
         if p.extension().unwrap_or(OsStr::new(".UNSET")) != "tga" {
             println!("Output file '{}' must have the tga extension, e.g. {}",
@@ -69,22 +172,180 @@ fn main() {
                      p.with_extension("tga").to_str().unwrap());
             return;
         }
-        let open_file = io::BufWriter::new(fs::File::create(&p).unwrap());
-        FileOrAnyWriter::FileWriter(open_file)
+        Box::new(io::BufWriter::new(fs::File::create(&p).unwrap()))
     } else {
-        FileOrAnyWriter::AnyWriter(io::stdout())
+        Box::new(io::stdout())
     };
 
-    let options = RenderOptions {
+    let mut options = RenderOptions {
         width: args.value_of("width").unwrap_or("1024").parse().unwrap(),
         height: args.value_of("height").unwrap_or("1024").parse().unwrap(),
-        samples_per_pixel: args.value_of("ssp").unwrap_or("1").parse().unwrap(),
+        samples_per_axis: args.value_of("ssp").unwrap_or("1").parse().unwrap(),
+        ..Default::default()
     };
+    if let Some(total) = args.value_of("samples") {
+        let total = total.parse().unwrap_or_else(|err| {
+            eprintln!("Invalid --samples: {}", err);
+            process::exit(1);
+        });
+        options.samples_per_axis = RenderOptions::with_total_samples(total).samples_per_axis;
+    }
+    if args.is_present("unlit") {
+        options.target = RenderTarget::Unlit;
+    }
+    if args.is_present("coverage") {
+        options.target = RenderTarget::Coverage;
+    }
+    if args.is_present("post-aa") {
+        options.post_aa = PostAA::Morphological;
+    }
+    if args.is_present("denoise") {
+        options.denoise = true;
+    }
+    if let Some(gamma) = args.value_of("gamma") {
+        options.color.gamma = match gamma.parse::<f32>() {
+            Ok(gamma) if gamma > 0.0 => gamma,
+            _ => {
+                eprintln!("Invalid --gamma '{}': it must be a positive number", gamma);
+                process::exit(1);
+            }
+        };
+    }
+    if let Some(image) = args.value_of("match-resolution") {
+        let dimensions = fs::File::open(image)
+            .map_err(ImageHeaderError::from)
+            .and_then(read_dimensions);
+        match dimensions {
+            Ok((width, height)) => {
+                options.width = width;
+                options.height = height;
+            }
+            Err(err) => {
+                eprintln!("Invalid --match-resolution '{}': {}", image, err);
+                process::exit(1);
+            }
+        }
+    }
 
-    Renderer::render(&options,
-                     s.clone(),
-                     &mut PPMStdoutRGBABufferWriter::new(true, &mut output),
-                     &pool);
+    let mut camera = Camera::default();
+    if let Some(eye) = args.value_of("eye") {
+        camera.eye = eye.parse().unwrap_or_else(|err| {
+            eprintln!("Invalid --eye: {}", err);
+            process::exit(1);
+        });
+        // Keep looking along +z
+        camera.look_at = camera.eye + Vector { z: 1.0, ..Vector::ZERO };
+    }
+    if let Some(scale) = args.value_of("orthographic") {
+        let scale = scale.parse().unwrap_or_else(|err| {
+            eprintln!("Invalid --orthographic: {}", err);
+            process::exit(1);
+        });
+        camera.projection = Projection::Orthographic { scale };
+    }
+    if let Some(aperture) = args.value_of("aperture") {
+        camera.aperture = aperture.parse().unwrap_or_else(|err| {
+            eprintln!("Invalid --aperture: {}", err);
+            process::exit(1);
+        });
+    }
+    if let Some(distance) = args.value_of("focus-distance") {
+        camera.focus_distance = distance.parse().unwrap_or_else(|err| {
+            eprintln!("Invalid --focus-distance: {}", err);
+            process::exit(1);
+        });
+    }
+    if let Some(light) = args.value_of("light") {
+        let light: Vector = light.parse().unwrap_or_else(|err| {
+            eprintln!("Invalid --light: {}", err);
+            process::exit(1);
+        });
+        scene.directional_light = light.try_normalized().unwrap_or_else(|| {
+            eprintln!("Invalid --light: the direction must not be zero");
+            process::exit(1);
+        });
+    }
+    if let Some(size) = args.value_of("light-size") {
+        scene.light_size = size.parse::<f32>().map(f32::to_radians).unwrap_or_else(|err| {
+            eprintln!("Invalid --light-size: {}", err);
+            process::exit(1);
+        });
+    }
+    let degrees = |name: &str| -> f32 {
+        args.value_of(name).unwrap_or("0").parse().unwrap_or_else(|err| {
+            eprintln!("Invalid --{}: {}", name, err);
+            process::exit(1);
+        })
+    };
+    options.camera = Some(camera.orbited(degrees("rotate-x"),
+                                         degrees("rotate-y"),
+                                         degrees("rotate-z")));
+    let s = Arc::new(scene);
+
+    // The first Ctrl-C stops rendering, but still writes what was rendered so far
+    let cancel = Arc::new(AtomicBool::new(false));
+    {
+        let cancel = cancel.clone();
+        ctrlc::set_handler(move || cancel.store(true, Ordering::Relaxed))
+            .expect("Failed to install the Ctrl-C handler");
+    }
+    let show_progress = io::stderr().is_terminal();
+    let mut progress = |completed, total| if show_progress {
+        print_progress(completed, total)
+    };
+
+    let render = |writer: &mut dyn RGBABufferWriter| {
+        Renderer::render_controlled(&options,
+                                    s.clone(),
+                                    writer,
+                                    &pool,
+                                    &mut RenderControl {
+                                        progress: Some(&mut progress),
+                                        cancel: Some(cancel),
+                                    })
+    };
+    let thumbnail = |result: &Result<RenderSummary, RenderError>,
+                     write: &dyn Fn(&mut dyn io::Write) -> io::Result<()>| {
+        if let (Some(path), Ok(true)) = (args.value_of("thumbnail"),
+                                         result.as_ref().map(|s| s.complete)) {
+            let written = fs::File::create(path)
+                .map(io::BufWriter::new)
+                .and_then(|mut f| write(&mut f));
+            if let Err(err) = written {
+                eprintln!("Could not write thumbnail to '{}': {}", path, err);
+            }
+        }
+    };
+    let finished = |written: io::Result<()>| if let Err(err) = written {
+        eprintln!("Could not write the image to '{}': {}", output_file, err);
+        process::exit(1);
+    };
+    // The writers must write the image before we exit, which finish() does.
+    // Files are TGA, while stdout gets PPM, which is easier to pipe into other tools.
+    let result = if output_file != "-" {
+        let mut writer = TgaRGBABufferWriter::new(&mut *output);
+        let result = render(&mut writer);
+        thumbnail(&result, &|f| writer.write_thumbnail(THUMBNAIL_SIZE, f));
+        finished(writer.finish());
+        result
+    } else {
+        let mut writer = PPMStdoutRGBABufferWriter::new(true, &mut *output);
+        let result = render(&mut writer);
+        thumbnail(&result, &|f| writer.write_thumbnail(THUMBNAIL_SIZE, f));
+        finished(writer.finish());
+        result
+    };
+    match result {
+        Ok(ref summary) if !summary.complete => {
+            eprintln!("\nCancelled - {}", summary);
+            process::exit(EXIT_CANCELLED);
+        }
+        Ok(summary) => eprintln!("{}", summary),
+        Err(err) => {
+            eprintln!("{}", err);
+            process::exit(1);
+        }
+    }
 
     process::exit(0);
 }
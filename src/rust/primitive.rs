@@ -5,24 +5,109 @@ use super::vec::{Vector, RFloat};
 use std::default::Default;
 
 use std::f32;
+#[cfg(test)]
+use std::cell::Cell;
 
-#[derive(Default, PartialEq, Clone, Copy, Debug)]
+#[derive(PartialEq, Clone, Copy, Debug)]
 pub struct Ray {
     pub pos: Vector,
     pub dir: Vector,
+    /// If set, allows to compute the footprint of the ray on the surface it hits
+    pub differentials: Option<RayDifferentials>,
+    /// Only hits farther than t_min, and at most t_max away are considered. This keeps
+    /// rays from hitting the surface they start on, or anything behind what they go to.
+    pub t_min: RFloat,
+    pub t_max: RFloat,
+}
+
+impl Default for Ray {
+    fn default() -> Ray {
+        Ray {
+            pos: Default::default(),
+            dir: Default::default(),
+            differentials: None,
+            t_min: 0.0,
+            t_max: f32::INFINITY,
+        }
+    }
+}
+
+/// The differences between a ray's direction and the ones of the rays through the
+/// neighbouring pixels (or samples) along x and y
+#[derive(Default, PartialEq, Clone, Copy, Debug)]
+pub struct RayDifferentials {
+    pub dx: Vector,
+    pub dy: Vector,
+}
+
+impl Ray {
+    /// A ray starting at pos, going along dir, which is normalized as intersections expect
+    pub fn new(pos: Vector, dir: Vector) -> Ray {
+        Ray::new_unnormalized(pos, dir.normalized())
+    }
+
+    /// Like new(), but uses dir as is, which saves normalizing it where it's known to be
+    /// normalized already
+    #[inline(always)]
+    pub fn new_unnormalized(pos: Vector, dir: Vector) -> Ray {
+        Ray {
+            pos,
+            dir,
+            ..Default::default()
+        }
+    }
+
+    // True if a hit at the given distance is within t_min and t_max
+    #[inline(always)]
+    fn accepts(&self, distance: RFloat) -> bool {
+        distance > self.t_min && distance <= self.t_max
+    }
+
+    /// Returns the width of the surface area covered by this ray at the given hit, which
+    /// grows with the distance and as the surface turns away from the ray.
+    /// Returns 0 if the ray has no differentials.
+    pub fn footprint(&self, hit: &Hit) -> RFloat {
+        // Keeps grazing hits from producing infinite footprints
+        const MIN_COS: RFloat = 1e-3;
+        match self.differentials {
+            Some(ref d) => {
+                let spread = d.dx.len().max(d.dy.len());
+                let cos = hit.normal.dot(&self.dir).abs().max(MIN_COS);
+                hit.distance * spread / cos
+            }
+            None => 0.0,
+        }
+    }
 }
 
 #[derive(Clone, Copy)]
 pub struct Hit {
     pub distance: RFloat,
-    pub pos: Vector,
+    /// The normalized direction the surface faces at the hit point, which renderers may flip
+    /// to face the ray
+    pub normal: Vector,
+    /// The point on the surface of the hit item, computed from the item itself.
+    /// It is more precise than `ray.pos + ray.dir * distance`, whose error grows with the
+    /// distance, even if the item is tiny.
+    pub point: Vector,
+    /// The size of the hit item, like the radius of a sphere, to scale offsets from its surface
+    pub scale: RFloat,
+    /// The width of the surface area covered by the ray, see `Ray::footprint()`.
+    /// It is not set by intersect(), but by whoever shot the ray.
+    pub footprint: RFloat,
+    /// The material of the hit item, if it has one
+    pub material: Option<Material>,
 }
 
 impl Hit {
     pub fn missed() -> Hit {
         Hit {
             distance: f32::INFINITY,
-            pos: Default::default(),
+            normal: Default::default(),
+            point: Default::default(),
+            scale: 0.0,
+            footprint: 0.0,
+            material: None,
         }
     }
 
@@ -35,10 +120,99 @@ impl Hit {
     }
 }
 
-#[derive(Clone, Copy)]
+/// Describes how a surface looks, as opposed to its shape
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub struct Material {
+    /// The linear color of the light the surface scatters
+    pub diffuse: Vector,
+    /// How much of the surface is a mirror, from 0 for none to 1 for a perfect one
+    pub reflectivity: RFloat,
+    /// The light the surface gives off by itself. Surfaces with any emission are light
+    /// sources, which appear in exactly this color no matter how they are lit.
+    pub emission: Vector,
+    /// If set, parts of the surface are cut out of it, and rays pass right through them
+    pub cutout: Option<Cutout>,
+}
+
+impl Default for Material {
+    fn default() -> Material {
+        Material {
+            diffuse: Material::DEFAULT_DIFFUSE,
+            reflectivity: 0.0,
+            emission: Default::default(),
+            cutout: None,
+        }
+    }
+}
+
+impl Material {
+    /// The red of the default material, which is also how surfaces without a material look
+    pub const DEFAULT_DIFFUSE: Vector = Vector {
+        x: 0xae as RFloat / 255.0,
+        y: 0x31 as RFloat / 255.0,
+        z: 0x31 as RFloat / 255.0,
+    };
+
+    pub fn is_emissive(&self) -> bool {
+        self.emission != Default::default()
+    }
+}
+
+/// Makes parts of a surface invisible, like the gaps between the leaves of foliage drawn on a
+/// single shape. Rays pass through where the alpha of the mask is below the threshold, and
+/// hit whatever is behind, including the back of the surface.
+/// Only spheres support it.
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub struct Cutout {
+    pub mask: AlphaMask,
+    pub threshold: RFloat,
+}
+
+impl Cutout {
+    // True if the given point on the unit sphere around the origin is kept
+    #[inline]
+    fn is_opaque(&self, p: &Vector) -> bool {
+        let (u, v) = sphere_uv(p);
+        self.mask.alpha(u, v) >= self.threshold
+    }
+}
+
+/// A procedural alpha over the uv coordinates of a surface
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum AlphaMask {
+    /// The given amount of opaque stripes along u, each followed by a transparent one
+    Stripes(u16),
+    /// Rising from 0 to 1 along v, which is from the top of a sphere to its bottom
+    Vertical,
+}
+
+impl AlphaMask {
+    /// The alpha at u, v, from 0 for transparent to 1 for opaque
+    pub fn alpha(&self, u: RFloat, v: RFloat) -> RFloat {
+        match *self {
+            AlphaMask::Stripes(count) => {
+                let stripe = (u * count as RFloat * 2.0).floor() as i64;
+                if stripe % 2 == 0 { 1.0 } else { 0.0 }
+            }
+            AlphaMask::Vertical => v,
+        }
+    }
+}
+
+// The uv coordinates of the given point on the unit sphere around the origin, each from 0
+// to 1. u goes around the y axis, and v from the top (+y) to the bottom.
+fn sphere_uv(p: &Vector) -> (RFloat, RFloat) {
+    let u = p.z.atan2(p.x) / (2.0 * f32::consts::PI) + 0.5;
+    let v = p.y.clamp(-1.0, 1.0).acos() / f32::consts::PI;
+    (u, v)
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
 pub struct Sphere {
     pub center: Vector,
     pub radius: RFloat,
+    /// Spheres without a material use the renderer's default look
+    pub material: Option<Material>,
 }
 
 impl Default for Sphere {
@@ -46,6 +220,7 @@ impl Default for Sphere {
         Sphere {
             center: Default::default(),
             radius: 1.0,
+            material: None,
         }
     }
 }
@@ -55,7 +230,7 @@ impl DistanceMeasure for Sphere {
     fn distance_from_ray(&self, r: &Ray) -> RFloat {
         let v = self.center - r.pos;
         let b = v.dot(&r.dir);
-        let disc = b * b - v.dot(&v) + self.radius * self.radius;
+        let disc = b * b - v.len_squared() + self.radius * self.radius;
 
         if disc < 0.0 {
             return f32::INFINITY;
@@ -63,40 +238,418 @@ impl DistanceMeasure for Sphere {
 
         let d = disc.sqrt();
         let t2 = b + d;
-        if t2 < 0.0 {
+        if t2 < r.t_min {
             return f32::INFINITY;
         }
 
         let t1 = b - d;
-        if t1 > 0.0 { t1 } else { t2 }
+        let t = if t1 > r.t_min { t1 } else { t2 };
+        if t <= r.t_max { t } else { f32::INFINITY }
+    }
+
+    #[inline(always)]
+    fn entry_distance(&self, r: &Ray) -> RFloat {
+        let v = self.center - r.pos;
+        let b = v.dot(&r.dir);
+        let disc = b * b - v.len_squared() + self.radius * self.radius;
+        if disc < 0.0 {
+            return f32::INFINITY;
+        }
+        let d = disc.sqrt();
+        let (t1, t2) = (b - d, b + d);
+        if t2 < r.t_min || t1 > r.t_max {
+            return f32::INFINITY;
+        }
+        t1.max(r.t_min)
+    }
+}
+
+impl Sphere {
+    // Like distance_from_ray(), but passes through where the material cuts out the surface,
+    // to hit its back or nothing at all, see `Cutout`
+    #[inline(always)]
+    fn surface_distance(&self, ray: &Ray) -> RFloat {
+        let cutout = match self.material.and_then(|m| m.cutout) {
+            Some(cutout) => cutout,
+            None => return self.distance_from_ray(ray),
+        };
+        let v = self.center - ray.pos;
+        let b = v.dot(&ray.dir);
+        let disc = b * b - v.len_squared() + self.radius * self.radius;
+        if disc < 0.0 {
+            return f32::INFINITY;
+        }
+
+        let d = disc.sqrt();
+        for &t in &[b - d, b + d] {
+            let p = (ray.pos + ray.dir.mulfed(t) - self.center).mulfed(self.radius.recip());
+            if ray.accepts(t) && cutout.is_opaque(&p) {
+                return t;
+            }
+        }
+        f32::INFINITY
     }
 }
 
 impl Intersectable for Sphere {
     #[inline(always)]
+    fn intersect(&self, hit: &mut Hit, ray: &Ray) {
+        let distance = self.surface_distance(ray);
+        if distance >= hit.distance {
+            return;
+        }
+        hit.distance = distance;
+        hit.normal = (ray.pos + (ray.dir.mulfed(distance) - self.center)).normalized();
+        hit.point = self.center + hit.normal.mulfed(self.radius);
+        hit.scale = self.radius;
+        hit.material = self.material;
+        #[cfg(test)]
+        NORMALS_COMPUTED.with(|n| n.set(n.get() + 1));
+    }
+
+    #[inline(always)]
+    fn occluder_distance(&self, ray: &Ray, max_distance: RFloat) -> Option<RFloat> {
+        let distance = self.surface_distance(ray);
+        if distance < max_distance { Some(distance) } else { None }
+    }
+}
+
+/// Rays cross at most this many cells of a lattice, as some never hit any of its spheres
+const MAX_LATTICE_CELLS: usize = 4096;
+
+/// A sphere repeated every period along each axis, which fills all of space with its copies
+/// without storing any of them
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Lattice {
+    /// One of the copies. Its radius must not exceed half the period, so that each copy
+    /// stays within its own cell.
+    pub sphere: Sphere,
+    pub period: RFloat,
+}
+
+impl Lattice {
+    // Walks the cells along the ray, nearest first, and returns the distance and the copy
+    // of the first sphere it hits before max_distance, along with the distance at which the
+    // ray entered its cell. Intersecting from there keeps far hits as precise as close ones.
+    fn nearest(&self, ray: &Ray, max_distance: RFloat) -> Option<(RFloat, Sphere, RFloat)> {
+        // In units of cells, whose corners are at whole numbers, centered on the copies
+        let p = (ray.pos - self.sphere.center).mulfed(self.period.recip());
+        let (pos, dir) = ([p.x + 0.5, p.y + 0.5, p.z + 0.5], [ray.dir.x, ray.dir.y, ray.dir.z]);
+        let mut cell = [0.0; 3];
+        let mut step = [0.0; 3];
+        // The distance at which the ray enters the next cell along each axis
+        let mut t_next = [f32::INFINITY; 3];
+        let mut t_delta = [f32::INFINITY; 3];
+        let mut t_cell = 0.0;
+        for i in 0..3 {
+            cell[i] = pos[i].floor();
+            if dir[i] > 0.0 {
+                step[i] = 1.0;
+                t_next[i] = (cell[i] + 1.0 - pos[i]) * self.period / dir[i];
+            } else if dir[i] < 0.0 {
+                step[i] = -1.0;
+                t_next[i] = (pos[i] - cell[i]) * self.period / -dir[i];
+            }
+            t_delta[i] = self.period / dir[i].abs();
+        }
+
+        for _ in 0..MAX_LATTICE_CELLS {
+            // Each copy lies within its cell, so its hits are closer than the ones of the
+            // cells which follow
+            let offset = Vector {
+                x: cell[0] * self.period,
+                y: cell[1] * self.period,
+                z: cell[2] * self.period,
+            };
+            let copy = Sphere {
+                center: self.sphere.center + offset,
+                ..self.sphere
+            };
+            let distance = t_cell + copy.surface_distance(&Lattice::advanced(ray, t_cell));
+            if distance < max_distance {
+                return Some((distance, copy, t_cell));
+            }
+            let i = if t_next[0] < t_next[1] {
+                if t_next[0] < t_next[2] { 0 } else { 2 }
+            } else if t_next[1] < t_next[2] {
+                1
+            } else {
+                2
+            };
+            if t_next[i] >= max_distance {
+                return None;
+            }
+            t_cell = t_next[i];
+            cell[i] += step[i];
+            t_next[i] += t_delta[i];
+        }
+        None
+    }
+
+    fn advanced(ray: &Ray, distance: RFloat) -> Ray {
+        Ray {
+            pos: ray.pos + ray.dir.mulfed(distance),
+            t_min: (ray.t_min - distance).max(0.0),
+            t_max: ray.t_max - distance,
+            ..*ray
+        }
+    }
+}
+
+impl Intersectable for Lattice {
+    fn intersect(&self, hit: &mut Hit, ray: &Ray) {
+        // The copy computes the normal, just like a sphere stored at its place would
+        if let Some((_, copy, t_cell)) = self.nearest(ray, hit.distance) {
+            hit.distance -= t_cell;
+            copy.intersect(hit, &Lattice::advanced(ray, t_cell));
+            hit.distance += t_cell;
+        }
+    }
+
+    fn occluder_distance(&self, ray: &Ray, max_distance: RFloat) -> Option<RFloat> {
+        self.nearest(ray, max_distance).map(|(distance, _, _)| distance)
+    }
+}
+
+// Counts the normals computed by spheres on this thread, to verify which queries need them
+#[cfg(test)]
+thread_local!(pub static NORMALS_COMPUTED: Cell<usize> = const { Cell::new(0) });
+
+/// An infinite plane through point, facing into the direction of its normal
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Plane {
+    pub point: Vector,
+    /// Must be normalized
+    pub normal: Vector,
+}
+
+/// Rays closer to parallel to a plane than this cosine miss it, as their hits would be
+/// too far away to be precise
+const PLANE_MIN_COS: RFloat = 1e-6;
+
+impl DistanceMeasure for Plane {
+    #[inline(always)]
+    fn distance_from_ray(&self, r: &Ray) -> RFloat {
+        let denom = r.dir.dot(&self.normal);
+        if denom.abs() < PLANE_MIN_COS {
+            return f32::INFINITY;
+        }
+        let distance = (self.point - r.pos).dot(&self.normal) / denom;
+        if r.accepts(distance) { distance } else { f32::INFINITY }
+    }
+}
+
+impl Intersectable for Plane {
     fn intersect(&self, hit: &mut Hit, ray: &Ray) {
         let distance = self.distance_from_ray(ray);
         if distance >= hit.distance {
             return;
         }
         hit.distance = distance;
-        hit.pos = (ray.pos + (ray.dir.mulfed(distance) - self.center)).normalized();
+        hit.normal = self.normal;
+        hit.point = ray.pos + ray.dir.mulfed(distance);
+        // A plane has no size, but the error of the hit point grows with the distance
+        hit.scale = distance;
+        hit.material = None;
+    }
+}
+
+/// Rays hitting a triangle at a smaller cosine than this miss it, which includes all rays
+/// hitting zero-area triangles, or their back
+const TRIANGLE_MIN_DET: RFloat = 1e-8;
+
+/// A triangle, which faces along the cross product of its edges ab and ac, see `normal()`.
+/// It is only hit from that side.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Triangle {
+    pub a: Vector,
+    pub b: Vector,
+    pub c: Vector,
+}
+
+impl Triangle {
+    /// The direction the triangle faces, which is zero if it has no area
+    pub fn normal(&self) -> Vector {
+        (self.b - self.a).cross(&(self.c - self.a)).try_normalized().unwrap_or_default()
+    }
+
+    // Returns the distance of the hit, and its barycentric coordinates along the edges ab
+    // and ac, using the Möller-Trumbore algorithm
+    #[inline(always)]
+    fn barycentric_hit(&self, ray: &Ray) -> Option<(RFloat, RFloat, RFloat)> {
+        let (e1, e2) = (self.b - self.a, self.c - self.a);
+        let p = ray.dir.cross(&e2);
+        let det = e1.dot(&p);
+        // Negative for hits from behind
+        if det < TRIANGLE_MIN_DET * e1.len() * e2.len() {
+            return None;
+        }
+        let inv_det = det.recip();
+        let s = ray.pos - self.a;
+        let u = s.dot(&p) * inv_det;
+        if !(0.0..=1.0).contains(&u) {
+            return None;
+        }
+        let q = s.cross(&e1);
+        let v = ray.dir.dot(&q) * inv_det;
+        if v < 0.0 || u + v > 1.0 {
+            return None;
+        }
+        let distance = e2.dot(&q) * inv_det;
+        if ray.accepts(distance) { Some((distance, u, v)) } else { None }
+    }
+}
+
+impl Intersectable for Triangle {
+    fn intersect(&self, hit: &mut Hit, ray: &Ray) {
+        let (distance, u, v) = match self.barycentric_hit(ray) {
+            Some(h) if h.0 < hit.distance => h,
+            _ => return,
+        };
+        let (e1, e2) = (self.b - self.a, self.c - self.a);
+        hit.distance = distance;
+        hit.normal = e1.cross(&e2).normalized();
+        hit.point = self.a + e1.mulfed(u) + e2.mulfed(v);
+        hit.scale = e1.len().max(e2.len()).max((self.c - self.b).len());
+        hit.material = None;
+    }
+
+    fn occluder_distance(&self, ray: &Ray, max_distance: RFloat) -> Option<RFloat> {
+        self.barycentric_hit(ray).map(|h| h.0).filter(|&d| d < max_distance)
+    }
+}
+
+impl BoundingBox for Triangle {
+    fn bounds(&self) -> Aabb {
+        Aabb {
+            min: self.a.min(&self.b).min(&self.c),
+            max: self.a.max(&self.b).max(&self.c),
+        }
+    }
+}
+
+/// An axis-aligned bounding box
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Aabb {
+    pub min: Vector,
+    pub max: Vector,
+}
+
+impl Aabb {
+    /// A box containing nothing, which is the identity for union()
+    pub fn empty() -> Aabb {
+        Aabb {
+            min: Vector {
+                x: f32::INFINITY,
+                y: f32::INFINITY,
+                z: f32::INFINITY,
+            },
+            max: Vector {
+                x: f32::NEG_INFINITY,
+                y: f32::NEG_INFINITY,
+                z: f32::NEG_INFINITY,
+            },
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.min.x > self.max.x || self.min.y > self.max.y || self.min.z > self.max.z
+    }
+
+    /// Returns the smallest box containing both boxes
+    pub fn union(&self, o: &Aabb) -> Aabb {
+        Aabb {
+            min: self.min.min(&o.min),
+            max: self.max.max(&o.max),
+        }
+    }
+
+    pub fn center(&self) -> Vector {
+        (self.min + self.max).mulfed(0.5)
+    }
+
+    /// The length of the box along each axis
+    pub fn size(&self) -> Vector {
+        self.max - self.min
+    }
+}
+
+/// The distance at which the ray enters the box, which is t_min if it starts inside.
+/// Makes boxes usable as bounds of groups, like `TypedGroup<Aabb, Sphere>`.
+impl DistanceMeasure for Aabb {
+    #[inline(always)]
+    fn distance_from_ray(&self, r: &Ray) -> RFloat {
+        // The ray is within the slab between both sides of the box along each axis from
+        // t_enter to t_exit, and within the box where it is within all of them
+        let (mut t_enter, mut t_exit) = (r.t_min, r.t_max);
+        for i in 0..3 {
+            let inv = r.dir[i].recip();
+            let (t0, t1) = ((self.min[i] - r.pos[i]) * inv, (self.max[i] - r.pos[i]) * inv);
+            let (near, far) = if inv < 0.0 { (t1, t0) } else { (t0, t1) };
+            // min() and max() skip the NaN of rays starting on a side they are parallel to
+            t_enter = near.max(t_enter);
+            t_exit = far.min(t_exit);
+        }
+        if t_enter <= t_exit { t_enter } else { f32::INFINITY }
+    }
+}
+
+/// Items which know the box they fit into
+pub trait BoundingBox {
+    fn bounds(&self) -> Aabb;
+}
+
+impl BoundingBox for Sphere {
+    fn bounds(&self) -> Aabb {
+        let r = Vector {
+            x: self.radius,
+            y: self.radius,
+            z: self.radius,
+        };
+        Aabb {
+            min: self.center - r,
+            max: self.center + r,
+        }
     }
 }
 
 pub trait Intersectable {
     /// Return intersection point of ray with item (relative to the Ray !!)
     fn intersect(&self, &mut Hit, ray: &Ray);
+
+    /// Returns the distance of a hit closer than max_distance, if there is one.
+    /// It's cheaper than intersect() as it computes nothing but the distance, and stops at
+    /// the first hit it finds - which isn't necessarily the closest one.
+    fn occluder_distance(&self, ray: &Ray, max_distance: RFloat) -> Option<RFloat> {
+        let mut hit = Hit::missed();
+        hit.distance = max_distance;
+        self.intersect(&mut hit, ray);
+        if hit.distance < max_distance { Some(hit.distance) } else { None }
+    }
+
+    /// True if the ray hits anything closer than max_distance, see occluder_distance()
+    fn is_occluded(&self, ray: &Ray, max_distance: RFloat) -> bool {
+        self.occluder_distance(ray, max_distance).is_some()
+    }
 }
 
 pub trait DistanceMeasure {
     fn distance_from_ray(&self, r: &Ray) -> RFloat;
+
+    /// The distance at which the ray enters the volume we enclose, which is t_min if it
+    /// starts inside, or infinite if it misses. Groups test their bound with it, as
+    /// anything inside may be hit from there on.
+    fn entry_distance(&self, r: &Ray) -> RFloat {
+        self.distance_from_ray(r)
+    }
 }
 
 
 #[cfg(test)]
 mod primitive_tests {
-    use super::Ray;
+    use super::{Ray, Sphere, Plane, Hit, DistanceMeasure, Intersectable};
+    use std::f32;
+    use super::super::vec::Vector;
     use std::default::Default;
 
     #[test]
@@ -104,11 +657,69 @@ mod primitive_tests {
         let r1: Ray = Ray {
             pos: Default::default(),
             dir: Default::default(),
+            differentials: None,
+            t_min: 0.0,
+            t_max: f32::INFINITY,
         };
 
         let r2: Ray = Default::default();
         assert_eq!(r1, r2);
     }
+
+    #[test]
+    fn t_range() {
+        let s = Sphere::default();
+        let p = Plane {
+            point: Vector::new(0.0, 0.0, 2.0),
+            normal: Vector::new(0.0, 0.0, -1.0),
+        };
+        let ray = Ray::new(Vector::new(0.0, 0.0, -4.0), Vector::new(0.0, 0.0, 1.0));
+        let clipped = |t_min, t_max| Ray { t_min, t_max, ..ray };
+        let hits = |r: &Ray| (s.distance_from_ray(r), p.distance_from_ray(r));
+        assert_eq!(hits(&ray), (3.0, 6.0));
+
+        // Just beyond t_max, and just within it
+        assert_eq!(hits(&clipped(0.0, 2.999)), (f32::INFINITY, f32::INFINITY));
+        assert_eq!(hits(&clipped(0.0, 3.001)), (3.0, f32::INFINITY));
+        assert_eq!(hits(&clipped(0.0, 6.0)), (3.0, 6.0));
+        // Past the front of the sphere, the back is hit
+        assert_eq!(hits(&clipped(3.001, f32::INFINITY)), (5.0, 6.0));
+        assert_eq!(hits(&clipped(5.001, f32::INFINITY)), (f32::INFINITY, 6.0));
+
+        let mut h = Hit::missed();
+        s.intersect(&mut h, &clipped(0.0, 2.999));
+        p.intersect(&mut h, &clipped(0.0, 2.999));
+        assert!(h.has_missed());
+        assert_eq!(s.occluder_distance(&clipped(0.0, 2.999), f32::INFINITY), None);
+    }
+
+    #[test]
+    fn new() {
+        let pos = Vector {
+            x: 0.0,
+            y: 0.0,
+            z: -4.0,
+        };
+        let r = Ray::new(pos, Vector { z: 3.0, ..Vector::ZERO });
+        assert_eq!(r.dir, Vector { z: 1.0, ..Vector::ZERO });
+        assert_eq!(r.pos, pos);
+        assert_eq!(r.differentials, None);
+        assert_eq!(Ray::new_unnormalized(pos, r.dir), r);
+
+        let r = Ray::new(pos,
+                         Vector {
+                             x: 0.3,
+                             y: -0.2,
+                             z: 1.0,
+                         });
+        assert!((r.dir.len() - 1.0).abs() < 1e-6);
+        let s = Sphere {
+            center: pos + r.dir.mulfed(10.0),
+            radius: 2.0,
+            material: None,
+        };
+        assert!((s.distance_from_ray(&r) - 8.0).abs() < 1e-5);
+    }
 }
 
 #[cfg(test)]
@@ -126,6 +737,7 @@ mod sphere {
         let s = Sphere {
             center: Default::default(),
             radius: 1.0,
+            material: None,
         };
 
         let mut dir: Vector = Default::default();
@@ -137,6 +749,8 @@ mod sphere {
                 z: 0.0,
             },
             dir: dir,
+            differentials: None,
+            ..Default::default()
         };
         let mut r2 = r1;
         r2.dir.x = -r2.dir.x;   // invert direction
@@ -155,13 +769,13 @@ mod sphere {
         }
 
         {
-            let mut h = Hit {
-                distance: 2.0,
-                pos: Default::default(),
-            };
+            let mut h = Hit::missed();
+            h.distance = 2.0;
             s.intersect(&mut h, &r1);
             assert_eq!(h.distance, 1.0);
-            assert_eq!(h.pos.x, 1.0);
+            assert_eq!(h.normal.x, 1.0);
+            assert_eq!(h.point, r1.pos + r1.dir.mulfed(h.distance));
+            assert_eq!(h.scale, s.radius);
 
             h.distance = 0.5;
             s.intersect(&mut h, &r1);
@@ -172,12 +786,78 @@ mod sphere {
         }
     }
 
+    #[test]
+    fn hit_point_and_normal() {
+        // Off the origin, where the point and the normal differ
+        let s = Sphere {
+            center: Vector::new(1.0, 2.0, 3.0),
+            radius: 2.0,
+            material: None,
+        };
+        for &side in &[1.0, -1.0] {
+            let ray = Ray::new(s.center + Vector::new(side * 5.0, 0.0, 0.0),
+                               Vector::new(-side, 0.0, 0.0));
+            let mut h = Hit::missed();
+            s.intersect(&mut h, &ray);
+            assert_eq!(h.distance, 3.0);
+            assert_eq!(h.normal, Vector::new(side, 0.0, 0.0));
+            assert_eq!(h.point, s.center + Vector::new(side * 2.0, 0.0, 0.0));
+            assert_eq!(h.scale, s.radius);
+        }
+    }
+
     #[test]
     fn defaultdefault() {
         let s: Sphere = Default::default();
         assert!(s.radius != 0.0);
     }
 
+    #[test]
+    fn cutout() {
+        // The upper half is cut out, in front of a wall of a sphere which catches what passes
+        let half = Sphere {
+            material: Some(Material {
+                cutout: Some(Cutout {
+                    mask: AlphaMask::Vertical,
+                    threshold: 0.5,
+                }),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let wall = Sphere {
+            center: Vector::new(0.0, 0.0, 100.0),
+            radius: 95.0,
+            material: None,
+        };
+        let trace = |ray: &Ray| {
+            let mut h = Hit::missed();
+            half.intersect(&mut h, ray);
+            wall.intersect(&mut h, ray);
+            h
+        };
+        let eye = Vector::new(0.0, 0.0, -4.0);
+        let forward = Vector { z: 1.0, ..Vector::ZERO };
+
+        let upper = Ray::new(eye + Vector::UP.mulfed(0.5), forward);
+        let h = trace(&upper);
+        assert!((h.point.z - 5.0).abs() < 0.1, "{:?} must hit the wall", h.point);
+        assert_eq!(half.occluder_distance(&upper, f32::INFINITY), None);
+
+        let lower = Ray::new(eye - Vector::UP.mulfed(0.5), forward);
+        let h = trace(&lower);
+        assert!(h.point.z < 0.0 && h.scale == 1.0, "{:?} must hit the front", h.point);
+        assert_eq!(half.occluder_distance(&lower, f32::INFINITY), Some(h.distance));
+
+        // Through the gap in the upper half onto the inside of the lower one
+        let diagonal = Ray::new(Vector::new(0.0, 4.0, -4.0), Vector::new(0.0, -1.0, 1.0));
+        let h = trace(&diagonal);
+        assert!((h.point - Vector::new(0.0, -1.0, 1.0).normalized()).len() < 1e-5);
+
+        let opaque = Sphere { material: None, ..half };
+        assert_eq!(opaque.surface_distance(&upper), opaque.distance_from_ray(&upper));
+    }
+
     const NUM_ITERATIONS: usize = 10000;
 
     #[bench]
@@ -208,3 +888,282 @@ mod sphere {
     }
 
 }
+
+#[cfg(test)]
+mod plane {
+    use super::*;
+    use super::super::vec::Vector;
+
+    fn ground() -> Plane {
+        Plane {
+            point: Vector::new(0.0, -1.0, 0.0),
+            normal: Vector::UP,
+        }
+    }
+
+    #[test]
+    fn hit_from_above() {
+        let p = ground();
+        let ray = Ray::new(Vector::new(1.0, 3.0, 2.0), Vector::new(1.0, -1.0, 0.0));
+        let expected = 4.0 * 2.0f32.sqrt();
+        assert!((p.distance_from_ray(&ray) - expected).abs() < 1e-5);
+
+        let mut h = Hit::missed();
+        p.intersect(&mut h, &ray);
+        assert_eq!(h.distance, p.distance_from_ray(&ray));
+        assert_eq!(h.normal, p.normal);
+        assert!(h.point.approx_eq(&Vector::new(5.0, -1.0, 2.0), 1e-5));
+        assert_eq!(p.occluder_distance(&ray, f32::INFINITY), Some(h.distance));
+        assert_eq!(p.occluder_distance(&ray, h.distance), None);
+
+        // Planes are two-sided, but only in front of the ray
+        let from_below = Ray::new(Vector::new(0.0, -3.0, 0.0), Vector::UP);
+        assert_eq!(p.distance_from_ray(&from_below), 2.0);
+        let away = Ray::new(ray.pos, Vector::UP);
+        assert_eq!(p.distance_from_ray(&away), f32::INFINITY);
+    }
+
+    #[test]
+    fn parallel_misses() {
+        let p = ground();
+        for &y in &[3.0, -1.0, -3.0] {
+            let ray = Ray::new(Vector::new(0.0, y, 0.0), Vector::new(1.0, 0.0, 1.0));
+            assert_eq!(p.distance_from_ray(&ray), f32::INFINITY);
+            let mut h = Hit::missed();
+            p.intersect(&mut h, &ray);
+            assert_eq!(h.distance, Hit::missed().distance);
+        }
+        // Almost parallel rays would hit very far away
+        let ray = Ray::new(Vector::ZERO, Vector::new(1.0, -1e-8, 0.0));
+        assert_eq!(p.distance_from_ray(&ray), f32::INFINITY);
+    }
+}
+
+#[cfg(test)]
+mod triangle {
+    use super::*;
+    use super::super::vec::Vector;
+
+    // In the z=0 plane, facing -z, where the rays come from
+    fn setup() -> Triangle {
+        Triangle {
+            a: Vector::new(-1.0, -1.0, 0.0),
+            b: Vector::new(0.0, 2.0, 0.0),
+            c: Vector::new(1.0, -1.0, 0.0),
+        }
+    }
+
+    fn towards(t: &Triangle, target: Vector) -> Ray {
+        Ray::new(target + t.normal().mulfed(3.0), t.normal().mulfed(-1.0))
+    }
+
+    #[test]
+    fn centroid() {
+        let t = setup();
+        assert_eq!(t.normal(), Vector::new(0.0, 0.0, -1.0));
+        let centroid = (t.a + t.b + t.c).mulfed(1.0 / 3.0);
+        let ray = towards(&t, centroid);
+
+        let mut h = Hit::missed();
+        t.intersect(&mut h, &ray);
+        assert!((h.distance - 3.0).abs() < 1e-6);
+        assert_eq!(h.normal, t.normal());
+        assert!(h.point.approx_eq(&centroid, 1e-6));
+        assert_eq!(h.scale, 10.0f32.sqrt(), "the longest edge");
+        assert_eq!(t.occluder_distance(&ray, 10.0), Some(h.distance));
+        assert_eq!(t.occluder_distance(&ray, 2.0), None);
+
+        assert_eq!(t.bounds(),
+                   Aabb {
+                       min: Vector::new(-1.0, -1.0, 0.0),
+                       max: Vector::new(1.0, 2.0, 0.0),
+                   });
+    }
+
+    #[test]
+    fn misses() {
+        let t = setup();
+        let mut h = Hit::missed();
+        // Just below the edge from a to c
+        t.intersect(&mut h, &towards(&t, Vector::new(0.0, -1.001, 0.0)));
+        assert!(h.has_missed());
+        t.intersect(&mut h, &towards(&t, Vector::new(0.0, -0.999, 0.0)));
+        assert!(!h.has_missed());
+
+        let parallel = Ray::new(Vector::new(-5.0, 0.0, 0.0), Vector::new(1.0, 0.0, 0.0));
+        let mut h = Hit::missed();
+        t.intersect(&mut h, &parallel);
+        assert!(h.has_missed());
+
+        // From behind
+        let ray = towards(&t, Vector::ZERO);
+        let back = Ray::new(ray.pos.mulfed(-1.0), ray.dir.mulfed(-1.0));
+        t.intersect(&mut h, &back);
+        assert!(h.has_missed());
+        assert_eq!(t.occluder_distance(&back, 10.0), None);
+
+        let degenerate = Triangle { c: Vector::new(1.0, 5.0, 0.0), ..t };
+        assert_eq!(degenerate.normal(), Vector::ZERO);
+        degenerate.intersect(&mut h, &ray);
+        assert!(h.has_missed());
+    }
+}
+
+#[cfg(test)]
+mod aabb {
+    use super::*;
+    use super::super::vec::Vector;
+
+    fn unit_box() -> Aabb {
+        Aabb {
+            min: Vector::splat(-1.0),
+            max: Vector::splat(1.0),
+        }
+    }
+
+    #[test]
+    fn entering() {
+        let b = unit_box();
+        let ray = Ray::new(Vector::new(0.5, 0.0, -4.0), Vector::new(0.0, 0.0, 1.0));
+        assert_eq!(b.distance_from_ray(&ray), 3.0);
+        let away = Ray::new(ray.pos, ray.dir.mulfed(-1.0));
+        assert_eq!(b.distance_from_ray(&away), f32::INFINITY);
+
+        // Through the corner region, entering through the x side last
+        let diagonal = Ray::new(Vector::new(-3.0, -2.5, 0.0), Vector::new(1.0, 1.0, 0.0));
+        let expected = 2.0 * 2.0f32.sqrt();
+        assert!((b.distance_from_ray(&diagonal) - expected).abs() < 1e-5);
+    }
+
+    #[test]
+    fn missing_one_slab() {
+        let b = unit_box();
+        // Within the x and z slabs all the way, but never within the y one
+        let above = Ray::new(Vector::new(0.0, 1.5, -4.0), Vector::new(0.0, 0.0, 1.0));
+        assert_eq!(b.distance_from_ray(&above), f32::INFINITY);
+        // Leaving the x slab before entering the y one
+        let past = Ray::new(Vector::new(-3.0, -6.0, 0.0), Vector::new(1.0, 1.0, 0.0));
+        assert_eq!(b.distance_from_ray(&past), f32::INFINITY);
+        assert_eq!(Aabb::empty().distance_from_ray(&above), f32::INFINITY);
+    }
+
+    #[test]
+    fn inside() {
+        let b = unit_box();
+        for &dir in &[Vector::new(0.0, 0.0, 1.0), Vector::new(-1.0, 2.0, 0.5)] {
+            assert_eq!(b.distance_from_ray(&Ray::new(Vector::new(0.5, 0.0, 0.0), dir)), 0.0);
+        }
+    }
+}
+
+#[cfg(test)]
+mod lattice {
+    use super::*;
+    use super::super::rng::Rng;
+
+    fn setup_lattice() -> Lattice {
+        Lattice {
+            sphere: Sphere {
+                center: Vector {
+                    x: 0.25,
+                    y: -0.5,
+                    z: 0.0,
+                },
+                radius: 0.3,
+                material: None,
+            },
+            period: 1.5,
+        }
+    }
+
+    fn random_ray(rng: &mut Rng) -> Ray {
+        let mut v = || rng.next_float() * 2.0 - 1.0;
+        Ray {
+            pos: Vector {
+                x: v() * 4.0,
+                y: v() * 4.0,
+                z: v() * 4.0,
+            },
+            dir: Vector {
+                    x: v(),
+                    y: v(),
+                    z: v(),
+                }
+                .normalized(),
+            differentials: None,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn periodic() {
+        let l = setup_lattice();
+        let mut rng = Rng::new(7);
+        for _ in 0..100 {
+            let ray = random_ray(&mut rng);
+            let mut h = Hit::missed();
+            l.intersect(&mut h, &ray);
+            assert!(!h.has_missed());
+            for &(x, y, z) in &[(1.0, 0.0, 0.0), (0.0, -1.0, 0.0), (3.0, -2.0, 5.0)] {
+                let shift = Vector { x, y, z }.mulfed(l.period);
+                let shifted = Ray { pos: ray.pos + shift, ..ray };
+                let mut hs = Hit::missed();
+                l.intersect(&mut hs, &shifted);
+                assert!((hs.distance - h.distance).abs() < 1e-3,
+                        "{} != {}",
+                        hs.distance,
+                        h.distance);
+                assert!((hs.normal - h.normal).len() < 1e-3, "{:?} != {:?}", hs.normal, h.normal);
+                assert!((hs.point - shift - h.point).len() < 1e-3);
+            }
+        }
+    }
+
+    #[test]
+    fn nearest_copy() {
+        // Walking the cells finds the same copy as trying all of them
+        let l = setup_lattice();
+        let mut rng = Rng::new(11);
+        for _ in 0..100 {
+            let ray = random_ray(&mut rng);
+            let mut expected = f32::INFINITY;
+            for x in -9..10 {
+                for y in -9..10 {
+                    for z in -9..10 {
+                        let offset = Vector {
+                            x: x as RFloat * l.period,
+                            y: y as RFloat * l.period,
+                            z: z as RFloat * l.period,
+                        };
+                        let copy = Sphere {
+                            center: l.sphere.center + offset,
+                            ..l.sphere
+                        };
+                        expected = expected.min(copy.distance_from_ray(&ray));
+                    }
+                }
+            }
+            match l.occluder_distance(&ray, 8.0) {
+                Some(d) => assert!((d - expected).abs() < 1e-3, "{} != {}", d, expected),
+                None => assert!(expected >= 8.0, "missed the copy at {}", expected),
+            }
+        }
+
+        // Passing between the rows of spheres, the ray never hits any
+        let ray = Ray {
+            pos: Vector {
+                x: 0.0,
+                y: 0.25,
+                z: 0.0,
+            },
+            dir: Vector {
+                x: 1.0,
+                y: 0.0,
+                z: 0.0,
+            },
+            differentials: None,
+            ..Default::default()
+        };
+        assert!(!l.is_occluded(&ray, f32::INFINITY));
+    }
+}
@@ -1,26 +1,84 @@
+#[cfg(feature = "threadpool")]
 extern crate threadpool;
 
 /// Implements the actual raytracer which produces the final image
-use std::ops::{Drop, Deref};
+use std::ops::Drop;
 use std::time::{Duration, Instant};
-use std::sync::Arc;
-use std::{io, fs};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::{io, fs, fmt, cmp};
+use std::error::Error;
 use std::default::Default;
-use std::sync::mpsc::sync_channel;
+use std::sync::mpsc::{sync_channel, Receiver};
+use std::thread;
 use super::vec::{Vector, RFloat};
-use super::group::SphericalGroup;
-use super::primitive::{Intersectable, Ray, Hit};
+use super::group::{SphericalGroup, SphericalInstance, Pair};
+use super::primitive::{Intersectable, Ray, RayDifferentials, Hit, Plane, Material, Sphere,
+                       Lattice};
+use super::transform::Transform;
+use super::rng::{Rng, splitmix64};
+use super::color::{Color, ColorOpts, color_to_rgba, rgba_to_color};
+use super::camera::{Camera, Projection, Handedness};
 
 use std::io::Seek;
+#[cfg(feature = "threadpool")]
 use self::threadpool::ThreadPool;
+#[cfg(feature = "threadpool")]
+use std::ops::Deref;
+#[cfg(feature = "threadpool")]
+use std::sync::mpsc::SyncSender;
 
 use std::f32;
 
+const CHUNK_SIZE: u16 = 64;
+/// The most samples per axis a pixel can have, i.e. 16384 samples in total.
+/// Samples are summed up in f32, which keeps the error of the average below half a
+/// level of an 8 bit channel up to here. Beyond that, rounding errors add up to visible ones.
+pub const MAX_SAMPLES_PER_AXIS: u16 = 128;
+/// Shadow rays start this far off the surface, relative to the size of the hit item.
+/// That way, tiny items don't lose their contact shadows, while big ones don't shadow
+/// themselves due to imprecision.
+const SHADOW_BIAS: RFloat = 1e-4;
+/// The gamma rendered colors are encoded with by default, which is about what displays
+/// expect. Linear colors look too dark on them.
+const GAMMA: RFloat = 2.2;
+/// Shadow rays hitting something closer than this, relative to the size of the item they
+/// start on, are taken to hit that very item due to imprecision, and continue past it
+const SHADOW_T_MIN: RFloat = 1e-5;
+/// The amount of shadow rays sampling a light with a size, spread over its disk
+const LIGHT_SAMPLES: usize = 16;
+/// `PostAA` only blends where the luminance of a pixel and its neighbours differs by at
+/// least this many levels, and this fraction of the brightest of them, as FXAA does
+const EDGE_MIN_CONTRAST: RFloat = 8.0;
+const EDGE_RELATIVE_CONTRAST: RFloat = 0.125;
+/// The most a pixel is blended with its neighbour across an edge
+const EDGE_MAX_BLEND: RFloat = 0.5;
+/// How many pixels around each pixel `RenderOptions::denoise` averages along each axis
+const DENOISE_RADIUS: usize = 2;
+/// The standard deviations of the weights of denoised pixels, falling off with the distance
+/// in pixels, and with the difference of the linear colors and coverage
+const DENOISE_SPATIAL_SIGMA: RFloat = 1.5;
+const DENOISE_RANGE_SIGMA: RFloat = 0.2;
+/// The side length of the image `Renderer::self_test()` renders
+const SELF_TEST_SIZE: u16 = 64;
+/// The hash of the image `Renderer::self_test()` renders, see `image_hash()`.
+/// Changes which are meant to alter the default image must update it. The
+/// `default_render_unchanged` test fails with the new hash, which is what goes here.
+const SELF_TEST_HASH: u64 = 0x701b_c906_49ae_9c15;
+/// The color of spheres without a material in the classic palette
+const OBJECT: Vector = Material::DEFAULT_DIFFUSE;
+/// The color of missed rays in the default scenes, which is also used to shade shadows
+const BACKGROUND: Vector = Vector {
+    x: 0x22 as RFloat / 255.0,
+    y: 0x0a as RFloat / 255.0,
+    z: 0x0a as RFloat / 255.0,
+};
 
 pub trait RGBABufferWriter {
     /// To be called before writing the first pixel
     /// x and y are the total image resolution
-    fn begin(&mut self, x: u16, y: u16);
+    /// Fails if the writer can't allocate what it needs to hold the image
+    fn begin(&mut self, x: u16, y: u16) -> Result<(), RenderError>;
 
     /// Write the given RGBA buffer - it's image region might be anywhere within
     /// our confines of total x and y resolution.
@@ -30,16 +88,534 @@ pub trait RGBABufferWriter {
 }
 
 
+/// What to compute for each pixel
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum RenderTarget {
+    /// The fully lit and shaded image
+    Beauty,
+    /// Only the colors of the hit surfaces, without any lighting, shadows or ambient term.
+    /// Misses still show the background. Useful to debug geometry and materials.
+    Unlit,
+    /// The fraction of samples hitting any geometry, as opaque grayscale matte which is
+    /// white where a pixel is fully covered and black where nothing was hit.
+    /// Shading, lights and the background play no role.
+    Coverage,
+    /// How often the primary rays of a pixel were reflected, from black for none over red
+    /// and yellow to white for `RenderOptions::max_reflection_depth` bounces. Useful to find
+    /// where rays bounce the most, see `RenderSummary::bounces`.
+    Bounces,
+}
+
+/// How samples missing all geometry contribute to pixels which are partially covered,
+/// i.e. at the edges of objects
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub enum EdgeMode {
+    /// Misses add the background to the color, but nothing to the alpha. Thus edges are
+    /// blended into the background, and partially transparent.
+    #[default]
+    Blend,
+    /// Like Blend, but edges are fully opaque, as if the background was part of the object
+    Opaque,
+    /// Misses add the given color instead of the background, but nothing to the alpha.
+    /// Pixels missing all geometry still show the background.
+    /// Black makes for colors premultiplied by their alpha, see `AlphaOverRGBABufferWriter`.
+    Color(Vector),
+}
+
+/// A filter applied to the assembled image, after all tiles were rendered
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub enum PostAA {
+    #[default]
+    Off,
+    /// Blends pixels across edges in the luminance, like FXAA. This smoothes the stair steps
+    /// along the edges of geometry at the cost of a little blur, and is much cheaper than
+    /// more samples per pixel. Areas without edges stay as they are.
+    Morphological,
+}
+
+/// The colors of objects without material, and of the background
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub enum Palette {
+    /// Red spheres in front of a dark red background
+    #[default]
+    ClassicRed,
+    /// ClassicRed without its hue, keeping its luminance
+    Grayscale,
+    Custom { object: Vector, background: Vector },
+}
+
+impl Palette {
+    pub fn object(&self) -> Vector {
+        match *self {
+            Palette::ClassicRed => OBJECT,
+            Palette::Grayscale => Palette::grey(&OBJECT),
+            Palette::Custom { object, .. } => object,
+        }
+    }
+
+    pub fn background(&self) -> Vector {
+        match *self {
+            Palette::ClassicRed => BACKGROUND,
+            Palette::Grayscale => Palette::grey(&BACKGROUND),
+            Palette::Custom { background, .. } => background,
+        }
+    }
+
+    // The grey with the same luminance, using the Rec. 709 weights
+    fn grey(c: &Vector) -> Vector {
+        let l = 0.2126 * c.x + 0.7152 * c.y + 0.0722 * c.z;
+        Vector {
+            x: l,
+            y: l,
+            z: l,
+        }
+    }
+}
+
+
+/// The order in which tiles are rendered, which only shows in previews
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub enum TileOrder {
+    /// Row by row, from the top-left to the bottom-right
+    #[default]
+    Raster,
+    /// Spiralling out of the center, which is often where the interesting things are
+    Spiral,
+    /// In random order, seeded by the given value, which fills in the whole image at once
+    Shuffled(u64),
+}
+
 #[derive(Clone, Copy)]
 pub struct RenderOptions {
     pub width: u16,
     pub height: u16,
-    pub samples_per_pixel: u16,
+    /// Each pixel is sampled on a grid of samples_per_axis x samples_per_axis,
+    /// see `with_total_samples()` to set the total amount of samples instead.
+    /// It must be between 1 and `MAX_SAMPLES_PER_AXIS`.
+    pub samples_per_axis: u16,
+    /// The maximum amount of bytes a single image buffer may use. Writers are expected to
+    /// keep the full image in memory, which is why it is checked against this budget
+    /// before rendering.
+    pub memory_budget: usize,
+    /// Darkens pixels towards the image borders, proportional to the cos^4 falloff of a lens
+    /// with the angle between their rays and the one through the image center.
+    /// 0 disables the vignette, 1 applies the full falloff.
+    pub vignette: RFloat,
+    /// If false, no shadow rays are cast and all surfaces facing the light are lit.
+    /// This makes for considerably faster previews.
+    pub shadows: bool,
+    /// Depth images map distances from min_depth to max_depth to black to white.
+    /// Misses are as far away as max_depth.
+    pub min_depth: RFloat,
+    pub max_depth: RFloat,
+    /// Primary rays start this far in front of the eye, which clips away everything closer
+    /// to it. Useful if the eye is inside or very close to geometry.
+    pub near: RFloat,
+    /// The camera to render the scene from. If None, the scene's deprecated eye and
+    /// camera transform are used, see `Scene::eye_camera()`.
+    pub camera: Option<Camera>,
+    /// The coordinate system the scene is in, which is left-handed by default.
+    /// Scenes imported from right-handed tools render as intended with `Handedness::Right`.
+    pub handedness: Handedness,
+    pub target: RenderTarget,
+    /// If true, each sample is placed randomly within its cell of the pixel's
+    /// samples_per_axis x samples_per_axis grid, instead of at its corner. The positions
+    /// only depend on the seed and the pixel, see `pixel_rng()`.
+    pub jitter: bool,
+    pub tile_order: TileOrder,
+    pub edges: EdgeMode,
+    /// Only applied by `Renderer::render()` and its variants, and `Renderer::render_scoped()`,
+    /// which then keep a copy of the whole image in memory.
+    pub post_aa: PostAA,
+    /// If true, the finished image is smoothed with an edge-aware bilateral filter before
+    /// `post_aa`, which removes the noise of jittered samples or noisy backgrounds while
+    /// keeping the silhouettes of objects. It's applied like `post_aa`.
+    pub denoise: bool,
+    /// Seeds all randomness, which makes renders reproducible, see `pixel_rng()`
+    pub seed: u64,
+    /// Shadow and reflected rays start this far off the surface, relative to the size of the
+    /// hit item. Too small values let surfaces shadow themselves, which `RenderSummary`
+    /// reports.
+    pub shadow_bias: RFloat,
+    /// How many times a ray may bounce off reflective materials. Beyond it, and with the
+    /// default of 0, they look as if they were not reflective at all.
+    pub max_reflection_depth: u16,
+    /// How the rendered colors become pixels, like whether they are sRGB-encoded
+    pub color: ColorOpts,
+    /// If set, tiles which didn't start rendering before it are filled with the background
+    /// instead, which keeps renders from taking much longer than that.
+    /// See `RenderSummary::skipped_tiles`.
+    pub deadline: Option<Instant>,
+}
+
+impl Default for RenderOptions {
+    fn default() -> RenderOptions {
+        RenderOptions {
+            width: 1024,
+            height: 1024,
+            samples_per_axis: 1,
+            memory_budget: 1 << 30,
+            vignette: 0.0,
+            shadows: true,
+            min_depth: 0.0,
+            max_depth: 100.0,
+            near: 0.0,
+            camera: None,
+            handedness: Handedness::Left,
+            target: RenderTarget::Beauty,
+            jitter: false,
+            tile_order: TileOrder::Raster,
+            edges: EdgeMode::Blend,
+            post_aa: PostAA::Off,
+            denoise: false,
+            seed: 0,
+            shadow_bias: SHADOW_BIAS,
+            max_reflection_depth: 0,
+            color: ColorOpts {
+                gamma: GAMMA,
+                ..Default::default()
+            },
+            deadline: None,
+        }
+    }
+}
+
+impl RenderOptions {
+    /// Default options with the square of samples per pixel closest to total_samples,
+    /// but at least one, and at most `MAX_SAMPLES_PER_AXIS` squared
+    pub fn with_total_samples(total_samples: u32) -> RenderOptions {
+        let per_axis = (total_samples as f64)
+            .sqrt()
+            .round()
+            .clamp(1.0, MAX_SAMPLES_PER_AXIS as f64);
+        RenderOptions {
+            samples_per_axis: per_axis as u16,
+            ..Default::default()
+        }
+    }
+
+    /// How pixels are turned into bytes with these options, which allows to reproduce them
+    /// with `color_to_rgba()`. It's `color`, unless the target is no color at all, like
+    /// `RenderTarget::Coverage`, which stays linear.
+    /// Vignetting is applied to the colors before.
+    pub fn color_opts(&self) -> ColorOpts {
+        match self.target {
+            RenderTarget::Coverage | RenderTarget::Bounces => Default::default(),
+            RenderTarget::Beauty | RenderTarget::Unlit => self.color,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum RenderError {
+    /// An image buffer of `bytes` would exceed the memory budget
+    OverBudget { bytes: usize, budget: usize },
+    /// The system could not provide `bytes` for an image buffer
+    AllocationFailed { bytes: usize },
+    /// A rendering thread died before delivering its tile
+    WorkerPanicked,
+    /// The caller-provided buffer has `actual` bytes, but the image needs `expected` bytes
+    BufferSizeMismatch { expected: usize, actual: usize },
+    /// The scene can't be rendered, see `Scene::validate()`
+    InvalidScene(SceneError),
+    /// The camera is not finite, or it doesn't know where to look, see `Camera::basis()`
+    InvalidCamera(Camera),
+    /// `RenderOptions::samples_per_axis` is 0, or greater than `MAX_SAMPLES_PER_AXIS`
+    InvalidSamplesPerAxis(u16),
+    /// `Refinement::max_passes` is 0, which would never write the image
+    InvalidMaxPasses,
+}
+
+impl fmt::Display for RenderError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RenderError::OverBudget { bytes, budget } => {
+                write!(f,
+                       "An image buffer of {} bytes exceeds the memory budget of {} bytes",
+                       bytes,
+                       budget)
+            }
+            RenderError::AllocationFailed { bytes } => {
+                write!(f, "Failed to allocate an image buffer of {} bytes", bytes)
+            }
+            RenderError::WorkerPanicked => write!(f, "A rendering thread panicked"),
+            RenderError::BufferSizeMismatch { expected, actual } => {
+                write!(f,
+                       "The image needs a buffer of {} bytes, but got one of {} bytes",
+                       expected,
+                       actual)
+            }
+            RenderError::InvalidScene(ref err) => write!(f, "Invalid scene: {}", err),
+            RenderError::InvalidCamera(ref camera) => write!(f, "Invalid camera: {:?}", camera),
+            RenderError::InvalidSamplesPerAxis(samples) => {
+                write!(f,
+                       "{} samples per axis are not between 1 and {}",
+                       samples,
+                       MAX_SAMPLES_PER_AXIS)
+            }
+            RenderError::InvalidMaxPasses => write!(f, "At least one refinement pass is needed"),
+        }
+    }
+}
+
+impl Error for RenderError {}
+
+/// Describes the first problem found by `Scene::validate()`
+#[derive(Debug, PartialEq)]
+pub enum SceneError {
+    /// Spheres must have a finite center, and a finite radius greater than 0
+    DegenerateSphere { center: Vector, radius: RFloat },
+    /// The light direction must be finite and not zero
+    InvalidLight(Vector),
+    /// The eye position must be finite
+    InvalidEye(Vector),
+    /// The light size must be finite, and at least 0 but less than a right angle
+    InvalidLightSize(RFloat),
+    /// The period of a lattice must be finite, and at least twice the radius of its sphere
+    InvalidLatticePeriod { radius: RFloat, period: RFloat },
+}
+
+impl fmt::Display for SceneError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SceneError::DegenerateSphere { center, radius } => {
+                write!(f, "sphere at {:?} with radius {} is degenerate", center, radius)
+            }
+            SceneError::InvalidLight(dir) => write!(f, "the light direction {:?} is invalid", dir),
+            SceneError::InvalidEye(eye) => write!(f, "the eye position {:?} is invalid", eye),
+            SceneError::InvalidLightSize(size) => write!(f, "the light size {} is invalid", size),
+            SceneError::InvalidLatticePeriod { radius, period } => {
+                write!(f,
+                       "the lattice period {} is too small for spheres of radius {}",
+                       period,
+                       radius)
+            }
+        }
+    }
+}
+
+impl Error for SceneError {}
+
+/// Allows to observe and cancel a render while it is running
+#[derive(Default)]
+pub struct RenderControl<'a> {
+    /// Called with the amount of (completed, total) tiles, each time a tile was written.
+    /// It runs on the thread which called render, never on a worker.
+    pub progress: Option<&'a mut dyn FnMut(usize, usize)>,
+    /// Once set, no more tiles are rendered. Tiles in flight are still written, and the
+    /// render returns with an incomplete summary.
+    pub cancel: Option<Arc<AtomicBool>>,
+}
+
+impl<'a> RenderControl<'a> {
+    fn is_cancelled(&self) -> bool {
+        Renderer::is_cancelled(&self.cancel)
+    }
+}
+
+/// When `Renderer::render_progressive()` stops refining the image
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Refinement {
+    /// No more passes are started once this much time has passed since the render started
+    pub budget: Duration,
+    /// Stops once no color component of any pixel changed by more than this between two
+    /// passes, in linear units of 0 to 1
+    pub threshold: RFloat,
+    /// Stops after this many passes, the last of which uses max_passes^2 samples per pixel.
+    /// It can't be more than `MAX_SAMPLES_PER_AXIS`, and must be at least 1.
+    pub max_passes: u16,
+}
+
+impl Default for Refinement {
+    fn default() -> Refinement {
+        Refinement {
+            budget: Duration::from_secs(10),
+            // Half a level of 8 bit linear colors
+            threshold: 0.5 / 255.0,
+            max_passes: 16,
+        }
+    }
+}
+
+/// Describes a finished render
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RenderSummary {
+    /// The amount of threads which rendered tiles
+    pub threads: usize,
+    /// The amount of tiles written
+    pub tiles: usize,
+    /// False if the render was cancelled before all tiles were written, or tiles were skipped
+    pub complete: bool,
+    /// The time it took from the call to the last written tile
+    pub elapsed: Duration,
+    /// The amount of shadow rays which hit the surface they started from.
+    /// Many of them hint at a `RenderOptions::shadow_bias` too small for the scene.
+    pub self_intersections: usize,
+    /// The amount of tiles filled with the background as they would have started rendering
+    /// after `RenderOptions::deadline`
+    pub skipped_tiles: usize,
+    /// The amount of tile buffers which were allocated. Buffers are reused once their tile
+    /// was written, which is why there are usually far fewer of them than tiles.
+    pub allocated_buffers: usize,
+    /// How often the primary rays of the written tiles were reflected
+    pub bounces: BounceStats,
+}
+
+/// How often primary rays were reflected, including the reflected rays they spawned
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct BounceStats {
+    /// The amount of primary rays
+    pub rays: usize,
+    /// The bounces of all of them
+    pub total: usize,
+    /// The most bounces of a single one
+    pub max: u16,
+}
+
+impl BounceStats {
+    /// The average bounces of a primary ray, or 0 if there were none
+    pub fn mean(&self) -> f64 {
+        if self.rays == 0 {
+            0.0
+        } else {
+            self.total as f64 / self.rays as f64
+        }
+    }
+
+    fn add(&mut self, bounces: u16) {
+        self.rays += 1;
+        self.total += bounces as usize;
+        self.max = cmp::max(self.max, bounces);
+    }
+
+    fn merge(&mut self, other: &BounceStats) {
+        self.rays += other.rays;
+        self.total += other.total;
+        self.max = cmp::max(self.max, other.max);
+    }
+}
+
+impl fmt::Display for RenderSummary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f,
+               "Rendered {} tiles on {} threads in {:.3}s{}",
+               self.tiles,
+               self.threads,
+               self.elapsed.as_secs_f64(),
+               if self.complete { "" } else { " (incomplete)" })?;
+        if self.self_intersections > 0 {
+            write!(f,
+                   ", {} shadow rays hit their own surface",
+                   self.self_intersections)?;
+        }
+        if self.skipped_tiles > 0 {
+            write!(f, ", {} tiles skipped at the deadline", self.skipped_tiles)?;
+        }
+        if self.bounces.max > 0 {
+            write!(f,
+                   ", rays bounced up to {} times, {:.2} on average",
+                   self.bounces.max,
+                   self.bounces.mean())?;
+        }
+        Ok(())
+    }
 }
 
 pub struct Renderer;
 
-#[derive(Clone, Copy, PartialEq)]
+// The camera a render uses, with the basis its primary rays are built from
+#[derive(Clone, Copy)]
+struct View {
+    camera: Camera,
+    right: Vector,
+    up: Vector,
+    forward: Vector,
+}
+
+impl View {
+    // Fails if the camera is invalid, see `Camera::basis()`
+    fn new(o: &RenderOptions, scene: &Scene) -> Result<View, RenderError> {
+        View::from_camera(o, o.camera.unwrap_or_else(|| scene.eye_camera(o.handedness)))
+    }
+
+    fn from_camera(o: &RenderOptions, camera: Camera) -> Result<View, RenderError> {
+        match camera.basis(o.handedness) {
+            Some((right, up, forward)) => {
+                Ok(View {
+                    camera,
+                    right,
+                    up,
+                    forward,
+                })
+            }
+            None => Err(RenderError::InvalidCamera(camera)),
+        }
+    }
+
+    // Like new(), but for renderers which can't fail, and whose callers checked the view
+    fn expect(o: &RenderOptions, scene: &Scene) -> View {
+        View::new(o, scene).expect("a valid camera")
+    }
+}
+
+// A tile with the average color and coverage of its pixels, see trace_pixels(), the
+// amount of self-intersections of its shadow rays and the bounces of its primary rays
+type TracedTile = (ImageRegion, Vec<(Color, RFloat)>, usize, BounceStats);
+
+// Keeps the buffers of written tiles, to render the next tiles into them instead of
+// allocating new ones
+#[derive(Default)]
+struct TilePool {
+    free: Mutex<Vec<Vec<u8>>>,
+    // The amount of buffers allocated because none was free
+    allocated: AtomicUsize,
+}
+
+impl TilePool {
+    fn buffer(&self, r: &ImageRegion) -> RGBABuffer {
+        let free = self.free.lock().unwrap().pop();
+        match free {
+            Some(mut buf) => {
+                // All pixels are overwritten, and tiles tend to have the same size
+                buf.resize(r.area() * RGBABuffer::components(), 0);
+                RGBABuffer {
+                    buf,
+                    reg: *r,
+                    self_intersections: 0,
+                    bounces: Default::default(),
+                    skipped: false,
+                }
+            }
+            None => {
+                self.allocated.fetch_add(1, Ordering::Relaxed);
+                RGBABuffer::new(r)
+            }
+        }
+    }
+
+    fn recycle(&self, b: RGBABuffer) {
+        self.free.lock().unwrap().push(b.buf);
+    }
+
+    fn allocated(&self) -> usize {
+        self.allocated.load(Ordering::Relaxed)
+    }
+}
+
+// What write_tiles() saw of the tiles it wrote
+#[derive(Default, Debug, PartialEq)]
+struct TileStats {
+    written: usize,
+    self_intersections: usize,
+    bounces: BounceStats,
+    /// Tiles filled with the background, see `RenderOptions::deadline`
+    skipped: usize,
+}
+
+/// A rectangle of pixels, which is half-open: it includes its `l`eft and `b`ottom edges,
+/// but excludes its `r`ight and `t`op edges. Thus x ranges over l..r and y over b..t.
+/// Note that y grows downwards in the final image, so `b` is the first row.
+#[derive(Clone, Copy, PartialEq, Debug)]
 pub struct ImageRegion {
     l: u16,
     t: u16,
@@ -47,7 +623,50 @@ pub struct ImageRegion {
     b: u16,
 }
 
+// FNV-1a of all bytes of an image, which changes whenever a single pixel does
+fn image_hash(pixels: &[u8]) -> u64 {
+    pixels.iter().fold(0xcbf2_9ce4_8422_2325u64, |h, &b| {
+        (h ^ b as u64).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+/// Returns a generator for the samples of the pixel at x, y, derived from the seed of the
+/// whole image. As no state is shared between pixels, their samples are the same no matter
+/// which tile they are in, which thread renders it, or when.
+pub fn pixel_rng(seed: u64, x: u16, y: u16) -> Rng {
+    Rng::new(splitmix64(seed) ^ ((x as u64) << 16 | y as u64))
+}
+
 impl ImageRegion {
+    /// Like new(), but returns None unless the region has pixels and all of its edges fit
+    /// into u16, instead of producing a nonsensical region
+    pub fn from_xywh(x: u16, y: u16, width: u16, height: u16) -> Option<ImageRegion> {
+        if width == 0 || height == 0 {
+            return None;
+        }
+        match (x.checked_add(width), y.checked_add(height)) {
+            (Some(r), Some(t)) => {
+                Some(ImageRegion {
+                    l: x,
+                    t,
+                    r,
+                    b: y,
+                })
+            }
+            _ => None,
+        }
+    }
+
+    /// The region of width x height pixels whose first pixel is at x, y
+    pub fn new(x: u16, y: u16, width: u16, height: u16) -> ImageRegion {
+        ImageRegion {
+            l: x,
+            t: y + height,
+            r: x + width,
+            b: y,
+        }
+    }
+
     pub fn width(&self) -> u16 {
         self.r - self.l
     }
@@ -60,8 +679,18 @@ impl ImageRegion {
         self.width() as usize * self.height() as usize
     }
 
+    pub fn is_empty(&self) -> bool {
+        self.area() == 0
+    }
+
+    /// True if all pixels of `o` are ours. Empty regions have no pixels, and are never contained
     pub fn contains(&self, o: &ImageRegion) -> bool {
-        return o.l >= self.l && o.b >= self.b && o.t <= self.t && o.r <= self.r;
+        !o.is_empty() && o.l >= self.l && o.b >= self.b && o.t <= self.t && o.r <= self.r
+    }
+
+    /// True if at least one pixel is in both regions. Regions merely sharing an edge don't overlap
+    pub fn overlaps(&self, o: &ImageRegion) -> bool {
+        self.l < o.r && o.l < self.r && self.b < o.t && o.b < self.t
     }
 
     /// x and y absolute to our rectangle ! Returns offset relative to our buffer
@@ -74,6 +703,12 @@ impl ImageRegion {
 pub struct RGBABuffer {
     buf: Vec<u8>,
     reg: ImageRegion,
+    /// The amount of shadow rays which hit the surface they started on while rendering us
+    self_intersections: usize,
+    /// How often our primary rays were reflected
+    bounces: BounceStats,
+    /// True if we were filled with the background, as the deadline had passed
+    skipped: bool,
 }
 
 impl RGBABuffer {
@@ -81,7 +716,43 @@ impl RGBABuffer {
         let mut v = Vec::with_capacity(r.area() * RGBABuffer::components());
         let l = v.capacity();
         unsafe { v.set_len(l) };
-        RGBABuffer { buf: v, reg: *r }
+        RGBABuffer {
+            buf: v,
+            reg: *r,
+            self_intersections: 0,
+            bounces: Default::default(),
+            skipped: false,
+        }
+    }
+
+    /// Returns the amount of bytes a buffer for the given region would need, or an error if
+    /// that exceeds the budget
+    fn checked_size(r: &ImageRegion, budget: usize) -> Result<usize, RenderError> {
+        let bytes = r.area().saturating_mul(RGBABuffer::components());
+        if bytes > budget {
+            return Err(RenderError::OverBudget {
+                bytes,
+                budget,
+            });
+        }
+        Ok(bytes)
+    }
+
+    /// Like new(), but fails gracefully if the buffer exceeds the budget or can't be allocated.
+    /// The buffer is black and transparent, which is what cancelled renders leave behind
+    /// where tiles are missing.
+    fn try_new(r: &ImageRegion, budget: usize) -> Result<RGBABuffer, RenderError> {
+        let bytes = RGBABuffer::checked_size(r, budget)?;
+        let mut v = Vec::new();
+        v.try_reserve_exact(bytes).map_err(|_| RenderError::AllocationFailed { bytes })?;
+        v.resize(bytes, 0);
+        Ok(RGBABuffer {
+            buf: v,
+            reg: *r,
+            self_intersections: 0,
+            bounces: Default::default(),
+            skipped: false,
+        })
     }
 
     fn components() -> usize {
@@ -89,23 +760,16 @@ impl RGBABuffer {
     }
 
     /// x and y must be absolute to our recangle !
-    fn set_pixel_from_vector(&mut self, x: u16, y: u16, p: &Vector, alpha: RFloat) {
+    /// The color is converted as configured by opts, see `color_to_rgba()`.
+    fn set_pixel_from_color(&mut self,
+                            x: u16,
+                            y: u16,
+                            color: &Color,
+                            alpha: RFloat,
+                            opts: &ColorOpts) {
         let ofs = self.reg.buffer_offset(x, y) * RGBABuffer::components();
-        let c = &mut self.buf[ofs..ofs + RGBABuffer::components()];
-
-        let scale = |v| -> u8 {
-
-            let r = 0.5 + 255.0 * v;
-            if r > 255.0 {
-                return 255;
-            }
-            r as u8
-        };
-
-        c[0] = scale(p.x);
-        c[1] = scale(p.y);
-        c[2] = scale(p.z);
-        c[3] = scale(alpha);
+        self.buf[ofs..ofs + RGBABuffer::components()]
+            .copy_from_slice(&color_to_rgba(&color.to_linear_rgb(), alpha, opts));
     }
 
     /// buffer must be contained in our rectangle
@@ -125,359 +789,4677 @@ impl RGBABuffer {
         }
     }
 
-    fn buffer(&self) -> &Vec<u8> {
+    // Returns a copy of the given region of ours, which must contain it
+    fn crop(&self, r: &ImageRegion) -> RGBABuffer {
+        assert!(self.reg.contains(r));
+        let mut b = RGBABuffer::new(r);
+        let w = r.width() as usize * RGBABuffer::components();
+        for y in r.b..r.t {
+            let ofs = r.buffer_offset(r.l, y) * RGBABuffer::components();
+            let our_ofs = self.reg.buffer_offset(r.l, y) * RGBABuffer::components();
+            b.buf[ofs..ofs + w].clone_from_slice(&self.buf[our_ofs..our_ofs + w]);
+        }
+        b
+    }
+
+    /// Returns a copy scaled down to at most longest_edge pixels along its longer side,
+    /// keeping the aspect ratio. Each pixel is the average of the pixels it covers.
+    fn thumbnail(&self, longest_edge: u16) -> RGBABuffer {
+        let (w, h) = (self.reg.width() as usize, self.reg.height() as usize);
+        let longest_edge = cmp::max(longest_edge as usize, 1);
+        let scaled = |edge: usize| cmp::max(edge * longest_edge / cmp::max(w, h), 1);
+        let (tw, th) = if cmp::max(w, h) <= longest_edge {
+            (w, h)
+        } else {
+            (scaled(w), scaled(h))
+        };
+
+        let mut thumb = RGBABuffer::new(&ImageRegion {
+            l: 0,
+            r: tw as u16,
+            b: 0,
+            t: th as u16,
+        });
+        let c = RGBABuffer::components();
+        for ty in 0..th {
+            for tx in 0..tw {
+                let mut sum = [0usize; 4];
+                let (x0, x1) = (tx * w / tw, (tx + 1) * w / tw);
+                let (y0, y1) = (ty * h / th, (ty + 1) * h / th);
+                for y in y0..y1 {
+                    for x in x0..x1 {
+                        let ofs = (y * w + x) * c;
+                        for (s, &v) in sum.iter_mut().zip(&self.buf[ofs..ofs + c]) {
+                            *s += v as usize;
+                        }
+                    }
+                }
+                let count = (x1 - x0) * (y1 - y0);
+                let ofs = (ty * tw + tx) * c;
+                for (d, s) in thumb.buf[ofs..ofs + c].iter_mut().zip(&sum) {
+                    *d = ((s + count / 2) / count) as u8;
+                }
+            }
+        }
+        thumb
+    }
+
+    /// Returns a copy which is blended across luminance edges, see `PostAA::Morphological`.
+    /// Pixels on our border are copied as they are.
+    fn smooth_edges(&self) -> RGBABuffer {
+        let (w, h) = (self.reg.width() as usize, self.reg.height() as usize);
+        let c = RGBABuffer::components();
+        let luma: Vec<RFloat> = self.buf
+            .chunks(c)
+            .map(|p| 0.299 * p[0] as RFloat + 0.587 * p[1] as RFloat + 0.114 * p[2] as RFloat)
+            .collect();
+        let mut smooth = self.crop(&self.reg);
+        for y in 1..cmp::max(h, 1) - 1 {
+            for x in 1..cmp::max(w, 1) - 1 {
+                let i = y * w + x;
+                let (m, n, s) = (luma[i], luma[i + w], luma[i - w]);
+                let (e, west) = (luma[i + 1], luma[i - 1]);
+                let hi = m.max(n).max(s).max(e).max(west);
+                let contrast = hi - m.min(n).min(s).min(e).min(west);
+                if contrast < EDGE_MIN_CONTRAST.max(hi * EDGE_RELATIVE_CONTRAST) {
+                    continue;
+                }
+                // Blend with the neighbour across the edge, which is horizontal if the luminance
+                // changes more vertically than horizontally
+                let across = if (n + s - 2.0 * m).abs() >= (e + west - 2.0 * m).abs() {
+                    if (n - m).abs() >= (s - m).abs() { i + w } else { i - w }
+                } else if (e - m).abs() >= (west - m).abs() {
+                    i + 1
+                } else {
+                    i - 1
+                };
+                // Pixels sticking out of their neighbourhood the most, like the corners of
+                // stair steps, are blended the most
+                let t = (((n + s + e + west) * 0.25 - m).abs() / contrast).min(1.0);
+                let smoothstep = t * t * (3.0 - 2.0 * t);
+                let weight = smoothstep * smoothstep * EDGE_MAX_BLEND;
+                for k in 0..c {
+                    let p = self.buf[i * c + k] as RFloat;
+                    let q = self.buf[across * c + k] as RFloat;
+                    smooth.buf[i * c + k] = (p + (q - p) * weight).round() as u8;
+                }
+            }
+        }
+        smooth
+    }
+
+    /// Returns a copy in which each pixel is the average of the pixels around it, weighted by
+    /// their distance and how similar their colors and coverage are, see
+    /// `RenderOptions::denoise`. Colors are decoded with the given options, and averaged in
+    /// linear space.
+    fn denoised(&self, color: &ColorOpts) -> RGBABuffer {
+        let (w, h) = (self.reg.width() as usize, self.reg.height() as usize);
+        let c = RGBABuffer::components();
+        // Exposure was applied to our colors already
+        let color = ColorOpts {
+            exposure: 0.0,
+            ..*color
+        };
+        let linear: Vec<(Vector, RFloat)> =
+            self.buf.chunks(c).map(|p| rgba_to_color(p, &color)).collect();
+        let spatial = -0.5 / (DENOISE_SPATIAL_SIGMA * DENOISE_SPATIAL_SIGMA);
+        let range = -0.5 / (DENOISE_RANGE_SIGMA * DENOISE_RANGE_SIGMA);
+        // The coordinates of the pixels around v along an axis of the given size
+        let around = |v: usize, size: usize| {
+            v.saturating_sub(DENOISE_RADIUS)..=cmp::min(v + DENOISE_RADIUS, size - 1)
+        };
+        let mut denoised = RGBABuffer::new(&self.reg);
+        for y in 0..h {
+            for x in 0..w {
+                let (p, p_alpha) = linear[y * w + x];
+                let (mut sum, mut alpha, mut total) = (Vector::ZERO, 0.0, 0.0);
+                for ny in around(y, h) {
+                    for nx in around(x, w) {
+                        let (q, q_alpha) = linear[ny * w + nx];
+                        let (dx, dy) = (nx as RFloat - x as RFloat, ny as RFloat - y as RFloat);
+                        let d = q - p;
+                        let difference = d.dot(&d) + (q_alpha - p_alpha) * (q_alpha - p_alpha);
+                        let weight = ((dx * dx + dy * dy) * spatial + difference * range).exp();
+                        sum += q.mulfed(weight);
+                        alpha += q_alpha * weight;
+                        total += weight;
+                    }
+                }
+                let ofs = (y * w + x) * c;
+                denoised.buf[ofs..ofs + c]
+                    .copy_from_slice(&color_to_rgba(&sum.mulfed(total.recip()),
+                                                    alpha / total,
+                                                    &color));
+            }
+        }
+        denoised
+    }
+
+    /// RGBA pixels of our region, row by row
+    pub fn buffer(&self) -> &Vec<u8> {
         &self.buf
     }
 
-    fn region(&self) -> &ImageRegion {
+    pub fn region(&self) -> &ImageRegion {
         &self.reg
     }
 }
 
 
+/// A horizontal plane below the scene, receiving its shadows
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Ground {
+    pub plane: Plane,
+    pub color: Vector,
+}
+
+/// Computes the color of a ray which didn't hit anything
+pub type BackgroundFn = Box<dyn Fn(&Ray) -> Vector + Send + Sync>;
+
 pub struct Scene {
     pub group: SphericalGroup,
+    /// Additional, possibly shared groups placed into the world with their own transform
+    pub instances: Vec<SphericalInstance>,
+    /// If set, copies of its sphere fill all of space, in addition to everything else
+    pub lattice: Option<Lattice>,
     pub directional_light: Vector,
+    /// The angular radius of the light in radians, like the one of the sun as seen from
+    /// earth. 0 casts hard shadows, otherwise they soften with the distance to their occluder.
+    pub light_size: RFloat,
+    /// Deprecated, use `RenderOptions::camera` instead, which allows to render the same
+    /// scene from different cameras. Only used if there is none, see `eye_camera()`.
     pub eye: Vector,
+    /// Deprecated like eye, and applied to it as well as all primary ray directions
+    pub camera: Transform,
+    /// Shades all rays missing the scene, which allows for procedural skies
+    pub background: BackgroundFn,
+    /// If set, it catches the shadows of everything above it
+    pub ground: Option<Ground>,
+    /// Colors spheres without a material, and shadows. Use `with_palette()` to also set the
+    /// background to the one of the palette.
+    pub palette: Palette,
+}
+
+impl Intersectable for Scene {
+    fn intersect(&self, hit: &mut Hit, ray: &Ray) {
+        self.group.intersect(hit, ray);
+        for instance in self.instances.iter() {
+            instance.intersect(hit, ray);
+        }
+        if let Some(ref lattice) = self.lattice {
+            lattice.intersect(hit, ray);
+        }
+    }
+
+    fn occluder_distance(&self, ray: &Ray, max_distance: RFloat) -> Option<RFloat> {
+        self.group
+            .occluder_distance(ray, max_distance)
+            .or_else(|| {
+                self.instances.iter().filter_map(|i| i.occluder_distance(ray, max_distance)).next()
+            })
+            .or_else(|| self.lattice.and_then(|l| l.occluder_distance(ray, max_distance)))
+    }
 }
 
 impl Default for Scene {
     fn default() -> Scene {
         Scene {
-            group: SphericalGroup::pyramid(8,
-                                           &Vector {
-                                               x: 0.0,
-                                               y: -1.0,
-                                               z: 0.0,
-                                           },
-                                           1.0),
-            directional_light: Vector {
-                    x: -1.0,
-                    y: -3.0,
-                    z: 2.0,
-                }
-                .normalized(),
-            eye: Vector {
-                x: 0.0,
-                y: 0.0,
-                z: -4.0,
+            group: SphericalGroup::pyramid(8, &Vector::new(0.0, -1.0, 0.0), 1.0),
+            instances: Vec::new(),
+            lattice: None,
+            directional_light: Vector::new(-1.0, -3.0, 2.0).normalized(),
+            light_size: 0.0,
+            eye: Vector::new(0.0, 0.0, -4.0),
+            camera: Transform::identity(),
+            background: Box::new(|_| BACKGROUND),
+            ground: None,
+            palette: Palette::ClassicRed,
+        }
+    }
+}
+
+impl Scene {
+    // Like intersect(), but also considers the ground, which is returned if it was hit.
+    // It's not part of intersect() as the ground can't cast shadows.
+    fn intersect_with_ground(&self, hit: &mut Hit, ray: &Ray) -> Option<&Ground> {
+        self.intersect(hit, ray);
+        match self.ground {
+            Some(ref ground) => {
+                let distance = hit.distance;
+                ground.plane.intersect(hit, ray);
+                if hit.distance < distance { Some(ground) } else { None }
+            }
+            None => None,
+        }
+    }
+
+    /// The camera at eye, looking along +z (or -z if right-handed), with the camera
+    /// transform applied to it. It's used for rendering if `RenderOptions::camera` is None.
+    pub fn eye_camera(&self, handedness: Handedness) -> Camera {
+        Camera {
+                eye: self.eye,
+                look_at: self.eye + handedness.forward(),
+                ..Default::default()
+            }
+            .transformed(&self.camera)
+    }
+
+    /// Checks that all geometry, the light and the eye are well-defined, as degenerate
+    /// ones produce garbage at best. Renderers call it before rendering.
+    pub fn validate(&self) -> Result<(), SceneError> {
+        let light = self.directional_light;
+        if !light.is_finite() || light.try_normalized().is_none() {
+            return Err(SceneError::InvalidLight(light));
+        }
+        if !(0.0..f32::consts::FRAC_PI_2).contains(&self.light_size) {
+            return Err(SceneError::InvalidLightSize(self.light_size));
+        }
+        if !self.eye.is_finite() {
+            return Err(SceneError::InvalidEye(self.eye));
+        }
+        let mut is_degenerate = |s: &Sphere| {
+            !s.center.is_finite() || !s.radius.is_finite() || s.radius <= 0.0
+        };
+        let degenerate = self.group
+            .find_item(&mut is_degenerate)
+            .or_else(|| {
+                self.instances.iter().filter_map(|i| i.group.find_item(&mut is_degenerate)).next()
+            })
+            .or_else(|| self.lattice.as_ref().map(|l| &l.sphere).filter(|s| is_degenerate(s)));
+        if let Some(s) = degenerate {
+            return Err(SceneError::DegenerateSphere {
+                center: s.center,
+                radius: s.radius,
+            });
+        }
+        match self.lattice {
+            Some(l) if !(l.period.is_finite() && l.sphere.radius <= l.period * 0.5) => {
+                Err(SceneError::InvalidLatticePeriod {
+                    radius: l.sphere.radius,
+                    period: l.period,
+                })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Uses the given palette, for the background as well
+    pub fn with_palette(mut self, palette: Palette) -> Scene {
+        let background = palette.background();
+        self.palette = palette;
+        self.background = Box::new(move |_| background);
+        self
+    }
+
+    /// Adds a ground plane at the given height, the default pyramid rests on -2
+    pub fn with_ground(mut self, height: RFloat, color: Vector) -> Scene {
+        self.ground = Some(Ground {
+            plane: Plane {
+                point: Vector {
+                    x: 0.0,
+                    y: height,
+                    z: 0.0,
+                },
+                normal: Vector {
+                    x: 0.0,
+                    y: 1.0,
+                    z: 0.0,
+                },
             },
+            color,
+        });
+        self
+    }
+
+    /// A scene simple enough to compute its pixels by hand: a unit sphere at the origin,
+    /// lit head-on by a light shining along +z, just like the eye looks at it.
+    /// The center pixel looks straight at the sphere, where the normal faces the light
+    /// directly (n.l == -1), so it must receive the full object color plus ambient.
+    pub fn unit_test_scene() -> Scene {
+        let mut group: SphericalGroup = Default::default();
+        group.children.push(Pair::Item(Default::default()));
+        group.bound = Default::default();
+
+        Scene {
+            group,
+            instances: Vec::new(),
+            lattice: None,
+            directional_light: Vector::new(0.0, 0.0, 1.0),
+            light_size: 0.0,
+            eye: Vector::new(0.0, 0.0, -4.0),
+            camera: Transform::identity(),
+            background: Box::new(|_| BACKGROUND),
+            ground: None,
+            palette: Palette::ClassicRed,
         }
     }
 }
 
+/// What a single ray contributes to its pixel, besides its color
+struct Sample {
+    /// 1 if the ray hit any geometry, which makes for the alpha of the pixel
+    coverage: RFloat,
+    /// 1 if the hit surface receives light, 0 if it faces away from it or is in shadow.
+    /// It's already part of the color, and only kept separately for inspection.
+    #[allow(dead_code)]
+    lit: RFloat,
+    /// True if the shadow ray hit the surface it started on, see `SHADOW_T_MIN`
+    self_intersection: bool,
+    /// How often the ray was reflected, see `RenderOptions::max_reflection_depth`
+    bounces: u16,
+}
+
 impl Renderer {
+    // Traces reflected rays as long as depth is below the max_reflection_depth of the options,
+    // which makes 0 the depth of primary rays
     #[inline]
-    fn raytrace(s: &Scene, r: &Ray, c: &mut Vector) -> RFloat {
-        const OBJECT: Vector = Vector {
-            x: 0xae as RFloat / 255.0,
-            y: 0x31 as RFloat / 255.0,
-            z: 0x31 as RFloat / 255.0,
-        };
-        const BACKGROUND: Vector = Vector {
-            x: 0x22 as RFloat / 255.0,
-            y: 0x0a as RFloat / 255.0,
-            z: 0x0a as RFloat / 255.0,
-        };
-        const AMBIENT_OFFSET: Vector = Vector {
-            x: BACKGROUND.x * 0.8,
-            y: BACKGROUND.y * 0.8,
-            z: BACKGROUND.z * 0.8,
-        };
-
+    fn raytrace(o: &RenderOptions, s: &Scene, r: &Ray, c: &mut Color, depth: u16) -> Sample {
         let mut h = Hit::missed();
-        s.group.intersect(&mut h, r);
+        let color = Color::from_linear_rgb(match s.intersect_with_ground(&mut h, r) {
+            Some(ground) => ground.color,
+            None => h.material.map_or_else(|| s.palette.object(), |m| m.diffuse),
+        });
         if h.has_missed() {
-            *c = *c + BACKGROUND;
-            return 0.0;
+            return Renderer::miss(s, r, c);
         }
-        let g = h.pos.dot(&s.directional_light);
-        if g >= 0.0 {
-            *c = *c + AMBIENT_OFFSET;
-            return 0.0;
+        // Surfaces are two-sided, and seen from behind if the eye is inside a sphere or
+        // below the ground. Their normal must face us, or they would be lit from outside.
+        if h.normal.dot(&r.dir) > 0.0 {
+            h.normal = h.normal.mulfed(-1.0);
         }
-        let p = r.pos + (r.dir.mulfed(h.distance)) + *h.pos.mulf(h.distance * f32::EPSILON.sqrt());
+        let reflectivity = h.material.map_or(0.0, |m| m.reflectivity);
+        if reflectivity <= 0.0 || depth >= o.max_reflection_depth ||
+           o.target == RenderTarget::Coverage {
+            return Renderer::shade(o, s, r, &mut h, color, c);
+        }
+        let mut local: Color = Default::default();
+        let sample = Renderer::shade(o, s, r, &mut h, color, &mut local);
+        let reflected_ray = Ray::new(h.point + h.normal.mulfed(h.scale * o.shadow_bias),
+                                     r.dir.reflect(&h.normal));
+        let mut reflected: Color = Default::default();
+        let bounces = Renderer::raytrace(o, s, &reflected_ray, &mut reflected, depth + 1).bounces;
+        *c = *c + local.mulfed(1.0 - reflectivity) + reflected.mulfed(reflectivity);
+        Sample {
+            bounces: bounces + 1,
+            ..sample
+        }
+    }
 
+    // The color of the surface at the hit, which faces the ray, without reflections
+    #[inline]
+    fn shade(o: &RenderOptions, s: &Scene, r: &Ray, h: &mut Hit, color: Color, c: &mut Color)
+             -> Sample {
+        let ambient = Color::from_linear_rgb(s.palette.background().mulfed(0.8));
+        if o.target == RenderTarget::Coverage {
+            return Sample {
+                coverage: 1.0,
+                lit: 1.0,
+                self_intersection: false,
+                bounces: 0,
+            };
+        }
+        h.footprint = r.footprint(h);
+        // Light sources look the same in every target, and can't be in shadow
+        if let Some(emission) = h.material.filter(Material::is_emissive).map(|m| m.emission) {
+            *c = *c + Color::from_linear_rgb(emission);
+            return Sample {
+                coverage: 1.0,
+                lit: 1.0,
+                self_intersection: false,
+                bounces: 0,
+            };
+        }
+        if o.target == RenderTarget::Unlit {
+            *c = *c + color;
+            return Sample {
+                coverage: 1.0,
+                lit: 1.0,
+                self_intersection: false,
+                bounces: 0,
+            };
+        }
+        // The scene's light direction needn't be normalized, but Lambert's cosine law needs
+        // it to be. Surfaces facing away from the light only get the ambient term.
+        let to_light = s.directional_light.mulfed(-1.0).normalized();
+        let lambert = h.normal.dot(&to_light).max(0.0);
+        if lambert == 0.0 {
+            *c = *c + ambient;
+            return Sample {
+                coverage: 1.0,
+                lit: 0.0,
+                self_intersection: false,
+                bounces: 0,
+            };
+        }
         // if there is something between us and the light, we are in shadow
-        h.set_missed();
-        s.group.intersect(&mut h,
-                          &Ray {
-                              pos: p,
-                              dir: s.directional_light.mulfed(-1.0),
-                          });
-        if h.has_missed() {
-            *c = *c + OBJECT.mulfed(-g) + AMBIENT_OFFSET;
-            return 1.0;
+        let mut self_intersection = false;
+        let mut lit = 1.0;
+        if o.shadows {
+            let t_min = h.scale * SHADOW_T_MIN;
+            let pos = h.point + h.normal.mulfed(h.scale * o.shadow_bias);
+            let mut is_occluded = |dir: Vector| {
+                let mut shadow_ray = Ray::new(pos, dir);
+                // Asked separately, as occluder_distance() may find a farther occluder first
+                if s.is_occluded(&shadow_ray, t_min) {
+                    self_intersection = true;
+                    shadow_ray.t_min = t_min;
+                }
+                s.is_occluded(&shadow_ray, f32::INFINITY)
+            };
+            lit = if s.light_size > 0.0 {
+                // Only part of a light with a size is hidden in the penumbra, which widens
+                // with the distance between the occluder and us
+                let visible = Renderer::light_directions(to_light, s.light_size)
+                    .filter(|&dir| !is_occluded(dir))
+                    .count();
+                visible as RFloat / LIGHT_SAMPLES as RFloat
+            } else if is_occluded(to_light) {
+                0.0
+            } else {
+                1.0
+            };
+        }
+        let shadow = Color::from_linear_rgb(s.palette.background());
+        if lit == 1.0 {
+            *c = *c + color.mulfed(lambert) + ambient;
+        } else if lit == 0.0 {
+            *c = *c + shadow + ambient.mulfed(lambert);
         } else {
-            *c = *c + BACKGROUND + AMBIENT_OFFSET.mulfed(-g);
-            return 0.0;
+            *c = *c + (color.mulfed(lambert) + ambient).mulfed(lit) +
+                 (shadow + ambient.mulfed(lambert)).mulfed(1.0 - lit);
+        }
+        Sample {
+            coverage: 1.0,
+            lit,
+            self_intersection,
+            bounces: 0,
         }
     }
 
-    // Render region is inherently single-threaded
-    pub fn render_region(o: &RenderOptions, scene: &Scene, buf: &mut RGBABuffer) {
-        let ssf = o.samples_per_pixel as RFloat;
-        let total_samples_per_pixel_recip = (ssf * ssf).recip();
-        let region = *buf.region();
-
-        let width = o.width as RFloat;
-        let height = o.height as RFloat;
-
-        let mut ray = Ray {
-            pos: scene.eye,
-            dir: Default::default(),
+    // The directions to LIGHT_SAMPLES points spread evenly over the disk of a light with the
+    // given angular radius, in a spiral around its center
+    fn light_directions(to_light: Vector, size: RFloat) -> impl Iterator<Item = Vector> {
+        const GOLDEN_ANGLE: RFloat = 2.399_963;
+        let axis = if to_light.y.abs() < 0.9 {
+            Vector::UP
+        } else {
+            Vector { x: 1.0, ..Vector::ZERO }
         };
+        let u = to_light.cross(&axis).normalized();
+        let v = to_light.cross(&u);
+        let radius = size.tan();
+        (0..LIGHT_SAMPLES).map(move |i| {
+            let r = radius * ((i as RFloat + 0.5) / LIGHT_SAMPLES as RFloat).sqrt();
+            let (sin, cos) = (i as RFloat * GOLDEN_ANGLE).sin_cos();
+            (to_light + u.mulfed(r * cos) + v.mulfed(r * sin)).normalized()
+        })
+    }
 
-        for y in region.b..region.t {
-            for x in region.l..region.r {
-                let mut g: Vector = Default::default();
-                let mut alpha: RFloat = 0.0;
+    // The sample of a ray which hits nothing
+    #[inline]
+    fn miss(s: &Scene, r: &Ray, c: &mut Color) -> Sample {
+        *c = *c + Color::from_linear_rgb((s.background)(r));
+        Sample {
+            coverage: 0.0,
+            lit: 0.0,
+            self_intersection: false,
+            bounces: 0,
+        }
+    }
 
-                for ssx in 0..o.samples_per_pixel {
-                    for ssy in 0..o.samples_per_pixel {
-                        let xres = x as RFloat + ssx as RFloat / ssf;
-                        let yres = y as RFloat + ssy as RFloat / ssf;
-                        ray.dir.x = xres - width / 2.0;
-                        ray.dir.y = (height - yres) - height / 2.0;
-                        ray.dir.z = width;
-                        ray.dir.normalize();
-                        alpha += Renderer::raytrace(scene, &ray, &mut g);
+    // True if no ray through the given region can hit anything in the scene, which is the
+    // case if the cone around the rays through its corners misses the group.
+    // It errs on the side of false, for example if there is a ground, instances or a lattice.
+    fn misses_scene(o: &RenderOptions, scene: &Scene, view: &View, region: &ImageRegion) -> bool {
+        scene.ground.is_none() && scene.instances.is_empty() && scene.lattice.is_none() &&
+            Renderer::misses_group(o, view, region, &scene.group)
+    }
 
-                    }//for each ss y
-                }// for each ss x
+    // True if no ray through the given region can hit the bound of the group, or all of its
+    // children if it is too small to test its bound, which then may be anywhere
+    fn misses_group(o: &RenderOptions,
+                    view: &View,
+                    region: &ImageRegion,
+                    group: &SphericalGroup)
+                    -> bool {
+        match group.tested_bound() {
+            Some(bound) => Renderer::misses_sphere(o, view, region, bound),
+            None => {
+                group.children.iter().all(|child| match *child {
+                    Pair::Item(ref s) => Renderer::misses_sphere(o, view, region, s),
+                    Pair::Group(ref g) => Renderer::misses_group(o, view, region, g),
+                })
+            }
+        }
+    }
 
-                g.mulf(total_samples_per_pixel_recip);
-                alpha *= total_samples_per_pixel_recip;
+    // True if no ray through the given region can hit the sphere, see misses_scene()
+    fn misses_sphere(o: &RenderOptions, view: &View, region: &ImageRegion, bound: &Sphere) -> bool {
+        // Wider cones rarely miss anything, and the angles don't bound their rays beyond 90°
+        const MAX_CONE_ANGLE: RFloat = f32::consts::FRAC_PI_4;
+        // Keeps rounding from deciding whether grazing rays miss
+        const MARGIN: RFloat = 1e-3;
+        // Rays starting anywhere on a lens leave the cone around the ones through the eye
+        if view.camera.projection != Projection::Perspective || view.camera.aperture > 0.0 ||
+           region.is_empty() {
+            return false;
+        }
+        let dir = |x: u16, y: u16| Renderer::primary_ray(o, view, x as RFloat, y as RFloat).dir;
+        let center = Renderer::primary_ray(o,
+                                           view,
+                                           (region.l + region.r) as RFloat / 2.0,
+                                           (region.b + region.t) as RFloat / 2.0)
+            .dir;
+        let cone_angle = [dir(region.l, region.b),
+                          dir(region.r, region.b),
+                          dir(region.l, region.t),
+                          dir(region.r, region.t)]
+            .iter()
+            .map(|d| d.dot(&center).clamp(-1.0, 1.0).acos())
+            .fold(0.0, RFloat::max);
+        if cone_angle > MAX_CONE_ANGLE {
+            return false;
+        }
 
-                buf.set_pixel_from_vector(x, y, &g, alpha);
-            }// for each x
-        }// for each y
+        let to_bound = bound.center - view.camera.eye;
+        let distance = to_bound.len();
+        if distance <= bound.radius {
+            return false;
+        }
+        let angle = (to_bound.dot(&center) / distance).clamp(-1.0, 1.0).acos();
+        angle > cone_angle + (bound.radius / distance).asin() + MARGIN
     }
 
-    // Use runtime dispatching for the image writer to remain flexible
-    // (And to test this ;))
-    // sets up multi-threading accordingly
+    // The factor to multiply the color of the given pixel with, based on the angle between
+    // the ray through its center and the one through the image center. Rays of parallel
+    // projections all have the same direction, which leaves them as they are.
+    fn vignette(o: &RenderOptions, view: &View, x: u16, y: u16) -> RFloat {
+        let direction = |x: RFloat, y: RFloat| {
+            Renderer::camera_direction(o, &view.camera, x, y).normalized()
+        };
+        let center = direction(o.width as RFloat / 2.0, o.height as RFloat / 2.0);
+        let cos = direction(x as RFloat + 0.5, y as RFloat + 0.5).dot(&center).max(0.0);
+        let cos2 = cos * cos;
+        1.0 - o.vignette * (1.0 - cos2 * cos2)
+    }
+
+    // Calls f with each of the samples_per_axis² primary rays through the given pixel, in
+    // the order they are traced. Jittered samples draw their offsets from rng.
+    #[inline]
+    fn for_each_primary_ray<F>(o: &RenderOptions,
+                               view: &View,
+                               x: u16,
+                               y: u16,
+                               rng: &mut Rng,
+                               mut f: F)
+        where F: FnMut(&Ray)
+    {
+        let ssf = o.samples_per_axis as RFloat;
+        for ssx in 0..o.samples_per_axis {
+            for ssy in 0..o.samples_per_axis {
+                let mut offset = || if o.jitter { rng.next_float() } else { 0.0 };
+                let xres = x as RFloat + (ssx as RFloat + offset()) / ssf;
+                let yres = y as RFloat + (ssy as RFloat + offset()) / ssf;
+                let ray = Renderer::primary_ray(o, view, xres, yres);
+                if view.camera.aperture > 0.0 {
+                    f(&Renderer::through_lens(view, &ray, rng));
+                } else {
+                    f(&ray);
+                }
+            }
+        }
+    }
+
+    // The given pinhole ray, starting on a random point of the lens instead, and aimed at
+    // where the pinhole ray meets the focal plane. Rays which don't look forward can't
+    // reach it, and stay as they are.
+    fn through_lens(view: &View, ray: &Ray, rng: &mut Rng) -> Ray {
+        let cos = ray.dir.dot(&view.forward);
+        if cos <= 0.0 {
+            return *ray;
+        }
+        let camera = &view.camera;
+        let ahead = (ray.pos - camera.eye).dot(&view.forward);
+        let focus = ray.pos + ray.dir.mulfed((camera.focus_distance - ahead) / cos);
+        // Uniformly distributed over the disk of the lens
+        let r = camera.aperture * rng.next_float().sqrt();
+        let (sin, cos) = (rng.next_float() * 2.0 * f32::consts::PI).sin_cos();
+        let pos = ray.pos + view.right.mulfed(r * cos) + view.up.mulfed(r * sin);
+        Ray {
+            pos,
+            dir: (focus - pos).normalized(),
+            ..*ray
+        }
+    }
+
+    /// Returns the samples_per_axis² primary rays through the pixel at x, y, as seen by the
+    /// given camera, which the renderers trace to compute its color. It allows to shade
+    /// pixels in other ways.
+    /// Jittered rays are drawn from the same `pixel_rng()` as the ones of the renderers.
+    /// Fails if the camera is invalid, see `Camera::basis()`.
+    pub fn primary_rays_for_pixel(o: &RenderOptions,
+                                  camera: &Camera,
+                                  x: u16,
+                                  y: u16)
+                                  -> Result<Vec<Ray>, RenderError> {
+        let view = View::from_camera(o, *camera)?;
+        let mut rng = pixel_rng(o.seed, x, y);
+        let mut rays = Vec::with_capacity((o.samples_per_axis as usize).pow(2));
+        Renderer::for_each_primary_ray(o, &view, x, y, &mut rng, |ray| rays.push(*ray));
+        Ok(rays)
+    }
+
+    // The ray through the given (sub-)pixel position, with differentials pointing to the
+    // neighbouring samples
+    fn primary_ray(o: &RenderOptions, view: &View, x: RFloat, y: RFloat) -> Ray {
+        let step = (o.samples_per_axis as RFloat).recip();
+        let direction = |x: RFloat, y: RFloat| -> Vector {
+            let d = Renderer::camera_direction(o, &view.camera, x, y);
+            (view.right.mulfed(d.x) + view.up.mulfed(d.y) + view.forward.mulfed(d.z)).normalized()
+        };
+
+        let dir = direction(x, y);
+        let (ox, oy) = Renderer::camera_offset(o, &view.camera, x, y);
+        let origin = view.camera.eye + view.right.mulfed(ox) + view.up.mulfed(oy);
+        Ray {
+            differentials: Some(RayDifferentials {
+                dx: direction(x + step, y) - dir,
+                dy: direction(x, y + step) - dir,
+            }),
+            ..Ray::new_unnormalized(origin + dir.mulfed(o.near), dir)
+        }
+    }
+
+    // Where the ray through the given image position starts, relative to the eye along the
+    // right and up directions of the camera. Only parallel projections leave the eye.
+    fn camera_offset(o: &RenderOptions, camera: &Camera, x: RFloat, y: RFloat)
+                     -> (RFloat, RFloat) {
+        match camera.projection {
+            Projection::Perspective |
+            Projection::Equirectangular => (0.0, 0.0),
+            Projection::Orthographic { scale } => {
+                let width = o.width as RFloat;
+                let height = o.height as RFloat;
+                let unit = scale / width.min(height);
+                (unit * (x - width / 2.0), unit * ((height - y) - height / 2.0))
+            }
+        }
+    }
+
+    // The direction through the given image position relative to the camera, with x pointing
+    // right, y up and z forward. It's not normalized.
+    fn camera_direction(o: &RenderOptions, camera: &Camera, x: RFloat, y: RFloat) -> Vector {
+        let width = o.width as RFloat;
+        let height = o.height as RFloat;
+        match camera.projection {
+            Projection::Perspective => {
+                // Image y grows downwards, while the direction's y grows upwards
+                Vector {
+                    x: x - width / 2.0,
+                    y: (height - y) - height / 2.0,
+                    z: width.min(height) / (2.0 * (camera.fov / 2.0).tan()),
+                }
+            }
+            Projection::Equirectangular => {
+                let longitude = (x / width - 0.5) * 2.0 * f32::consts::PI;
+                let latitude = (0.5 - y / height) * f32::consts::PI;
+                let (sin_lon, cos_lon) = longitude.sin_cos();
+                let (sin_lat, cos_lat) = latitude.sin_cos();
+                Vector {
+                    x: cos_lat * sin_lon,
+                    y: sin_lat,
+                    z: cos_lat * cos_lon,
+                }
+            }
+            // The rays differ in their origin instead, see camera_offset()
+            Projection::Orthographic { .. } => Vector::new(0.0, 0.0, 1.0),
+        }
+    }
+
+    // The distance of the hit from the eye, clamped to the depth range of the options.
+    // Misses are infinitely far away, which would otherwise end up in the arithmetic.
+    fn depth(o: &RenderOptions, h: &Hit) -> RFloat {
+        if h.has_missed() {
+            o.max_depth
+        } else {
+            (h.distance + o.near).max(o.min_depth).min(o.max_depth)
+        }
+    }
+
+    /// Like render_region(), but renders a depth image where near is dark and far is bright,
+    /// see `RenderOptions::min_depth`.
+    /// There is only one sample per pixel, through its center.
+    /// Fails if the camera is invalid, see `Camera::basis()`.
+    pub fn render_depth_region(o: &RenderOptions,
+                               scene: &Scene,
+                               buf: &mut RGBABuffer)
+                               -> Result<(), RenderError> {
+        let region = *buf.region();
+        let view = View::new(o, scene)?;
+        let range = (o.max_depth - o.min_depth).max(RFloat::EPSILON);
+
+        for y in region.b..region.t {
+            for x in region.l..region.r {
+                let (cx, cy) = (x as RFloat + 0.5, y as RFloat + 0.5);
+                let ray = Renderer::primary_ray(o, &view, cx, cy);
+                let mut h = Hit::missed();
+                scene.intersect_with_ground(&mut h, &ray);
+                let d = (Renderer::depth(o, &h) - o.min_depth) / range;
+                buf.set_pixel_from_color(x, y, &Color::grey(d), 1.0, &Default::default());
+            }
+        }
+        Ok(())
+    }
+
+    /// Renders the pixels of the buffer's region, which is inherently single-threaded.
+    /// Fails if the options can't be rendered with, like `render_patch()`.
+    pub fn render_region(o: &RenderOptions,
+                         scene: &Scene,
+                         buf: &mut RGBABuffer)
+                         -> Result<(), RenderError> {
+        let view = Renderer::check_options(o, scene)?;
+        Renderer::render_view_region(o, scene, &view, buf);
+        Ok(())
+    }
+
+    // Like render_region(), but with the view of options which were checked already
+    fn render_view_region(o: &RenderOptions, scene: &Scene, view: &View, buf: &mut RGBABuffer) {
+        let empty = Renderer::misses_scene(o, scene, view, buf.region());
+        Renderer::render_samples(o, scene, view, buf, empty);
+    }
+
+    // Does the work of render_view_region(). If empty is true, all rays are known to miss, which
+    // is the same as not intersecting them at all.
+    fn render_samples(o: &RenderOptions,
+                      scene: &Scene,
+                      view: &View,
+                      buf: &mut RGBABuffer,
+                      empty: bool) {
+        let region = *buf.region();
+        let (pixels, self_intersections, bounces) =
+            Renderer::trace_pixels(o, scene, view, &region, empty);
+        buf.self_intersections += self_intersections;
+        buf.bounces.merge(&bounces);
+        for y in region.b..region.t {
+            for x in region.l..region.r {
+                let (g, alpha) = pixels[region.buffer_offset(x, y)];
+                Renderer::set_pixel(o, view, buf, x, y, g, alpha);
+            }
+        }
+    }
+
+    // The average color and coverage of all samples of each pixel in the region, in the
+    // order of its buffer, along with the amount of self-intersections of their shadow rays
+    // and how often they were reflected
+    fn trace_pixels(o: &RenderOptions,
+                    scene: &Scene,
+                    view: &View,
+                    region: &ImageRegion,
+                    empty: bool)
+                    -> (Vec<(Color, RFloat)>, usize, BounceStats) {
+        let ssf = o.samples_per_axis as RFloat;
+        let total_samples_per_pixel_recip = (ssf * ssf).recip();
+        let mut pixels = Vec::with_capacity(region.area());
+        let mut self_intersections = 0;
+        let mut bounces: BounceStats = Default::default();
+
+        for y in region.b..region.t {
+            for x in region.l..region.r {
+                let mut g: Color = Default::default();
+                let mut alpha: RFloat = 0.0;
+                // The background seen by missing samples, if it's kept apart from g
+                let mut misses: Color = Default::default();
+
+                let mut rng = pixel_rng(o.seed, x, y);
+                Renderer::for_each_primary_ray(o, view, x, y, &mut rng, |ray| {
+                    let shade = |c: &mut Color| if empty {
+                        Renderer::miss(scene, ray, c)
+                    } else {
+                        Renderer::raytrace(o, scene, ray, c, 0)
+                    };
+                    let trace = |c: &mut Color| if o.target == RenderTarget::Bounces {
+                        let sample = shade(&mut Default::default());
+                        *c = *c + Renderer::bounce_color(o, sample.bounces);
+                        sample
+                    } else {
+                        shade(c)
+                    };
+                    let sample = match o.edges {
+                        EdgeMode::Color(_) => {
+                            let mut c: Color = Default::default();
+                            let sample = trace(&mut c);
+                            if sample.coverage == 0.0 {
+                                misses = misses + c;
+                            } else {
+                                g = g + c;
+                            }
+                            sample
+                        }
+                        _ => trace(&mut g),
+                    };
+                    alpha += sample.coverage;
+                    self_intersections += sample.self_intersection as usize;
+                    bounces.add(sample.bounces);
+                });
+
+                g = g.mulfed(total_samples_per_pixel_recip);
+                alpha *= total_samples_per_pixel_recip;
+                match o.edges {
+                    EdgeMode::Opaque if alpha > 0.0 => alpha = 1.0,
+                    EdgeMode::Color(edge) if alpha > 0.0 => {
+                        g = g + Color::from_linear_rgb(edge).mulfed(1.0 - alpha);
+                    }
+                    EdgeMode::Color(_) => g = misses.mulfed(total_samples_per_pixel_recip),
+                    _ => {}
+                }
+                pixels.push((g, alpha));
+            }// for each x
+        }// for each y
+        (pixels, self_intersections, bounces)
+    }
+
+    // The color of a ray which was reflected the given number of times, see
+    // `RenderTarget::Bounces`
+    fn bounce_color(o: &RenderOptions, bounces: u16) -> Color {
+        let t = bounces as RFloat / cmp::max(o.max_reflection_depth, 1) as RFloat;
+        Color::from_linear_rgb(Vector {
+            x: (3.0 * t).clamp(0.0, 1.0),
+            y: (3.0 * t - 1.0).clamp(0.0, 1.0),
+            z: (3.0 * t - 2.0).clamp(0.0, 1.0),
+        })
+    }
+
+    // Writes the averaged color and coverage of a pixel as the render target demands
+    fn set_pixel(o: &RenderOptions,
+                 view: &View,
+                 buf: &mut RGBABuffer,
+                 x: u16,
+                 y: u16,
+                 g: Color,
+                 alpha: RFloat) {
+        match o.target {
+            RenderTarget::Coverage => {
+                buf.set_pixel_from_color(x, y, &Color::grey(alpha), 1.0, &o.color_opts());
+                return;
+            }
+            RenderTarget::Bounces => {
+                buf.set_pixel_from_color(x, y, &g, 1.0, &o.color_opts());
+                return;
+            }
+            RenderTarget::Beauty | RenderTarget::Unlit => {}
+        }
+        let g = if o.vignette > 0.0 {
+            g.mulfed(Renderer::vignette(o, view, x, y))
+        } else {
+            g
+        };
+        buf.set_pixel_from_color(x, y, &g, alpha, &o.color_opts());
+    }
+
+    /// Renders just the pixels of the given region of the image described by the options.
+    /// They match the ones of a full render, even if the options jitter the samples.
+    pub fn render_patch(o: &RenderOptions,
+                        scene: &Scene,
+                        region: &ImageRegion)
+                        -> Result<RGBABuffer, RenderError> {
+        let view = Renderer::check_options(o, scene)?;
+        let mut buf = RGBABuffer::try_new(region, o.memory_budget)?;
+        Renderer::render_view_region(o, scene, &view, &mut buf);
+        Ok(buf)
+    }
+
+    // Returns the regions of all tiles making up the image, in the order they should be rendered.
+    // Tiles along the right and top edges are smaller, unless the image size is a multiple of
+    // CHUNK_SIZE.
+    fn tiles(o: &RenderOptions) -> Vec<ImageRegion> {
+        let count = |size: u16| (size as usize).div_ceil(CHUNK_SIZE as usize);
+        let mut tiles = Vec::with_capacity(count(o.width) * count(o.height));
+        for row in 0..count(o.height) {
+            for column in 0..count(o.width) {
+                let (x, y) = (column as u16 * CHUNK_SIZE, row as u16 * CHUNK_SIZE);
+                tiles.push(ImageRegion {
+                    l: x,
+                    r: o.width.min(x.saturating_add(CHUNK_SIZE)),
+                    b: y,
+                    t: o.height.min(y.saturating_add(CHUNK_SIZE)),
+                });
+            }
+        }
+
+        match o.tile_order {
+            TileOrder::Raster => {}
+            TileOrder::Spiral => {
+                // Rings of tiles around the one containing the image center, each ordered by angle
+                let column = |t: &ImageRegion| (t.l / CHUNK_SIZE) as i32;
+                let row = |t: &ImageRegion| (t.b / CHUNK_SIZE) as i32;
+                let center_column = (o.width / 2 / CHUNK_SIZE) as i32;
+                let center_row = (o.height / 2 / CHUNK_SIZE) as i32;
+                let key = |t: &ImageRegion| {
+                    let (dx, dy) = (column(t) - center_column, row(t) - center_row);
+                    (cmp::max(dx.abs(), dy.abs()), (dy as RFloat).atan2(dx as RFloat))
+                };
+                tiles.sort_by(|a, b| key(a).partial_cmp(&key(b)).unwrap());
+            }
+            TileOrder::Shuffled(seed) => {
+                let mut rng = Rng::new(seed);
+                for i in (1..tiles.len()).rev() {
+                    let j = (rng.next_u64() % (i as u64 + 1)) as usize;
+                    tiles.swap(i, j);
+                }
+            }
+        }
+        tiles
+    }
+
+    // Checks the options can be rendered with, and that they have a valid camera for the scene,
+    // whose view is returned
+    fn check_options(o: &RenderOptions, scene: &Scene) -> Result<View, RenderError> {
+        if o.samples_per_axis == 0 || o.samples_per_axis > MAX_SAMPLES_PER_AXIS {
+            return Err(RenderError::InvalidSamplesPerAxis(o.samples_per_axis));
+        }
+        View::new(o, scene)
+    }
+
+    // Checks the scene is valid and the image fits into our memory budget, and prepares the
+    // writer for it
+    fn begin(o: &RenderOptions,
+             scene: &Scene,
+             writer: &mut dyn RGBABufferWriter)
+             -> Result<(), RenderError> {
+        scene.validate().map_err(RenderError::InvalidScene)?;
+        Renderer::check_options(o, scene)?;
+        RGBABuffer::checked_size(&ImageRegion {
+                                     l: 0,
+                                     r: o.width,
+                                     b: 0,
+                                     t: o.height,
+                                 },
+                                 o.memory_budget)?;
+        writer.begin(o.width, o.height)
+    }
+
+    // Pushes one task per tile, each of which sends its finished buffer through tx.
+    // Returns the amount of tasks pushed
+    #[cfg(feature = "threadpool")]
+    fn dispatch_tiles(o: &RenderOptions,
+                      scene: &Arc<Scene>,
+                      pool: &ThreadPool,
+                      tx: &SyncSender<RGBABuffer>,
+                      cancel: &Option<Arc<AtomicBool>>,
+                      buffers: &Arc<TilePool>)
+                      -> usize {
+        let tiles = Renderer::tiles(o);
+        let count = tiles.len();
+        for region in tiles {
+            let tx = tx.clone();
+            let opts = *o;
+            let tscene = scene.clone();
+            let cancel = cancel.clone();
+            let buffers = buffers.clone();
+
+            pool.execute(move || {
+                // Dropping tx without sending tells the receiver we are done
+                if Renderer::is_cancelled(&cancel) {
+                    return;
+                }
+                let b = Renderer::render_tile(&opts, tscene.deref(), &region, &buffers);
+
+                // The receiver may legitimately hang up early, e.g. if a consumer
+                // of scanlines() stops iterating
+                tx.send(b).ok();
+            });
+        }
+        count
+    }
+
+    // Use runtime dispatching for the image writer to remain flexible
+    // (And to test this ;))
+    // sets up multi-threading accordingly
+    #[cfg(feature = "threadpool")]
     pub fn render(o: &RenderOptions,
                   scene: Arc<Scene>,
                   writer: &mut RGBABufferWriter,
-                  pool: &ThreadPool) {
-        const CHUNK_SIZE: u16 = 64;
-        assert!(o.width % CHUNK_SIZE == 0, "TODO: handle chunk sizes");
-        assert!(o.height % CHUNK_SIZE == 0, "TODO: handle chunk sizes");
+                  pool: &ThreadPool)
+                  -> Result<RenderSummary, RenderError> {
+        Renderer::render_controlled(o, scene, writer, pool, &mut Default::default())
+    }
+
+    /// Like render(), but writes RGBA pixels row by row right into out, which must have
+    /// exactly the size of the image. This is useful to render into framebuffers of windows.
+    #[cfg(feature = "threadpool")]
+    pub fn render_into(o: &RenderOptions,
+                       scene: Arc<Scene>,
+                       pool: &ThreadPool,
+                       out: &mut [u8])
+                       -> Result<RenderSummary, RenderError> {
+        Renderer::render(o, scene, &mut SliceRGBABufferWriter { out, width: 0 }, pool)
+    }
+
+    /// Like render_into(), but allocates the buffer and returns it, which makes for the
+    /// simplest way to get at the pixels. It's subject to `RenderOptions::memory_budget`.
+    #[cfg(feature = "threadpool")]
+    pub fn render_to_buffer(o: &RenderOptions,
+                            scene: Arc<Scene>,
+                            pool: &ThreadPool)
+                            -> Result<Vec<u8>, RenderError> {
+        let region = ImageRegion::new(0, 0, o.width, o.height);
+        let mut image = RGBABuffer::try_new(&region, o.memory_budget)?;
+        Renderer::render_into(o, scene, pool, &mut image.buf)?;
+        Ok(image.buf)
+    }
+
+    /// Like render(), but calls tile_done with each finished tile instead of passing it to a
+    /// writer. It's called on the thread which called us, in the order the tiles finish.
+    #[cfg(feature = "threadpool")]
+    pub fn render_with<F>(o: &RenderOptions,
+                          scene: Arc<Scene>,
+                          pool: &ThreadPool,
+                          tile_done: F)
+                          -> Result<RenderSummary, RenderError>
+        where F: FnMut(&RGBABuffer)
+    {
+        Renderer::render(o, scene, &mut CallbackRGBABufferWriter(tile_done), pool)
+    }
+
+    /// Like render(), but allows to follow the progress and to cancel the render.
+    /// Cancelled renders still write all tiles in flight, and return Ok.
+    #[cfg(feature = "threadpool")]
+    pub fn render_controlled(o: &RenderOptions,
+                             scene: Arc<Scene>,
+                             writer: &mut dyn RGBABufferWriter,
+                             pool: &ThreadPool,
+                             control: &mut RenderControl)
+                             -> Result<RenderSummary, RenderError> {
+        if o.post_aa != PostAA::Off || o.denoise {
+            let mut post = PostProcessRGBABufferWriter::new(o, writer);
+            let o = RenderOptions {
+                post_aa: PostAA::Off,
+                denoise: false,
+                ..*o
+            };
+            let summary = Renderer::render_controlled(&o, scene, &mut post, pool, control)?;
+            post.finish();
+            return Ok(summary);
+        }
+        let start = Instant::now();
+        Renderer::begin(o, &scene, writer)?;
+
+        // Push all tasks
+        let (tx, rx) = sync_channel::<RGBABuffer>(4);
+        let buffers: Arc<TilePool> = Default::default();
+        let count = Renderer::dispatch_tiles(o, &scene, pool, &tx, &control.cancel, &buffers);
+        // Only workers may keep the channel open, or we couldn't tell if they died
+        drop(tx);
+
+        let stats = Renderer::write_tiles(&rx, count, writer, control, &buffers)?;
+        Ok(RenderSummary {
+            threads: pool.max_count(),
+            tiles: stats.written,
+            complete: stats.written == count && stats.skipped == 0,
+            elapsed: start.elapsed(),
+            self_intersections: stats.self_intersections,
+            skipped_tiles: stats.skipped,
+            allocated_buffers: buffers.allocated(),
+            bounces: stats.bounces,
+        })
+    }
+
+    fn is_cancelled(cancel: &Option<Arc<AtomicBool>>) -> bool {
+        match *cancel {
+            Some(ref c) => c.load(Ordering::Relaxed),
+            None => false,
+        }
+    }
+
+    // Renders the given tile, unless the deadline has passed, in which case it's filled with
+    // the background
+    fn render_tile(o: &RenderOptions,
+                   scene: &Scene,
+                   region: &ImageRegion,
+                   buffers: &TilePool)
+                   -> RGBABuffer {
+        let mut b = buffers.buffer(region);
+        b.skipped = o.deadline.is_some_and(|d| Instant::now() >= d);
+        let view = View::expect(o, scene);
+        if b.skipped {
+            Renderer::render_samples(o, scene, &view, &mut b, true);
+        } else {
+            Renderer::render_view_region(o, scene, &view, &mut b);
+        }
+        b
+    }
+
+    // Read count results and pass them to the writer, returning the amount of tiles written.
+    // Written buffers are returned to buffers.
+    // Fails if all workers hung up before delivering them, which happens if they panic,
+    // unless the render was cancelled.
+    fn write_tiles(rx: &Receiver<RGBABuffer>,
+                   count: usize,
+                   writer: &mut dyn RGBABufferWriter,
+                   control: &mut RenderControl,
+                   buffers: &TilePool)
+                   -> Result<TileStats, RenderError> {
+        let mut stats: TileStats = Default::default();
+        while stats.written < count {
+            match rx.recv() {
+                Ok(b) => {
+                    stats.self_intersections += b.self_intersections;
+                    stats.bounces.merge(&b.bounces);
+                    stats.skipped += b.skipped as usize;
+                    writer.write_rgba_buffer(&b);
+                    buffers.recycle(b);
+                }
+                Err(_) if control.is_cancelled() => return Ok(stats),
+                Err(_) => return Err(RenderError::WorkerPanicked),
+            }
+            stats.written += 1;
+            if let Some(ref mut progress) = control.progress {
+                progress(stats.written, count);
+            }
+        }
+        Ok(stats)
+    }
+
+    /// Like render(), but without a pool. Instead, num_threads scoped threads are spawned
+    /// which pull tiles until there are none left.
+    /// The output is identical to the one of render().
+    pub fn render_scoped(o: &RenderOptions,
+                         scene: &Scene,
+                         writer: &mut dyn RGBABufferWriter,
+                         num_threads: usize)
+                         -> Result<RenderSummary, RenderError> {
+        if o.post_aa != PostAA::Off || o.denoise {
+            let mut post = PostProcessRGBABufferWriter::new(o, writer);
+            let o = RenderOptions {
+                post_aa: PostAA::Off,
+                denoise: false,
+                ..*o
+            };
+            let summary = Renderer::render_scoped(&o, scene, &mut post, num_threads)?;
+            post.finish();
+            return Ok(summary);
+        }
+        let start = Instant::now();
+        Renderer::begin(o, scene, writer)?;
+
+        let tiles = Renderer::tiles(o);
+        let threads = cmp::max(num_threads, 1);
+        let buffers: TilePool = Default::default();
+        let stats = Renderer::render_tiles_scoped(o, scene, &tiles, writer, threads, &buffers)?;
+        Ok(RenderSummary {
+            threads,
+            tiles: tiles.len(),
+            complete: stats.skipped == 0,
+            elapsed: start.elapsed(),
+            self_intersections: stats.self_intersections,
+            skipped_tiles: stats.skipped,
+            allocated_buffers: buffers.allocated(),
+            bounces: stats.bounces,
+        })
+    }
+
+    /// Renders the default scene into a small image, and returns true if it is exactly the
+    /// expected one. It isn't if floating point math differs on this platform, or the crate
+    /// was built with different settings, like an optimization which reorders operations.
+    pub fn self_test() -> bool {
+        let options = RenderOptions {
+            width: SELF_TEST_SIZE,
+            height: SELF_TEST_SIZE,
+            ..Default::default()
+        };
+        let size = SELF_TEST_SIZE as usize;
+        let mut image = vec![0u8; size * size * RGBABuffer::components()];
+        let mut writer = SliceRGBABufferWriter {
+            out: &mut image,
+            width: 0,
+        };
+        Renderer::render_scoped(&options, &Scene::default(), &mut writer, 1).is_ok() &&
+        image_hash(&image) == SELF_TEST_HASH
+    }
+
+    /// Like render_scoped(), but only renders the tiles which may look different in the new
+    /// scene than in the old one, whose image is previous. All other tiles are copied from it.
+    /// Only spheres of the group may change for this to work, which are compared by position.
+    /// If anything else changed, like the eye or the light, all tiles are rendered. The
+    /// background can't be compared, and is assumed to be the same.
+    /// Shadows changed spheres cast on others are not updated, use `RenderOptions::shadows`
+    /// to turn them off while editing. Neither are their reflections in other spheres, which
+    /// a `RenderOptions::max_reflection_depth` of 0 turns off.
+    /// The summary counts the rendered tiles, but not the copied ones.
+    pub fn render_changed(o: &RenderOptions,
+                          old: &Scene,
+                          new: &Scene,
+                          previous: &RGBABuffer,
+                          writer: &mut dyn RGBABufferWriter,
+                          num_threads: usize)
+                          -> Result<RenderSummary, RenderError> {
+        let start = Instant::now();
+        let image = ImageRegion::new(0, 0, o.width, o.height);
+        if *previous.region() != image {
+            return Err(RenderError::BufferSizeMismatch {
+                expected: image.area() * RGBABuffer::components(),
+                actual: previous.buffer().len(),
+            });
+        }
+        Renderer::begin(o, new, writer)?;
+
+        let view = View::expect(o, new);
+        let changed = Renderer::changed_spheres(o, old, new);
+        let (tiles, unchanged): (Vec<_>, Vec<_>) = Renderer::tiles(o).into_iter().partition(|t| {
+            match changed {
+                Some(ref spheres) => {
+                    !spheres.iter().all(|s| Renderer::misses_sphere(o, &view, t, s))
+                }
+                None => true,
+            }
+        });
+        for tile in unchanged.iter() {
+            writer.write_rgba_buffer(&previous.crop(tile));
+        }
+
+        let threads = cmp::max(num_threads, 1);
+        let buffers: TilePool = Default::default();
+        let stats = Renderer::render_tiles_scoped(o, new, &tiles, writer, threads, &buffers)?;
+        Ok(RenderSummary {
+            threads,
+            tiles: tiles.len(),
+            complete: stats.skipped == 0,
+            elapsed: start.elapsed(),
+            self_intersections: stats.self_intersections,
+            skipped_tiles: stats.skipped,
+            allocated_buffers: buffers.allocated(),
+            bounces: stats.bounces,
+        })
+    }
+
+    // The spheres which differ between the scenes, in both their old and new versions.
+    // Returns None if anything else differs that could change any pixel.
+    fn changed_spheres(o: &RenderOptions, old: &Scene, new: &Scene) -> Option<Vec<Sphere>> {
+        let camera = |s: &Scene| View::new(o, s).ok().map(|v| v.camera);
+        if camera(old) != camera(new) || old.directional_light != new.directional_light ||
+           old.light_size != new.light_size || old.palette != new.palette ||
+           old.ground != new.ground || old.lattice != new.lattice ||
+           !old.instances.is_empty() || !new.instances.is_empty() {
+            return None;
+        }
+        let (old_spheres, new_spheres) = (old.group.items(), new.group.items());
+        if old_spheres.len() != new_spheres.len() {
+            return None;
+        }
+        Some(old_spheres.iter()
+            .zip(new_spheres.iter())
+            .filter(|&(o, n)| o != n)
+            .flat_map(|(&&o, &&n)| vec![o, n])
+            .collect())
+    }
+
+    // Renders the given tiles on num_threads scoped threads, which pull tiles until there are
+    // none left, and passes them to the writer
+    fn render_tiles_scoped(o: &RenderOptions,
+                           scene: &Scene,
+                           tiles: &[ImageRegion],
+                           writer: &mut dyn RGBABufferWriter,
+                           threads: usize,
+                           buffers: &TilePool)
+                           -> Result<TileStats, RenderError> {
+        let next_tile = AtomicUsize::new(0);
+        let (tx, rx) = sync_channel::<RGBABuffer>(4);
+        thread::scope(|scope| {
+            let workers: Vec<_> = (0..threads)
+                .map(|_| {
+                    let tx = tx.clone();
+                    let next_tile = &next_tile;
+                    scope.spawn(move || {
+                        loop {
+                            let i = next_tile.fetch_add(1, Ordering::Relaxed);
+                            if i >= tiles.len() {
+                                break;
+                            }
+                            tx.send(Renderer::render_tile(o, scene, &tiles[i], buffers)).ok();
+                        }
+                    })
+                })
+                .collect();
+            drop(tx);
+
+            let res =
+                Renderer::write_tiles(&rx, tiles.len(), writer, &mut Default::default(), buffers);
+            // Unblock workers still sending, and join them explicitly - otherwise their
+            // panics would be propagated to us
+            drop(rx);
+            for w in workers {
+                w.join().ok();
+            }
+            res
+        })
+    }
+
+    /// Renders the image in passes of 1, 4, 9, ... samples per pixel, each with a different
+    /// seed, and averages all of their samples. After each pass, all tiles of the average
+    /// so far are written, which makes for a preview that keeps improving.
+    /// It stops as configured by refinement, and ignores `RenderOptions::samples_per_axis`.
+    /// Unless the options jitter the samples, passes share some of their sample positions.
+    /// The summary counts the tiles written in all passes.
+    pub fn render_progressive(o: &RenderOptions,
+                              scene: &Scene,
+                              writer: &mut dyn RGBABufferWriter,
+                              num_threads: usize,
+                              refinement: &Refinement)
+                              -> Result<RenderSummary, RenderError> {
+        let start = Instant::now();
+        if refinement.max_passes == 0 {
+            return Err(RenderError::InvalidMaxPasses);
+        }
+        let max_passes = cmp::min(refinement.max_passes, MAX_SAMPLES_PER_AXIS);
+        let first = Renderer::pass_options(o, 1);
+        Renderer::begin(&first, scene, writer)?;
+
+        let view = View::expect(o, scene);
+        let image = ImageRegion::new(0, 0, o.width, o.height);
+        let tiles = Renderer::tiles(o);
+        let threads = cmp::max(num_threads, 1);
+        // The sums of the colors and coverages of all samples so far
+        let mut sums = vec![(Color::default(), 0.0); image.area()];
+        let mut samples = 0;
+        let mut summary = RenderSummary {
+            threads,
+            tiles: 0,
+            complete: true,
+            elapsed: Default::default(),
+            self_intersections: 0,
+            skipped_tiles: 0,
+            allocated_buffers: 0,
+            bounces: Default::default(),
+        };
+        let buffers: TilePool = Default::default();
+        for pass in 1..max_passes + 1 {
+            let po = Renderer::pass_options(o, pass);
+            let pass_samples = pass as RFloat * pass as RFloat;
+            let (sums_before, samples_before) = (sums.clone(), samples as RFloat);
+            samples += pass as usize * pass as usize;
+            let recip = (samples as RFloat).recip();
+
+            let mut change: RFloat = 0.0;
+            for (tile, pixels, self_intersections, bounces) in
+                Renderer::trace_tiles(&po, scene, &view, &tiles, threads)? {
+                let mut buf = buffers.buffer(&tile);
+                for y in tile.b..tile.t {
+                    for x in tile.l..tile.r {
+                        let (g, alpha) = pixels[tile.buffer_offset(x, y)];
+                        let i = image.buffer_offset(x, y);
+                        let (sum, coverage) = sums[i];
+                        sums[i] = (sum + g.mulfed(pass_samples), coverage + alpha * pass_samples);
+                        let average = sums[i].0.mulfed(recip);
+                        if pass > 1 {
+                            let before = sums_before[i].0.mulfed(samples_before.recip());
+                            let d = average.to_linear_rgb() - before.to_linear_rgb();
+                            change = change.max(d.x.abs()).max(d.y.abs()).max(d.z.abs());
+                        }
+                        Renderer::set_pixel(o,
+                                            &view,
+                                            &mut buf,
+                                            x,
+                                            y,
+                                            average,
+                                            sums[i].1 * recip);
+                    }
+                }
+                writer.write_rgba_buffer(&buf);
+                buffers.recycle(buf);
+                summary.tiles += 1;
+                summary.self_intersections += self_intersections;
+                summary.bounces.merge(&bounces);
+            }
+            if (pass > 1 && change <= refinement.threshold) ||
+               start.elapsed() >= refinement.budget {
+                break;
+            }
+        }
+        summary.elapsed = start.elapsed();
+        summary.allocated_buffers = buffers.allocated();
+        Ok(summary)
+    }
+
+    // The options of the given pass of a progressive render, which takes pass^2 samples
+    fn pass_options(o: &RenderOptions, pass: u16) -> RenderOptions {
+        RenderOptions {
+            samples_per_axis: pass,
+            seed: o.seed.wrapping_add(pass as u64),
+            ..*o
+        }
+    }
+
+    // Like trace_pixels() for each of the tiles, which are traced on threads scoped threads.
+    // Returns the tiles grouped by the thread which traced them.
+    fn trace_tiles(o: &RenderOptions,
+                   scene: &Scene,
+                   view: &View,
+                   tiles: &[ImageRegion],
+                   threads: usize)
+                   -> Result<Vec<TracedTile>, RenderError> {
+        let next_tile = AtomicUsize::new(0);
+        thread::scope(|scope| {
+            let workers: Vec<_> = (0..threads)
+                .map(|_| {
+                    let next_tile = &next_tile;
+                    scope.spawn(move || {
+                        let mut traced = Vec::new();
+                        loop {
+                            let i = next_tile.fetch_add(1, Ordering::Relaxed);
+                            if i >= tiles.len() {
+                                break;
+                            }
+                            let empty = Renderer::misses_scene(o, scene, view, &tiles[i]);
+                            let (pixels, self_intersections, bounces) =
+                                Renderer::trace_pixels(o, scene, view, &tiles[i], empty);
+                            traced.push((tiles[i], pixels, self_intersections, bounces));
+                        }
+                        traced
+                    })
+                })
+                .collect();
+            let mut traced = Vec::with_capacity(tiles.len());
+            for w in workers {
+                traced.extend(w.join().map_err(|_| RenderError::WorkerPanicked)?);
+            }
+            Ok(traced)
+        })
+    }
+
+    /// Pull-based alternative to render(), yielding the RGBA pixels of one image row at a
+    /// time, top to bottom. Tiles are rendered on an internal pool using all available cores,
+    /// which is throttled by the speed at which rows are consumed.
+    /// The scene and options are checked before rendering. If a rendering thread panics,
+    /// the iteration ends with `RenderError::WorkerPanicked`.
+    #[cfg(feature = "threadpool")]
+    pub fn scanlines(o: &RenderOptions,
+                     scene: Arc<Scene>)
+                     -> Result<impl Iterator<Item = Result<Vec<u8>, RenderError>>, RenderError> {
+        scene.validate().map_err(RenderError::InvalidScene)?;
+        Renderer::check_options(o, &scene)?;
+        let pool = ThreadPool::new(thread::available_parallelism().map(|n| n.get()).unwrap_or(1));
+        let (tx, rx) = sync_channel::<RGBABuffer>(4);
+        // Rows are assembled top to bottom, which is the order tiles should arrive in
+        let o = RenderOptions {
+            tile_order: TileOrder::Raster,
+            ..*o
+        };
+        let buffers: Arc<TilePool> = Default::default();
+        Renderer::dispatch_tiles(&o, &scene, &pool, &tx, &None, &buffers);
+
+        Ok(Scanlines {
+            width: o.width,
+            height: o.height,
+            y: 0,
+            band: None,
+            stash: Vec::new(),
+            rx,
+            buffers,
+            _pool: pool,
+        })
+    }
+}
+
+/// Reorders tiles, which arrive in any order, into rows
+#[cfg(feature = "threadpool")]
+struct Scanlines {
+    width: u16,
+    height: u16,
+    /// The next row to yield
+    y: u16,
+    /// A full-width row of tiles containing y
+    band: Option<RGBABuffer>,
+    /// Tiles received ahead of their band
+    stash: Vec<RGBABuffer>,
+    rx: Receiver<RGBABuffer>,
+    /// Takes the tiles once they are part of their band
+    buffers: Arc<TilePool>,
+    _pool: ThreadPool,
+}
+
+#[cfg(feature = "threadpool")]
+impl Scanlines {
+    // Fails if all workers hung up before delivering the tiles of the band, which happens if
+    // they panic
+    fn assemble_band(&mut self, top: u16) -> Result<RGBABuffer, RenderError> {
+        let mut band = RGBABuffer::new(&ImageRegion {
+            l: 0,
+            r: self.width,
+            b: top,
+            t: self.height.min(top.saturating_add(CHUNK_SIZE)),
+        });
+        let (mine, others): (Vec<_>, Vec<_>) =
+            self.stash.drain(..).partition(|b| b.region().b == top);
+        self.stash = others;
+
+        let mut missing = (self.width as usize).div_ceil(CHUNK_SIZE as usize) - mine.len();
+        for b in mine {
+            band.set_pixels_from_buffer(&b);
+            self.buffers.recycle(b);
+        }
+        while missing > 0 {
+            let b = self.rx.recv().map_err(|_| RenderError::WorkerPanicked)?;
+            if b.region().b == top {
+                band.set_pixels_from_buffer(&b);
+                self.buffers.recycle(b);
+                missing -= 1;
+            } else {
+                self.stash.push(b);
+            }
+        }
+        Ok(band)
+    }
+}
+
+#[cfg(feature = "threadpool")]
+impl Iterator for Scanlines {
+    type Item = Result<Vec<u8>, RenderError>;
+
+    fn next(&mut self) -> Option<Result<Vec<u8>, RenderError>> {
+        if self.y >= self.height {
+            return None;
+        }
+        let band_done = match self.band {
+            Some(ref b) => self.y >= b.region().t,
+            None => true,
+        };
+        if band_done {
+            let top = self.y;
+            match self.assemble_band(top) {
+                Ok(band) => self.band = Some(band),
+                Err(err) => {
+                    // There are no more rows to come
+                    self.y = self.height;
+                    return Some(Err(err));
+                }
+            }
+        }
+
+        let band = self.band.as_ref().unwrap();
+        let ofs = band.region().buffer_offset(0, self.y) * RGBABuffer::components();
+        let len = self.width as usize * RGBABuffer::components();
+        self.y += 1;
+        Some(Ok(band.buffer()[ofs..ofs + len].to_vec()))
+    }
+}
+
+/// Where images are written to. Outputs which can seek are rewritten with the image
+/// rendered so far while rendering, all others only get the final image appended.
+pub trait ImageOutput: io::Write {
+    /// True if rewind() is supported
+    fn can_seek(&self) -> bool {
+        false
+    }
+
+    /// Discards everything written so far, so the next write starts from the beginning
+    fn rewind(&mut self) -> io::Result<()> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "the output can't seek"))
+    }
+}
+
+impl ImageOutput for io::Stdout {}
+
+impl ImageOutput for Vec<u8> {}
+
+impl ImageOutput for io::BufWriter<fs::File> {
+    fn can_seek(&self) -> bool {
+        true
+    }
+
+    fn rewind(&mut self) -> io::Result<()> {
+        // Seeking flushes the buffer before the file is truncated
+        self.seek(io::SeekFrom::Start(0))?;
+        self.get_mut().set_len(0)
+    }
+}
+
+impl ImageOutput for io::Cursor<Vec<u8>> {
+    fn can_seek(&self) -> bool {
+        true
+    }
+
+    fn rewind(&mut self) -> io::Result<()> {
+        self.get_mut().clear();
+        self.set_position(0);
+        Ok(())
+    }
+}
+
+/// The file formats `ImageRGBABufferWriter` can write
+enum ImageFormat {
+    /// See `write_ppm()`
+    Ppm { rgb: bool },
+    /// See `write_tga()`
+    Tga,
+}
+
+// Assembles the full image, and writes it in the given format whenever the output
+// can be rewritten, and when finished or dropped
+struct ImageRGBABufferWriter<'a> {
+    out: &'a mut dyn ImageOutput,
+    image: Option<RGBABuffer>,
+    format: ImageFormat,
+    last_written_at: Option<Instant>,
+    buffer_dirty: bool,
+    // The first failed write, after which nothing is written anymore
+    error: Option<io::Error>,
+}
+
+// It's required to mark it unsafe, as the compiler apparently can't verify
+// that our `out` reference is still valid (even though the bounds say just that).
+// Errors are lost here, which is why finish() should be called instead.
+impl<'a> Drop for ImageRGBABufferWriter<'a> {
+    fn drop(&mut self) {
+        self.write_buffer_with_header();
+    }
+}
+
+impl<'a> ImageRGBABufferWriter<'a> {
+    fn new(format: ImageFormat, writer: &'a mut dyn ImageOutput) -> ImageRGBABufferWriter<'a> {
+        ImageRGBABufferWriter {
+            out: writer,
+            image: None,
+            format,
+            last_written_at: None,
+            buffer_dirty: false,
+            error: None,
+        }
+    }
+
+    // Writes the image unless it was written already, or writing failed before
+    fn write_buffer_with_header(&mut self) {
+        if !self.buffer_dirty || self.error.is_some() {
+            return;
+        }
+        let image = match self.image {
+            Some(ref image) => image,
+            None => return,
+        };
+
+        self.error = ImageRGBABufferWriter::write_image(self.out, image, &self.format).err();
+        self.buffer_dirty = false;
+    }
+
+    fn write_image(out: &mut dyn ImageOutput,
+                   image: &RGBABuffer,
+                   format: &ImageFormat)
+                   -> io::Result<()> {
+        if out.can_seek() {
+            out.rewind()?;
+        }
+        match *format {
+            ImageFormat::Ppm { rgb } => write_ppm(out, image, rgb)?,
+            ImageFormat::Tga => write_tga(out, image)?,
+        }
+        out.flush()
+    }
+
+    fn finish(mut self) -> io::Result<()> {
+        self.write_buffer_with_header();
+        // Keep drop() from trying again once the error is taken
+        self.buffer_dirty = false;
+        match self.error.take() {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+
+    fn write_thumbnail(&self, longest_edge: u16, out: &mut dyn io::Write) -> io::Result<()> {
+        let rgb = match self.format {
+            ImageFormat::Ppm { rgb } => rgb,
+            ImageFormat::Tga => true,
+        };
+        match self.image {
+            Some(ref image) => write_ppm(out, &image.thumbnail(longest_edge), rgb),
+            None => Ok(()),
+        }
+    }
+}
+
+impl<'a> RGBABufferWriter for ImageRGBABufferWriter<'a> {
+    fn begin(&mut self, x: u16, y: u16) -> Result<(), RenderError> {
+        // The budget was checked by the renderer already
+        self.image = Some(RGBABuffer::try_new(&ImageRegion {
+                                                  l: 0,
+                                                  r: x,
+                                                  b: 0,
+                                                  t: y,
+                                              },
+                                              usize::MAX)?);
+        Ok(())
+    }
+
+    fn write_rgba_buffer(&mut self, buffer: &RGBABuffer) {
+        self.image.as_mut().unwrap().set_pixels_from_buffer(buffer);
+        self.buffer_dirty = true;
+
+        // Flush full image right away
+        if self.out.can_seek() &&
+           (self.last_written_at.is_none() ||
+            self.last_written_at.unwrap() + Duration::from_secs(1) <= Instant::now()) {
+            self.last_written_at = Some(Instant::now());
+            self.write_buffer_with_header();
+        }
+    }
+}
+
+/// Writes the full image as binary PPM, see `write_ppm()`
+pub struct PPMStdoutRGBABufferWriter<'a>(ImageRGBABufferWriter<'a>);
+
+impl<'a> PPMStdoutRGBABufferWriter<'a> {
+    pub fn new(write_rgb: bool, writer: &'a mut dyn ImageOutput) -> PPMStdoutRGBABufferWriter<'a> {
+        PPMStdoutRGBABufferWriter(ImageRGBABufferWriter::new(ImageFormat::Ppm { rgb: write_rgb },
+                                                             writer))
+    }
+
+    /// Writes the image rendered so far as PPM, scaled down to at most longest_edge pixels
+    /// along its longer side. Nothing is written before begin() was called.
+    pub fn write_thumbnail(&self, longest_edge: u16, out: &mut dyn io::Write) -> io::Result<()> {
+        self.0.write_thumbnail(longest_edge, out)
+    }
+
+    /// Writes the image if it changed since it was last written, and returns the first
+    /// error writing it ever ran into. Once writing failed, nothing is written anymore.
+    /// Dropping the writer also writes the image, but loses the error.
+    pub fn finish(self) -> io::Result<()> {
+        self.0.finish()
+    }
+}
+
+impl<'a> RGBABufferWriter for PPMStdoutRGBABufferWriter<'a> {
+    fn begin(&mut self, x: u16, y: u16) -> Result<(), RenderError> {
+        self.0.begin(x, y)
+    }
+
+    fn write_rgba_buffer(&mut self, buffer: &RGBABuffer) {
+        self.0.write_rgba_buffer(buffer)
+    }
+}
+
+/// Writes the full image as uncompressed 24 bit TGA, see `write_tga()`.
+/// Thumbnails are still PPM.
+pub struct TgaRGBABufferWriter<'a>(ImageRGBABufferWriter<'a>);
+
+impl<'a> TgaRGBABufferWriter<'a> {
+    pub fn new(writer: &'a mut dyn ImageOutput) -> TgaRGBABufferWriter<'a> {
+        TgaRGBABufferWriter(ImageRGBABufferWriter::new(ImageFormat::Tga, writer))
+    }
+
+    /// See `PPMStdoutRGBABufferWriter::write_thumbnail()`
+    pub fn write_thumbnail(&self, longest_edge: u16, out: &mut dyn io::Write) -> io::Result<()> {
+        self.0.write_thumbnail(longest_edge, out)
+    }
+
+    /// See `PPMStdoutRGBABufferWriter::finish()`
+    pub fn finish(self) -> io::Result<()> {
+        self.0.finish()
+    }
+}
+
+impl<'a> RGBABufferWriter for TgaRGBABufferWriter<'a> {
+    fn begin(&mut self, x: u16, y: u16) -> Result<(), RenderError> {
+        self.0.begin(x, y)
+    }
+
+    fn write_rgba_buffer(&mut self, buffer: &RGBABuffer) {
+        self.0.write_rgba_buffer(buffer)
+    }
+}
+
+/// Images which can be written, no matter how they store their pixels
+pub trait PixelSource {
+    /// Returns (width, height)
+    fn dimensions(&self) -> (u16, u16);
+    /// The color of the given pixel, with components from 0 to 1.
+    /// x and y are relative to the top-left pixel.
+    fn rgb_at(&self, x: u16, y: u16) -> [f32; 3];
+}
+
+impl PixelSource for RGBABuffer {
+    fn dimensions(&self) -> (u16, u16) {
+        (self.reg.width(), self.reg.height())
+    }
+
+    fn rgb_at(&self, x: u16, y: u16) -> [f32; 3] {
+        let ofs = self.reg.buffer_offset(self.reg.l + x, self.reg.b + y);
+        let b = &self.buf[ofs * RGBABuffer::components()..];
+        [b[0] as f32 / 255.0, b[1] as f32 / 255.0, b[2] as f32 / 255.0]
+    }
+}
+
+/// Writes the image as binary PPM, in color if rgb is true, or in greyscale otherwise
+pub fn write_ppm(out: &mut dyn io::Write, image: &dyn PixelSource, rgb: bool) -> io::Result<()> {
+    let mut ptype: &str = "P5";
+    if rgb {
+        ptype = "P6"
+    }
+    let (width, height) = image.dimensions();
+    writeln!(out, "{}", ptype)?;
+    writeln!(out, "{} {}", width, height)?;
+    writeln!(out, "255")?;
+
+    let mut row = Vec::with_capacity(width as usize * 3);
+    for y in 0..height {
+        row.clear();
+        for x in 0..width {
+            let c = image.rgb_at(x, y);
+            let b = c.map(|v| (v * 255.0).round().clamp(0.0, 255.0) as u8);
+            if rgb {
+                row.extend_from_slice(&b);
+            } else {
+                let avg = ((b[0] as f32 + b[1] as f32 + b[2] as f32) / 3.0f32) as u8;
+                row.push(avg);
+            }
+        }
+        out.write_all(&row)?;
+    }
+    Ok(())
+}
+
+/// Writes the image as uncompressed 24 bit TGA, whose rows go from the bottom to the top,
+/// with each pixel stored as blue, green and red
+pub fn write_tga(out: &mut dyn io::Write, image: &dyn PixelSource) -> io::Result<()> {
+    const UNCOMPRESSED_TRUE_COLOR: u8 = 2;
+    let (width, height) = image.dimensions();
+    let mut header = [0u8; 18];
+    header[2] = UNCOMPRESSED_TRUE_COLOR;
+    header[12..14].copy_from_slice(&width.to_le_bytes());
+    header[14..16].copy_from_slice(&height.to_le_bytes());
+    header[16] = 24;
+    out.write_all(&header)?;
+
+    let mut row = Vec::with_capacity(width as usize * 3);
+    for y in (0..height).rev() {
+        row.clear();
+        for x in 0..width {
+            let [r, g, b] = image.rgb_at(x, y).map(|v| (v * 255.0).round().clamp(0.0, 255.0) as u8);
+            row.extend_from_slice(&[b, g, r]);
+        }
+        out.write_all(&row)?;
+    }
+    Ok(())
+}
+
+// Passes every buffer to a closure
+#[cfg(feature = "threadpool")]
+struct CallbackRGBABufferWriter<F>(F);
+
+#[cfg(feature = "threadpool")]
+impl<F> RGBABufferWriter for CallbackRGBABufferWriter<F>
+    where F: FnMut(&RGBABuffer)
+{
+    fn begin(&mut self, _: u16, _: u16) -> Result<(), RenderError> {
+        Ok(())
+    }
+
+    fn write_rgba_buffer(&mut self, buffer: &RGBABuffer) {
+        (self.0)(buffer)
+    }
+}
+
+// Copies every buffer into a slice of the full image, without allocating one
+struct SliceRGBABufferWriter<'a> {
+    out: &'a mut [u8],
+    width: u16,
+}
+
+impl<'a> RGBABufferWriter for SliceRGBABufferWriter<'a> {
+    fn begin(&mut self, x: u16, y: u16) -> Result<(), RenderError> {
+        let expected = x as usize * y as usize * RGBABuffer::components();
+        if self.out.len() != expected {
+            return Err(RenderError::BufferSizeMismatch {
+                expected,
+                actual: self.out.len(),
+            });
+        }
+        self.width = x;
+        Ok(())
+    }
+
+    fn write_rgba_buffer(&mut self, buffer: &RGBABuffer) {
+        let reg = *buffer.region();
+        let full = ImageRegion::new(0, 0, self.width, reg.t);
+        let w = reg.width() as usize * RGBABuffer::components();
+        for y in reg.b..reg.t {
+            let ofs = full.buffer_offset(reg.l, y) * RGBABuffer::components();
+            let their_ofs = reg.buffer_offset(reg.l, y) * RGBABuffer::components();
+            self.out[ofs..ofs + w].copy_from_slice(&buffer.buffer()[their_ofs..their_ofs + w]);
+        }
+    }
+}
+
+// Assembles the whole image, to pass it on to another writer with `RenderOptions::denoise`
+// and `PostAA` applied once finished
+struct PostProcessRGBABufferWriter<'a> {
+    out: &'a mut dyn RGBABufferWriter,
+    image: Option<RGBABuffer>,
+    options: RenderOptions,
+}
+
+impl<'a> PostProcessRGBABufferWriter<'a> {
+    fn new(o: &RenderOptions,
+           out: &'a mut dyn RGBABufferWriter)
+           -> PostProcessRGBABufferWriter<'a> {
+        PostProcessRGBABufferWriter {
+            out,
+            image: None,
+            options: *o,
+        }
+    }
+
+    // Writes everything written so far, denoised and blended across edges as configured
+    fn finish(self) {
+        if let Some(mut image) = self.image {
+            if self.options.denoise {
+                image = image.denoised(&self.options.color_opts());
+            }
+            if self.options.post_aa != PostAA::Off {
+                image = image.smooth_edges();
+            }
+            self.out.write_rgba_buffer(&image);
+        }
+    }
+}
+
+impl<'a> RGBABufferWriter for PostProcessRGBABufferWriter<'a> {
+    fn begin(&mut self, x: u16, y: u16) -> Result<(), RenderError> {
+        self.out.begin(x, y)?;
+        self.image = Some(RGBABuffer::try_new(&ImageRegion::new(0, 0, x, y), usize::MAX)?);
+        Ok(())
+    }
+
+    fn write_rgba_buffer(&mut self, buffer: &RGBABuffer) {
+        self.image.as_mut().unwrap().set_pixels_from_buffer(buffer);
+    }
+}
+
+/// Composites every buffer over a background image using the rendered alpha as coverage,
+/// before passing it on to another writer.
+/// Edges must not contain the background of the scene, which is what rendering with
+/// `EdgeMode::Color(Vector::ZERO)` does. Their colors then are premultiplied by their
+/// coverage, and composited as `fg + bg * (1 - alpha)` in linear space. Pixels missing all
+/// geometry show the background image only.
+pub struct AlphaOverRGBABufferWriter<'a> {
+    /// RGBA pixels of the full image, row by row, encoded like the rendered ones
+    background: &'a [u8],
+    out: &'a mut dyn RGBABufferWriter,
+    /// How the rendered pixels and the background are encoded, see
+    /// `RenderOptions::color_opts()`
+    color: ColorOpts,
+    width: u16,
+}
+
+impl<'a> AlphaOverRGBABufferWriter<'a> {
+    pub fn new(background: &'a [u8],
+               color: &ColorOpts,
+               out: &'a mut dyn RGBABufferWriter)
+               -> AlphaOverRGBABufferWriter<'a> {
+        AlphaOverRGBABufferWriter {
+            background,
+            out,
+            // Exposure was applied to the rendered colors already
+            color: ColorOpts {
+                exposure: 0.0,
+                ..*color
+            },
+            width: 0,
+        }
+    }
+}
+
+impl<'a> RGBABufferWriter for AlphaOverRGBABufferWriter<'a> {
+    fn begin(&mut self, x: u16, y: u16) -> Result<(), RenderError> {
+        let expected = x as usize * y as usize * RGBABuffer::components();
+        if self.background.len() != expected {
+            return Err(RenderError::BufferSizeMismatch {
+                expected,
+                actual: self.background.len(),
+            });
+        }
+        self.width = x;
+        self.out.begin(x, y)
+    }
+
+    fn write_rgba_buffer(&mut self, buffer: &RGBABuffer) {
+        let reg = *buffer.region();
+        let full = ImageRegion {
+            l: 0,
+            r: self.width,
+            b: reg.b,
+            t: reg.t,
+        };
+        let mut composite = RGBABuffer {
+            buf: buffer.buffer().clone(),
+            reg,
+            self_intersections: buffer.self_intersections,
+            bounces: buffer.bounces,
+            skipped: buffer.skipped,
+        };
+
+        for y in reg.b..reg.t {
+            for x in reg.l..reg.r {
+                let ofs = reg.buffer_offset(x, y) * RGBABuffer::components();
+                let bg_ofs = full.buffer_offset(x, y) * RGBABuffer::components();
+                let fg = &mut composite.buf[ofs..ofs + RGBABuffer::components()];
+                let (bg, bg_alpha) =
+                    rgba_to_color(&self.background[bg_ofs..bg_ofs + RGBABuffer::components()],
+                                  &self.color);
+                let (c, alpha) = rgba_to_color(fg, &self.color);
+                let (color, alpha) = if alpha == 0.0 {
+                    (bg, bg_alpha)
+                } else {
+                    (c + bg.mulfed(1.0 - alpha), alpha + bg_alpha * (1.0 - alpha))
+                };
+                fg.copy_from_slice(&color_to_rgba(&color, alpha, &self.color));
+            }
+        }
+        self.out.write_rgba_buffer(&composite);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate test;
+    #[cfg(feature = "threadpool")]
+    extern crate threadpool;
+
+    use super::*;
+    use super::super::vec::RFloat;
+    use super::super::group::Instance;
+    use std::sync::Arc;
+    use std::default::Default;
+    #[cfg(feature = "threadpool")]
+    use std::io::Read;
+
+    #[cfg(feature = "threadpool")]
+    use self::threadpool::ThreadPool;
+
+    #[derive(Default)]
+    struct DummyWriter {
+        begin_called: bool,
+        write_count: usize,
+    }
+
+    impl RGBABufferWriter for DummyWriter {
+        fn begin(&mut self, _: u16, _: u16) -> Result<(), RenderError> {
+            self.begin_called = true;
+            Ok(())
+        }
+        fn write_rgba_buffer(&mut self, _: &RGBABuffer) {
+            self.write_count += 1;
+        }
+    }
+
+    /// Fails every write, like stdout piped into a process which exited
+    struct BrokenPipe {
+        seekable: bool,
+        writes: usize,
+    }
+
+    impl io::Write for BrokenPipe {
+        fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+            self.writes += 1;
+            Err(io::Error::new(io::ErrorKind::BrokenPipe, "closed"))
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl ImageOutput for BrokenPipe {
+        fn can_seek(&self) -> bool {
+            self.seekable
+        }
+
+        fn rewind(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Keeps the entire image in memory
+    #[derive(Default)]
+    struct ImageWriter {
+        image: Option<RGBABuffer>,
+    }
+
+    impl RGBABufferWriter for ImageWriter {
+        fn begin(&mut self, x: u16, y: u16) -> Result<(), RenderError> {
+            self.image = Some(RGBABuffer::new(&ImageRegion {
+                l: 0,
+                r: x,
+                b: 0,
+                t: y,
+            }));
+            Ok(())
+        }
+        fn write_rgba_buffer(&mut self, b: &RGBABuffer) {
+            self.image.as_mut().unwrap().set_pixels_from_buffer(b);
+        }
+    }
+
+    const W: usize = 64;
+    const H: usize = 128;
+
+    #[test]
+    #[cfg(feature = "threadpool")]
+    fn basic_rendering() {
+        let s: Arc<Scene> = Arc::new(Default::default());
+        let pool = ThreadPool::new(1);
+        let options = RenderOptions {
+            width: W as u16,
+            height: H as u16,
+            samples_per_axis: 2,
+            ..Default::default()
+        };
+
+        let mut dw: DummyWriter = Default::default();
+        Renderer::render(&options, s.clone(), &mut dw, &pool).unwrap();
+
+        assert!(dw.begin_called);
+        assert_eq!(dw.write_count, 2);
+    }
+
+    #[test]
+    #[cfg(feature = "threadpool")]
+    fn summary() {
+        let s: Arc<Scene> = Arc::new(Scene::unit_test_scene());
+        let options = RenderOptions {
+            width: W as u16,
+            height: H as u16,
+            ..Default::default()
+        };
+
+        let mut dw: DummyWriter = Default::default();
+        let summary = Renderer::render(&options, s.clone(), &mut dw, &ThreadPool::new(4)).unwrap();
+        assert_eq!(summary.threads, 4);
+        assert_eq!(summary.tiles, 2);
+
+        let summary = Renderer::render_scoped(&options, &s, &mut dw, 0).unwrap();
+        assert_eq!(summary.threads, 1);
+    }
+
+    #[test]
+    #[cfg(feature = "threadpool")]
+    fn render_into() {
+        let s: Arc<Scene> = Arc::new(Default::default());
+        let options = RenderOptions {
+            width: W as u16,
+            height: H as u16,
+            ..Default::default()
+        };
+        let pool = ThreadPool::new(2);
+        let mut iw: ImageWriter = Default::default();
+        Renderer::render(&options, s.clone(), &mut iw, &pool).unwrap();
+
+        let mut out = vec![0u8; W * H * RGBABuffer::components()];
+        Renderer::render_into(&options, s.clone(), &pool, &mut out).unwrap();
+        assert!(out == *iw.image.unwrap().buffer());
+
+        assert_eq!(Renderer::render_into(&options, s, &pool, &mut out[1..]).unwrap_err(),
+                   RenderError::BufferSizeMismatch {
+                       expected: out.len(),
+                       actual: out.len() - 1,
+                   });
+    }
+
+    #[test]
+    #[cfg(feature = "threadpool")]
+    fn render_to_buffer() {
+        let s: Arc<Scene> = Arc::new(Default::default());
+        let options = RenderOptions {
+            width: W as u16,
+            height: H as u16,
+            ..Default::default()
+        };
+        let pool = ThreadPool::new(2);
+        let mut dw: DummyWriter = Default::default();
+        let summary = Renderer::render(&options, s.clone(), &mut dw, &pool).unwrap();
+        let mut iw: ImageWriter = Default::default();
+        Renderer::render(&options, s.clone(), &mut iw, &pool).unwrap();
+
+        let pixels = Renderer::render_to_buffer(&options, s.clone(), &pool).unwrap();
+        assert_eq!(dw.write_count, summary.tiles);
+        assert_eq!(pixels.len(), W * H * RGBABuffer::components());
+        assert!(pixels == *iw.image.unwrap().buffer());
+        // The default scene leaves the top-left corner to the background
+        let background = color_to_rgba(&BACKGROUND, 0.0, &options.color_opts());
+        assert_eq!(pixels[..4], background);
+
+        let tight = RenderOptions {
+            memory_budget: pixels.len() - 1,
+            ..options
+        };
+        assert_eq!(Renderer::render_to_buffer(&tight, s, &pool).unwrap_err(),
+                   RenderError::OverBudget {
+                       bytes: pixels.len(),
+                       budget: pixels.len() - 1,
+                   });
+    }
+
+    #[test]
+    #[cfg(feature = "threadpool")]
+    fn render_with() {
+        let s: Arc<Scene> = Arc::new(Default::default());
+        let options = RenderOptions {
+            width: W as u16,
+            height: H as u16,
+            ..Default::default()
+        };
+        let pool = ThreadPool::new(2);
+        let mut iw: ImageWriter = Default::default();
+        Renderer::render(&options, s.clone(), &mut iw, &pool).unwrap();
+
+        let mut image = RGBABuffer::new(&ImageRegion::new(0, 0, W as u16, H as u16));
+        let mut tiles = 0;
+        let summary = Renderer::render_with(&options, s, &pool, |tile| {
+                tiles += 1;
+                image.set_pixels_from_buffer(tile);
+            })
+            .unwrap();
+        assert!(summary.complete);
+        assert_eq!(tiles, summary.tiles);
+        assert!(image.buffer() == iw.image.unwrap().buffer());
+    }
+
+    #[test]
+    #[cfg(feature = "threadpool")]
+    fn deadline() {
+        let s: Arc<Scene> = Arc::new(Default::default());
+        let options = RenderOptions {
+            width: 2 * CHUNK_SIZE,
+            height: 2 * CHUNK_SIZE,
+            // It has passed by the time the first tile starts
+            deadline: Some(Instant::now()),
+            ..Default::default()
+        };
+        let mut iw: ImageWriter = Default::default();
+        let summary = Renderer::render(&options, s, &mut iw, &ThreadPool::new(2)).unwrap();
+        assert!(!summary.complete);
+        assert_eq!(summary.tiles, 4);
+        assert_eq!(summary.skipped_tiles, 4);
+        // Only background was written
+        assert!(iw.image.unwrap().buffer().chunks(4).all(|p| p[3] == 0));
+
+        let mut dw: DummyWriter = Default::default();
+        let summary = Renderer::render_scoped(&options, &Scene::default(), &mut dw, 2).unwrap();
+        assert!(!summary.complete);
+        assert_eq!(summary.skipped_tiles, 4);
+        assert_eq!(dw.write_count, 4);
+    }
+
+    #[test]
+    fn validate() {
+        let with_sphere = |center: Vector, radius: RFloat| {
+            let mut s = Scene::default();
+            s.group.children.push(Pair::Item(Sphere {
+                center,
+                radius,
+                ..Default::default()
+            }));
+            s
+        };
+        assert_eq!(Scene::default().validate(), Ok(()));
+
+        let zero_radius = with_sphere(Vector::default(), 0.0);
+        assert_eq!(zero_radius.validate(),
+                   Err(SceneError::DegenerateSphere {
+                       center: Vector::default(),
+                       radius: 0.0,
+                   }));
+        let options = RenderOptions {
+            width: 8,
+            height: 8,
+            ..Default::default()
+        };
+        let mut dw: DummyWriter = Default::default();
+        assert!(matches!(Renderer::render_scoped(&options, &zero_radius, &mut dw, 1),
+                         Err(RenderError::InvalidScene(SceneError::DegenerateSphere { .. }))));
+        assert!(!dw.begin_called);
+
+        let nan_center = with_sphere(Vector {
+                                         x: RFloat::NAN,
+                                         y: 0.0,
+                                         z: 0.0,
+                                     },
+                                     1.0);
+        assert!(matches!(nan_center.validate(), Err(SceneError::DegenerateSphere { .. })));
+
+        let no_light = Scene {
+            directional_light: Vector::default(),
+            ..Default::default()
+        };
+        assert_eq!(no_light.validate(), Err(SceneError::InvalidLight(Vector::default())));
+
+        let overlapping = Scene {
+            lattice: Some(Lattice {
+                sphere: Default::default(),
+                period: 1.0,
+            }),
+            ..Default::default()
+        };
+        assert_eq!(overlapping.validate(),
+                   Err(SceneError::InvalidLatticePeriod {
+                       radius: 1.0,
+                       period: 1.0,
+                   }));
+    }
+
+    #[test]
+    #[cfg(feature = "threadpool")]
+    fn scanlines() {
+        let s: Arc<Scene> = Arc::new(Default::default());
+        // The second size leaves partial tiles along the edges
+        for &(width, height) in &[(H, H), (100, 70)] {
+            let options = RenderOptions {
+                width: width as u16,
+                height: height as u16,
+                samples_per_axis: 1,
+                ..Default::default()
+            };
+
+            let mut iw: ImageWriter = Default::default();
+            Renderer::render(&options, s.clone(), &mut iw, &ThreadPool::new(2)).unwrap();
+
+            let rows: Vec<Vec<u8>> = Renderer::scanlines(&options, s.clone())
+                .unwrap()
+                .collect::<Result<_, _>>()
+                .unwrap();
+            assert_eq!(rows.len(), height);
+            assert!(rows.iter().all(|r| r.len() == width * 4));
+            assert!(rows.concat() == *iw.image.unwrap().buffer());
+        }
+
+        let options = RenderOptions {
+            width: W as u16,
+            height: W as u16,
+            ..Default::default()
+        };
+        let invalid = RenderOptions {
+            samples_per_axis: 0,
+            ..options
+        };
+        assert_eq!(Renderer::scanlines(&invalid, s.clone()).err(),
+                   Some(RenderError::InvalidSamplesPerAxis(0)));
+
+        let mut panicking = Scene::unit_test_scene();
+        panicking.background = Box::new(|_| panic!("deliberate panic in the background"));
+        let rows: Vec<_> = Renderer::scanlines(&options, Arc::new(panicking)).unwrap().collect();
+        assert_eq!(rows, [Err(RenderError::WorkerPanicked)]);
+    }
+
+    #[test]
+    fn memory_budget() {
+        let s: Arc<Scene> = Arc::new(Default::default());
+        let options = RenderOptions {
+            width: u16::MAX,
+            height: u16::MAX,
+            ..Default::default()
+        };
+
+        let mut dw: DummyWriter = Default::default();
+        match Renderer::render_scoped(&options, &s, &mut dw, 1) {
+            Err(RenderError::OverBudget { bytes, budget }) => {
+                assert_eq!(bytes, u16::MAX as usize * u16::MAX as usize * 4);
+                assert_eq!(budget, options.memory_budget);
+            }
+            _ => panic!("Expected the render to exceed the budget"),
+        }
+        assert!(!dw.begin_called);
+
+        let r = ImageRegion {
+            l: 0,
+            r: 64,
+            b: 0,
+            t: 64,
+        };
+        assert!(RGBABuffer::try_new(&r, 64 * 64 * 4).is_ok());
+        assert!(RGBABuffer::try_new(&r, 64 * 64 * 4 - 1).is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "threadpool")]
+    fn scoped_rendering() {
+        let s: Arc<Scene> = Arc::new(Default::default());
+        let options = RenderOptions {
+            width: H as u16,
+            height: H as u16,
+            samples_per_axis: 2,
+            ..Default::default()
+        };
+
+        let mut pooled: ImageWriter = Default::default();
+        Renderer::render(&options, s.clone(), &mut pooled, &ThreadPool::new(3)).unwrap();
+        let mut scoped: ImageWriter = Default::default();
+        Renderer::render_scoped(&options, &s, &mut scoped, 3).unwrap();
+
+        assert!(pooled.image.unwrap().buffer() == scoped.image.unwrap().buffer());
+    }
+
+    #[test]
+    fn tile_pool() {
+        let s = Scene::unit_test_scene();
+        let options = RenderOptions {
+            width: 4 * CHUNK_SIZE,
+            height: 4 * CHUNK_SIZE,
+            ..Default::default()
+        };
+        let render = || {
+            let mut iw: ImageWriter = Default::default();
+            let summary = Renderer::render_scoped(&options, &s, &mut iw, 2).unwrap();
+            (summary, iw.image.unwrap().buffer().clone())
+        };
+        let ((first, image), (second, again)) = (render(), render());
+        assert!(image == again);
+        for summary in &[first, second] {
+            assert_eq!(summary.tiles, 16);
+            // No more buffers than fit into the channel, plus one per thread and the writer
+            assert!(summary.allocated_buffers <= 4 + 2 + 1, "{}", summary.allocated_buffers);
+        }
+
+        let pool: TilePool = Default::default();
+        let region = ImageRegion::new(0, 0, 8, 8);
+        let b = pool.buffer(&region);
+        let ptr = b.buffer().as_ptr();
+        pool.recycle(b);
+        assert_eq!(pool.buffer(&region).buffer().as_ptr(), ptr);
+        assert_eq!(pool.allocated(), 1);
+    }
+
+    #[test]
+    #[cfg(feature = "threadpool")]
+    fn worker_panic() {
+        let pool = ThreadPool::new(2);
+        let (tx, rx) = sync_channel::<RGBABuffer>(4);
+        for i in 0..4 {
+            let tx = tx.clone();
+            pool.execute(move || {
+                if i == 2 {
+                    panic!("deliberate panic in tile {}", i);
+                }
+                tx.send(RGBABuffer::new(&ImageRegion {
+                        l: 0,
+                        r: 1,
+                        b: 0,
+                        t: 1,
+                    }))
+                    .unwrap();
+            });
+        }
+        drop(tx);
+
+        let mut dw: DummyWriter = Default::default();
+        let buffers: TilePool = Default::default();
+        assert_eq!(Renderer::write_tiles(&rx, 4, &mut dw, &mut Default::default(), &buffers),
+                   Err(RenderError::WorkerPanicked));
+        assert_eq!(dw.write_count, 3);
+    }
+
+    /// Raises the cancel flag once the first tile was written
+    #[cfg(feature = "threadpool")]
+    struct CancellingWriter<'a> {
+        cancel: Arc<AtomicBool>,
+        out: PPMStdoutRGBABufferWriter<'a>,
+    }
+
+    #[cfg(feature = "threadpool")]
+    impl<'a> RGBABufferWriter for CancellingWriter<'a> {
+        fn begin(&mut self, x: u16, y: u16) -> Result<(), RenderError> {
+            self.out.begin(x, y)
+        }
+        fn write_rgba_buffer(&mut self, b: &RGBABuffer) {
+            self.out.write_rgba_buffer(b);
+            self.cancel.store(true, Ordering::Relaxed);
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "threadpool")]
+    fn progress() {
+        let s: Arc<Scene> = Arc::new(Default::default());
+        let options = RenderOptions {
+            width: 3 * W as u16,
+            height: 2 * W as u16,
+            ..Default::default()
+        };
+        let caller = thread::current().id();
+        let mut calls = Vec::new();
+        let summary = {
+            let mut progress = |completed, total| {
+                assert_eq!(thread::current().id(), caller, "progress runs on a worker");
+                calls.push((completed, total));
+            };
+            Renderer::render_controlled(&options,
+                                        s,
+                                        &mut DummyWriter::default(),
+                                        &ThreadPool::new(4),
+                                        &mut RenderControl {
+                                            progress: Some(&mut progress),
+                                            ..Default::default()
+                                        })
+                .unwrap()
+        };
+        assert!(summary.complete);
+        let expected: Vec<_> = (1..=6).map(|completed| (completed, 6)).collect();
+        assert_eq!(calls, expected);
+    }
+
+    #[test]
+    #[cfg(feature = "threadpool")]
+    fn cancel() {
+        let s: Arc<Scene> = Arc::new(Default::default());
+        let options = RenderOptions {
+            width: W as u16,
+            height: 16 * W as u16,
+            ..Default::default()
+        };
+        let path = ::std::env::temp_dir().join("rtrace-cancel-test.ppm");
+        let mut file = io::BufWriter::new(fs::File::create(&path).unwrap());
+        let cancel = Arc::new(AtomicBool::new(false));
+        let mut progress_calls = Vec::new();
+
+        let summary = {
+            let mut progress = |completed, total| progress_calls.push((completed, total));
+            let mut writer = CancellingWriter {
+                cancel: cancel.clone(),
+                out: PPMStdoutRGBABufferWriter::new(true, &mut file),
+            };
+            Renderer::render_controlled(&options,
+                                        s,
+                                        &mut writer,
+                                        &ThreadPool::new(1),
+                                        &mut RenderControl {
+                                            progress: Some(&mut progress),
+                                            cancel: Some(cancel.clone()),
+                                        })
+                .unwrap()
+        };
+        assert!(!summary.complete);
+        assert!(summary.tiles >= 1 && summary.tiles < 16, "{:?}", summary);
+        assert_eq!(progress_calls.len(), summary.tiles);
+        assert_eq!(progress_calls[0], (1, 16));
+
+        // The partial image is flushed in full, with its missing tiles left black
+        drop(file);
+        let mut ppm = Vec::new();
+        fs::File::open(&path).unwrap().read_to_end(&mut ppm).unwrap();
+        fs::remove_file(&path).ok();
+        let header = format!("P6\n{} {}\n255\n", options.width, options.height);
+        assert!(ppm.starts_with(header.as_bytes()));
+        let pixels = &ppm[header.len()..];
+        assert_eq!(pixels.len(), W * 16 * W * 3);
+        assert!(pixels.iter().any(|&c| c != 0));
+        assert!(pixels[pixels.len() - W * 3..].iter().all(|&c| c == 0));
+    }
+
+    #[test]
+    #[cfg(feature = "threadpool")]
+    fn cancel_before_start() {
+        let s: Arc<Scene> = Arc::new(Default::default());
+        let options = RenderOptions {
+            width: W as u16,
+            height: 4 * W as u16,
+            ..Default::default()
+        };
+        // Workers check the flag before each tile, and skip all of them
+        let summary = Renderer::render_controlled(&options,
+                                                  s,
+                                                  &mut DummyWriter::default(),
+                                                  &ThreadPool::new(2),
+                                                  &mut RenderControl {
+                                                      cancel: Some(Arc::new(AtomicBool::new(true))),
+                                                      ..Default::default()
+                                                  })
+            .unwrap();
+        assert!(!summary.complete);
+        assert_eq!(summary.tiles, 0);
+    }
+
+    #[test]
+    fn default_render_unchanged() {
+        let options = RenderOptions {
+            width: SELF_TEST_SIZE,
+            height: SELF_TEST_SIZE,
+            ..Default::default()
+        };
+        let mut iw: ImageWriter = Default::default();
+        Renderer::render_scoped(&options, &Scene::default(), &mut iw, 1).unwrap();
+        assert_eq!(image_hash(iw.image.unwrap().buffer()), SELF_TEST_HASH);
+    }
+
+    #[test]
+    fn self_test() {
+        assert!(Renderer::self_test());
+    }
+
+    #[test]
+    fn srgb() {
+        let s = Scene::unit_test_scene();
+        let render = |srgb| -> Vec<u8> {
+            let options = RenderOptions {
+                width: W as u16,
+                height: W as u16,
+                color: ColorOpts {
+                    srgb,
+                    ..Default::default()
+                },
+                ..Default::default()
+            };
+            let mut iw: ImageWriter = Default::default();
+            Renderer::render_scoped(&options, &s, &mut iw, 1).unwrap();
+            iw.image.unwrap().buffer().clone()
+        };
+        let (linear, srgb) = (render(false), render(true));
+        for (l, s) in linear.chunks(4).zip(srgb.chunks(4)) {
+            assert!(l[..3].iter().zip(&s[..3]).all(|(l, s)| l <= s), "{:?} {:?}", l, s);
+            assert_eq!(l[3], s[3]);
+        }
+        assert!(linear != srgb);
+    }
+
+    #[test]
+    fn pixel_sources() {
+        struct RGBBuffer {
+            width: u16,
+            height: u16,
+            pixels: Vec<[f32; 3]>,
+        }
+
+        impl PixelSource for RGBBuffer {
+            fn dimensions(&self) -> (u16, u16) {
+                (self.width, self.height)
+            }
+            fn rgb_at(&self, x: u16, y: u16) -> [f32; 3] {
+                self.pixels[y as usize * self.width as usize + x as usize]
+            }
+        }
+
+        let mut rgba = RGBABuffer::new(&ImageRegion::new(3, 5, 4, 2));
+        let mut rgb = RGBBuffer {
+            width: 4,
+            height: 2,
+            pixels: Vec::new(),
+        };
+        for y in 5..7 {
+            for x in 3..7 {
+                let c = Vector {
+                    x: x as RFloat / 8.0,
+                    y: y as RFloat / 8.0,
+                    z: 1.0,
+                };
+                rgba.set_pixel_from_color(x,
+                                          y,
+                                          &Color::from_linear_rgb(c),
+                                          0.5,
+                                          &Default::default());
+                let ofs = rgba.region().buffer_offset(x, y) * RGBABuffer::components();
+                let b = &rgba.buffer()[ofs..ofs + 3];
+                rgb.pixels.push([b[0] as f32 / 255.0, b[1] as f32 / 255.0, b[2] as f32 / 255.0]);
+            }
+        }
+
+        for &color in &[true, false] {
+            let (mut from_rgba, mut from_rgb) = (Vec::new(), Vec::new());
+            write_ppm(&mut from_rgba, &rgba, color).unwrap();
+            write_ppm(&mut from_rgb, &rgb, color).unwrap();
+            assert_eq!(from_rgba, from_rgb);
+        }
+        let mut ppm = Vec::new();
+        write_ppm(&mut ppm, &rgba, true).unwrap();
+        assert_eq!(&ppm[..11], b"P6\n4 2\n255\n");
+        assert_eq!(&ppm[11..14], &rgba.buffer()[..3]);
+    }
+
+    #[test]
+    fn partial_tiles() {
+        // Counts how often each pixel is written
+        #[derive(Default)]
+        struct CountingWriter {
+            width: usize,
+            counts: Vec<u8>,
+        }
+        impl RGBABufferWriter for CountingWriter {
+            fn begin(&mut self, x: u16, y: u16) -> Result<(), RenderError> {
+                self.width = x as usize;
+                self.counts = vec![0; x as usize * y as usize];
+                Ok(())
+            }
+            fn write_rgba_buffer(&mut self, b: &RGBABuffer) {
+                let r = b.region();
+                for y in r.b..r.t {
+                    for x in r.l..r.r {
+                        self.counts[y as usize * self.width + x as usize] += 1;
+                    }
+                }
+            }
+        }
+
+        let s = Scene::default();
+        let options = RenderOptions {
+            width: 100,
+            height: 70,
+            ..Default::default()
+        };
+        let tiles = Renderer::tiles(&options);
+        assert_eq!(tiles.len(), 4);
+        assert!(tiles.contains(&ImageRegion::new(CHUNK_SIZE, CHUNK_SIZE, 100 - CHUNK_SIZE, 6)));
+
+        let mut counter: CountingWriter = Default::default();
+        Renderer::render_scoped(&options, &s, &mut counter, 2).unwrap();
+        assert_eq!(counter.counts.len(), 100 * 70);
+        assert!(counter.counts.iter().all(|&c| c == 1));
+
+        let mut iw: ImageWriter = Default::default();
+        Renderer::render_scoped(&options, &s, &mut iw, 2).unwrap();
+        let whole = Renderer::render_patch(&options, &s, &ImageRegion::new(0, 0, 100, 70)).unwrap();
+        assert!(iw.image.unwrap().buffer() == whole.buffer());
+    }
+
+    #[test]
+    fn tga() {
+        let (width, height) = (3, 2);
+        let mut tile = RGBABuffer::new(&ImageRegion::new(0, 0, width, height));
+        for y in 0..height {
+            for x in 0..width {
+                let c = Vector::new(x as RFloat * 0.5, y as RFloat, 0.25);
+                let c = Color::from_linear_rgb(c);
+                tile.set_pixel_from_color(x, y, &c, 1.0, &Default::default());
+            }
+        }
+        let mut tga = Vec::new();
+        {
+            let mut writer = TgaRGBABufferWriter::new(&mut tga);
+            writer.begin(width, height).unwrap();
+            writer.write_rgba_buffer(&tile);
+            writer.finish().unwrap();
+        }
+
+        let u16_at = |ofs: usize| u16::from_le_bytes([tga[ofs], tga[ofs + 1]]);
+        assert_eq!(tga[0], 0, "no image id");
+        assert_eq!(tga[1], 0, "no color map");
+        assert_eq!(tga[2], 2, "uncompressed true color");
+        assert_eq!((u16_at(12), u16_at(14)), (width, height));
+        assert_eq!(tga[16], 24, "bits per pixel");
+        assert_eq!(tga[17], 0, "rows from the bottom to the top");
+        assert_eq!(tga.len(), 18 + 3 * width as usize * height as usize);
+
+        for y in 0..height {
+            for x in 0..width {
+                let row = (height - 1 - y) as usize;
+                let ofs = 18 + 3 * (row * width as usize + x as usize);
+                let rgba = tile.region().buffer_offset(x, y) * RGBABuffer::components();
+                let rgb = &tile.buffer()[rgba..rgba + 3];
+                assert_eq!(&tga[ofs..ofs + 3], &[rgb[2], rgb[1], rgb[0]], "at {}x{}", x, y);
+            }
+        }
+
+        let mut ppm = Vec::new();
+        {
+            let mut writer = PPMStdoutRGBABufferWriter::new(true, &mut ppm);
+            writer.begin(width, height).unwrap();
+            writer.write_rgba_buffer(&tile);
+        }
+        let mut expected = Vec::new();
+        write_ppm(&mut expected, &tile, true).unwrap();
+        assert_eq!(ppm, expected, "the PPM writer keeps writing PPM");
+
+        let mut broken = BrokenPipe {
+            seekable: false,
+            writes: 0,
+        };
+        let mut writer = TgaRGBABufferWriter::new(&mut broken);
+        writer.begin(width, height).unwrap();
+        writer.write_rgba_buffer(&tile);
+        assert_eq!(writer.finish().unwrap_err().kind(), io::ErrorKind::BrokenPipe);
+    }
+
+    #[test]
+    fn image_outputs() {
+        fn write_two_tiles(out: &mut dyn ImageOutput) {
+            let mut writer = PPMStdoutRGBABufferWriter::new(true, out);
+            writer.begin(2, 1).unwrap();
+            for x in 0..2 {
+                let mut tile = RGBABuffer::new(&ImageRegion::new(x, 0, 1, 1));
+                tile.set_pixel_from_color(x,
+                                          0,
+                                          &Color::grey(x as RFloat),
+                                          1.0,
+                                          &Default::default());
+                writer.write_rgba_buffer(&tile);
+            }
+        }
+
+        // Gets the image after the first tile, which is rewritten with the final one
+        let mut seekable = io::Cursor::new(Vec::new());
+        assert!(seekable.can_seek());
+        write_two_tiles(&mut seekable);
+
+        let mut sink = Vec::new();
+        assert!(!sink.can_seek());
+        assert!(sink.rewind().is_err());
+        write_two_tiles(&mut sink);
+
+        assert_eq!(sink, b"P6\n2 1\n255\n\x00\x00\x00\xff\xff\xff");
+        assert_eq!(seekable.into_inner(), sink);
+
+        // Writing stops at the first error, which finish() reports
+        let tile = RGBABuffer::new(&ImageRegion::new(0, 0, 1, 1));
+        let mut broken = BrokenPipe {
+            seekable: true,
+            writes: 0,
+        };
+        {
+            let mut writer = PPMStdoutRGBABufferWriter::new(true, &mut broken);
+            writer.begin(1, 1).unwrap();
+            writer.write_rgba_buffer(&tile);
+            writer.write_rgba_buffer(&tile);
+            assert_eq!(writer.finish().unwrap_err().kind(), io::ErrorKind::BrokenPipe);
+        }
+        assert_eq!(broken.writes, 1);
+
+        // Dropping the writer loses the error, but doesn't panic
+        let mut broken = BrokenPipe {
+            seekable: false,
+            writes: 0,
+        };
+        {
+            let mut writer = PPMStdoutRGBABufferWriter::new(true, &mut broken);
+            writer.begin(1, 1).unwrap();
+            writer.write_rgba_buffer(&tile);
+        }
+        assert_eq!(broken.writes, 1);
+    }
+
+    #[test]
+    fn thumbnail() {
+        let s = Scene::default();
+        let options = RenderOptions {
+            width: W as u16,
+            height: H as u16,
+            ..Default::default()
+        };
+        let mut iw: ImageWriter = Default::default();
+        Renderer::render_scoped(&options, &s, &mut iw, 1).unwrap();
+        let image = iw.image.unwrap();
+
+        let thumb = image.thumbnail(32);
+        assert_eq!((thumb.region().width(), thumb.region().height()), (16, 32));
+        assert_eq!(image.thumbnail(1024).buffer(), image.buffer());
+
+        // Each thumbnail pixel covers 4x4 pixels of the full image
+        let mut corner = [0usize; 4];
+        for y in 0..4 {
+            for x in 0..4 {
+                let ofs = image.region().buffer_offset(x, y) * RGBABuffer::components();
+                for (c, &v) in corner.iter_mut().zip(&image.buffer()[ofs..ofs + 4]) {
+                    *c += v as usize;
+                }
+            }
+        }
+        for (&c, &t) in corner.iter().zip(&thumb.buffer()[..4]) {
+            assert!((c as i32 / 16 - t as i32).abs() <= 1);
+        }
+
+        let mut ppm = Vec::new();
+        write_ppm(&mut ppm, &thumb, true).unwrap();
+        assert!(ppm.starts_with(b"P6\n16 32\n255\n"));
+        assert_eq!(ppm.len(), "P6\n16 32\n255\n".len() + 16 * 32 * 3);
+    }
+
+    #[test]
+    fn alpha_over() {
+        let background = [0u8, 0, 255, 255, 10, 20, 30, 255, 255, 255, 255, 255];
+        let r = ImageRegion {
+            l: 1,
+            r: 3,
+            b: 0,
+            t: 1,
+        };
+        // A miss showing the background of the scene, and a half-covered edge of
+        // premultiplied black
+        let mut fg = RGBABuffer::new(&r);
+        fg.buf.copy_from_slice(&[200, 100, 0, 0, 0, 0, 0, 128]);
+        let composite = |fg: &RGBABuffer, opts: &ColorOpts| {
+            let mut iw: ImageWriter = Default::default();
+            {
+                let mut aw = AlphaOverRGBABufferWriter::new(&background, opts, &mut iw);
+                aw.begin(3, 1).unwrap();
+                aw.write_rgba_buffer(fg);
+            }
+            iw.image.unwrap().buffer()[4..].to_vec()
+        };
+        // The edge keeps half of the background, without any of the scene
+        let linear: ColorOpts = Default::default();
+        assert_eq!(composite(&fg, &linear), [10, 20, 30, 255, 127, 127, 127, 255]);
+        // ... which is half of its light, not of its encoded value
+        let gamma = RenderOptions::default().color_opts();
+        assert_eq!(composite(&fg, &gamma), [10, 20, 30, 255, 186, 186, 186, 255]);
+        // Premultiplied colors are added to the background
+        fg.buf.copy_from_slice(&[200, 100, 0, 0, 100, 50, 0, 128]);
+        assert_eq!(composite(&fg, &linear)[4..], [227, 177, 127, 255]);
+
+        let mut iw: ImageWriter = Default::default();
+        let mut aw = AlphaOverRGBABufferWriter::new(&background, &linear, &mut iw);
+        assert_eq!(aw.begin(2, 1).unwrap_err(),
+                   RenderError::BufferSizeMismatch {
+                       expected: 8,
+                       actual: background.len(),
+                   });
+    }
+
+    #[test]
+    fn unit_test_scene() {
+        let s = Scene::unit_test_scene();
+        let options = RenderOptions {
+            width: 64,
+            height: 64,
+            // Written as they are, to compare them with the constants
+            color: ColorOpts::default(),
+            ..Default::default()
+        };
+        let mut b = RGBABuffer::new(&ImageRegion {
+            l: 32,
+            r: 33,
+            b: 32,
+            t: 33,
+        });
+        Renderer::render_region(&options, &s, &mut b).unwrap();
+
+        // OBJECT * -(n.l) + BACKGROUND * 0.8, with n.l == -1
+        let expected = [0xae as RFloat + 0x22 as RFloat * 0.8,
+                        0x31 as RFloat + 0x0a as RFloat * 0.8,
+                        0x31 as RFloat + 0x0a as RFloat * 0.8,
+                        255.0];
+        for (&c, &e) in b.buffer().iter().zip(expected.iter()) {
+            assert!((c as RFloat - e).abs() <= 1.0, "{:?} != {:?}", b.buffer(), expected);
+        }
+    }
+
+    #[test]
+    fn shadow_bias() {
+        // A tiny sphere resting on top of another one, lit from straight above
+        let r: RFloat = 1e-4;
+        let mut s = Scene::unit_test_scene();
+        s.group.children.clear();
+        s.group.bound.radius = 4.0 * r;
+        for &y in &[0.0, 2.0 * r] {
+            s.group.children.push(Pair::Item(Sphere {
+                center: Vector {
+                    x: 0.0,
+                    y,
+                    z: 0.0,
+                },
+                radius: r,
+                material: None,
+            }));
+        }
+        s.directional_light = Vector {
+            x: 0.0,
+            y: -1.0,
+            z: 0.0,
+        };
+
+        // Looking at the upper half of the lower sphere, which lies in the shadow of the
+        // upper one. An offset independent of the sphere size would leave it lit.
+        let ray = Ray {
+            pos: Vector {
+                x: 0.0,
+                y: 0.5 * r,
+                z: -4.0,
+            },
+            dir: Vector {
+                x: 0.0,
+                y: 0.0,
+                z: 1.0,
+            },
+            differentials: None,
+            ..Default::default()
+        };
+        let mut c: Color = Default::default();
+        assert_eq!(Renderer::raytrace(&Default::default(), &s, &ray, &mut c, 0).lit, 0.0);
+
+        // Large spheres must not shadow themselves, even if they are far away
+        s.group.children.clear();
+        s.group.bound.radius = 1e6;
+        s.group.children.push(Pair::Item(Sphere {
+            center: Vector {
+                x: 0.0,
+                y: -1e3,
+                z: 1e3,
+            },
+            radius: 1e3,
+            material: None,
+        }));
+        let ray = Ray {
+            pos: Default::default(),
+            dir: Vector {
+                    x: 0.0,
+                    y: -1.0,
+                    z: 1.0,
+                }
+                .normalized(),
+            differentials: None,
+            ..Default::default()
+        };
+        assert_eq!(Renderer::raytrace(&Default::default(), &s, &ray, &mut c, 0).lit, 1.0);
+    }
+
+    #[test]
+    fn touching_spheres_without_acne() {
+        // Two spheres touching side by side, seen at a grazing angle and lit from the eye.
+        // Everything we see is lit then, including the crease where they meet.
+        let mut s = Scene::unit_test_scene();
+        s.group.children.clear();
+        s.group.bound.radius = 3.0;
+        for &x in &[-1.0, 1.0] {
+            s.group.children.push(Pair::Item(Sphere {
+                center: Vector::new(x, 0.0, 0.0),
+                radius: 1.0,
+                material: None,
+            }));
+        }
+        let dir = Vector::new(0.0, -0.2, 1.0).normalized();
+        s.directional_light = dir;
+
+        const N: usize = 64;
+        let mut hits = 0;
+        for i in 0..N * N {
+            let (x, y) = ((i % N) as RFloat + 0.5, (i / N) as RFloat + 0.5);
+            let (x, y) = (x / N as RFloat, y / N as RFloat);
+            let ray = Ray::new(Vector::new(4.0 * x - 2.0, 2.0 * y - 0.2, -4.0), dir);
+            let mut c: Color = Default::default();
+            let sample = Renderer::raytrace(&Default::default(), &s, &ray, &mut c, 0);
+            if sample.coverage == 1.0 {
+                hits += 1;
+                assert_eq!(sample.lit, 1.0, "speckle at {:?}", (x, y));
+            }
+        }
+        assert!(hits > N * N / 2);
+    }
+
+    #[test]
+    fn contact_hardening() {
+        // Counts the partly lit samples along a line on the ground, across the shadow of a
+        // sphere of radius 0.5 hovering at the given height above it
+        let penumbra = |height: RFloat, light_size: RFloat| {
+            let mut s = Scene {
+                directional_light: Vector { y: -1.0, ..Vector::ZERO },
+                light_size,
+                ..Scene::unit_test_scene()
+            };
+            s.group.children = vec![Pair::Item(Sphere {
+                                         radius: 0.5,
+                                         ..Default::default()
+                                     })];
+            let ground = -0.5 - height;
+            let s = s.with_ground(ground, OBJECT);
+            (0..200)
+                .filter(|&i| {
+                    // Grazing the ground, passing underneath the sphere
+                    let ray = Ray {
+                        pos: Vector {
+                            x: i as RFloat / 100.0 - 1.0,
+                            y: ground + 0.05,
+                            z: -5.0,
+                        },
+                        dir: Vector {
+                                y: -0.05,
+                                z: 5.0,
+                                ..Vector::ZERO
+                            }
+                            .normalized(),
+                        differentials: None,
+                        ..Default::default()
+                    };
+                    let mut c: Color = Default::default();
+                    let lit = Renderer::raytrace(&Default::default(), &s, &ray, &mut c, 0).lit;
+                    lit > 0.0 && lit < 1.0
+                })
+                .count()
+        };
+        assert_eq!(penumbra(0.1, 0.0), 0);
+        let near = penumbra(0.1, 0.05);
+        let far = penumbra(3.0, 0.05);
+        assert!(near > 0, "the shadow edge must be soft");
+        assert!(far > 2 * near, "{} vs {}", far, near);
+
+        let s = Scene {
+            light_size: f32::consts::FRAC_PI_2,
+            ..Default::default()
+        };
+        assert_eq!(s.validate(), Err(SceneError::InvalidLightSize(s.light_size)));
+    }
+
+    #[test]
+    fn occlusion() {
+        use super::super::primitive::NORMALS_COMPUTED;
+
+        let mut s = Scene::default();
+        let pyramid = Arc::new(SphericalGroup::pyramid(3, &Default::default(), 1.0));
+        s.instances.push(Instance::new(Transform::scale(&Vector::splat(0.5)), pyramid).unwrap());
+        let rays = shadow_rays(&s);
+        let normals = || NORMALS_COMPUTED.with(|n| n.get());
+
+        let mut occluded = 0;
+        for ray in &rays {
+            let mut h = Hit::missed();
+            s.intersect(&mut h, ray);
+            let before = normals();
+            assert_eq!(s.is_occluded(ray, f32::INFINITY), !h.has_missed());
+            assert!(!s.is_occluded(ray, 0.0));
+            assert_eq!(normals(), before, "occlusion queries must not compute normals");
+            occluded += !h.has_missed() as usize;
+        }
+        assert!(occluded > 0 && occluded < rays.len());
+    }
+
+    #[test]
+    fn self_intersections() {
+        let s = Scene::default();
+        let render = |shadow_bias| {
+            let options = RenderOptions {
+                width: W as u16,
+                height: W as u16,
+                shadow_bias,
+                ..Default::default()
+            };
+            Renderer::render_scoped(&options, &s, &mut DummyWriter::default(), 1)
+                .unwrap()
+                .self_intersections
+        };
+        // Without a bias, many lit surface points end up inside their own sphere
+        assert!(render(0.0) > W * W / 10);
+        assert!(render(SHADOW_BIAS) <= W * W / 1000);
+
+        // A sphere behind the eye shadows the lit one. Self-intersections count the same
+        // no matter if it's found before or after the sphere the shadow rays start on.
+        let occluder = Sphere {
+            center: Vector::new(0.0, 0.0, -10.0),
+            radius: 5.0,
+            material: None,
+        };
+        let unbiased = RenderOptions {
+            width: W as u16,
+            height: W as u16,
+            shadow_bias: 0.0,
+            ..Default::default()
+        };
+        let count = |occluder_first| {
+            let mut s = Scene::unit_test_scene();
+            let lit = s.group.children.pop().unwrap();
+            if occluder_first {
+                s.group.children.push(Pair::Item(occluder));
+                s.group.children.push(lit);
+            } else {
+                s.group.children.push(lit);
+                s.group.children.push(Pair::Item(occluder));
+            }
+            assert!(s.group.tested_bound().is_none(), "children are tested in order");
+            Renderer::render_scoped(&unbiased, &s, &mut DummyWriter::default(), 1)
+                .unwrap()
+                .self_intersections
+        };
+        let after = count(false);
+        assert!(after > 0);
+        assert_eq!(count(true), after);
+    }
+
+    #[test]
+    fn palette() {
+        let options = RenderOptions {
+            width: W as u16,
+            height: W as u16,
+            target: RenderTarget::Unlit,
+            // Written as they are, to compare them with the constants
+            color: ColorOpts::default(),
+            ..Default::default()
+        };
+        let render = |s: &Scene| -> RGBABuffer {
+            let mut iw: ImageWriter = Default::default();
+            Renderer::render_scoped(&options, s, &mut iw, 1).unwrap();
+            iw.image.unwrap()
+        };
+        let pixel = |b: &RGBABuffer, x, y| {
+            let ofs = b.region().buffer_offset(x, y) * RGBABuffer::components();
+            b.buffer()[ofs..ofs + 3].to_vec()
+        };
+        let center = W as u16 / 2;
+
+        let classic = render(&Scene::unit_test_scene().with_palette(Palette::ClassicRed));
+        assert_eq!(pixel(&classic, center, center), vec![0xae, 0x31, 0x31]);
+        assert_eq!(pixel(&classic, 0, 0), vec![0x22, 0x0a, 0x0a]);
+        assert!(classic.buffer() == render(&Scene::unit_test_scene()).buffer());
+
+        let grey = render(&Scene::unit_test_scene().with_palette(Palette::Grayscale));
+        for &(x, y) in &[(center, center), (0, 0)] {
+            let p = pixel(&grey, x, y);
+            assert!(p[0] == p[1] && p[1] == p[2], "{:?} at {}x{}", p, x, y);
+        }
+        assert!(pixel(&grey, center, center) != pixel(&grey, 0, 0));
+    }
+
+    #[test]
+    fn lambert() {
+        // Rays parallel to the light, hitting the sphere from its lit pole to its terminator
+        let brightness = |s: &Scene| -> Vec<RFloat> {
+            (0..10)
+                .map(|i| {
+                    let ray = Ray::new(Vector::new(i as RFloat * 0.099, 0.0, -4.0),
+                                       Vector::new(0.0, 0.0, 1.0));
+                    let mut c: Color = Default::default();
+                    Renderer::raytrace(&Default::default(), s, &ray, &mut c, 0);
+                    c.to_linear_rgb().x
+                })
+                .collect()
+        };
+        let mut s = Scene::unit_test_scene();
+        let falloff = brightness(&s);
+        for w in falloff.windows(2) {
+            assert!(w[0] > w[1], "{:?}", falloff);
+        }
+        // Only the direction of the light matters, not its length
+        s.directional_light = s.directional_light.mulfed(3.0);
+        assert_eq!(brightness(&s), falloff);
+    }
+
+    #[test]
+    fn materials() {
+        let blue = Vector::new(0.1, 0.2, 0.9);
+        let mut s = Scene::unit_test_scene();
+        s.group.children.clear();
+        s.group.bound.radius = 2.0;
+        for &(x, diffuse) in &[(-0.5, Material::DEFAULT_DIFFUSE), (0.5, blue)] {
+            s.group.children.push(Pair::Item(Sphere {
+                center: Vector::new(x, 0.0, 0.0),
+                radius: 0.5,
+                material: Some(Material {
+                    diffuse,
+                    ..Default::default()
+                }),
+            }));
+        }
+        let shade = |s: &Scene, x| -> Vector {
+            let ray = Ray::new(Vector::new(x, 0.0, -4.0), Vector::new(0.0, 0.0, 1.0));
+            let mut c: Color = Default::default();
+            assert_eq!(Renderer::raytrace(&Default::default(), s, &ray, &mut c, 0).coverage, 1.0);
+            c.to_linear_rgb()
+        };
+
+        let (left, right) = (shade(&s, -0.5), shade(&s, 0.5));
+        assert!(left != right);
+        assert!(right.z > right.x && left.x > left.z);
+        // The default material looks just like no material at all
+        if let Pair::Item(ref mut sphere) = s.group.children[0] {
+            sphere.material = None;
+        }
+        assert_eq!(shade(&s, -0.5), left);
+    }
+
+    #[test]
+    fn reflections() {
+        let blue = Vector::new(0.1, 0.2, 0.9);
+        let mut s = Scene {
+            directional_light: Vector::new(0.0, -1.0, -1.0).normalized(),
+            ..Scene::unit_test_scene()
+        };
+        s.group.children.clear();
+        s.group.bound.radius = 4.0;
+        // A mirror, and a blue sphere behind the start of the ray looking at it
+        for &(z, diffuse, reflectivity) in &[(0.0, OBJECT, 1.0), (-3.0, blue, 0.0)] {
+            s.group.children.push(Pair::Item(Sphere {
+                center: Vector::new(0.0, 0.0, z),
+                radius: 0.5,
+                material: Some(Material {
+                    diffuse,
+                    reflectivity,
+                    ..Default::default()
+                }),
+            }));
+        }
+        let ray = Ray::new(Vector::new(0.0, 0.0, -1.0), Vector::new(0.0, 0.0, 1.0));
+        let shade = |max_reflection_depth| -> Vector {
+            let options = RenderOptions {
+                max_reflection_depth,
+                ..Default::default()
+            };
+            let mut c: Color = Default::default();
+            assert_eq!(Renderer::raytrace(&options, &s, &ray, &mut c, 0).coverage, 1.0);
+            c.to_linear_rgb()
+        };
+
+        let matte = shade(0);
+        assert!(matte.x > matte.z, "{:?}", matte);
+        let mirror = shade(1);
+        assert!(mirror.z > mirror.x, "{:?}", mirror);
+        // The blue sphere isn't reflective, so there is nothing to bounce any further
+        assert_eq!(shade(4), mirror);
+    }
+
+    #[test]
+    fn bounces() {
+        let mut s = Scene::unit_test_scene();
+        s.group.children.clear();
+        s.group.bound.radius = 4.0;
+        // Two mirrors next to each other, between which some rays bounce back and forth
+        for &x in &[-1.05, 1.05] {
+            s.group.children.push(Pair::Item(Sphere {
+                center: Vector::new(x, 0.0, 0.0),
+                radius: 1.0,
+                material: Some(Material {
+                    reflectivity: 1.0,
+                    ..Default::default()
+                }),
+            }));
+        }
+        let render = |max_reflection_depth| {
+            let options = RenderOptions {
+                width: W as u16,
+                height: W as u16,
+                max_reflection_depth,
+                target: RenderTarget::Bounces,
+                ..Default::default()
+            };
+            let mut iw: ImageWriter = Default::default();
+            let summary = Renderer::render_scoped(&options, &s, &mut iw, 2).unwrap();
+            (summary.bounces, iw.image.unwrap())
+        };
+
+        let (bounces, image) = render(4);
+        assert_eq!(bounces.rays, W * W);
+        assert!(bounces.max > 1 && bounces.max <= 4, "{:?}", bounces);
+        assert!(bounces.mean() > 0.0 && bounces.mean() < 1.0, "{:?}", bounces);
+        // Pixels showing the mirrors are brighter than the background, which wasn't reflected
+        let pixel = |x: usize, y: usize| {
+            let ofs = (y * W + x) * 4;
+            image.buffer()[ofs..ofs + 4].to_vec()
+        };
+        assert_eq!(pixel(0, 0), [0, 0, 0, 255]);
+        for &x in &[W / 4, 3 * W / 4] {
+            assert!(pixel(x, W / 2)[0] > 0, "{:?} at {}", pixel(x, W / 2), x);
+        }
+
+        // Rays stop bouncing at the maximum depth, and don't bounce at all without it
+        assert_eq!(render(1).0.max, 1);
+        let (bounces, image) = render(0);
+        assert_eq!(bounces.total, 0);
+        assert!(image.buffer().chunks(4).all(|p| p == [0, 0, 0, 255]));
+    }
+
+    #[test]
+    fn emission() {
+        let mut s = Scene::unit_test_scene();
+        let ray = Ray {
+            pos: s.eye,
+            dir: Vector {
+                x: 0.0,
+                y: 0.0,
+                z: 1.0,
+            },
+            differentials: None,
+            ..Default::default()
+        };
+        let glow = Vector {
+            x: 0.2,
+            y: 2.0,
+            z: 0.5,
+        };
+        let shade = |s: &Scene| -> Vector {
+            let mut c: Color = Default::default();
+            assert_eq!(Renderer::raytrace(&Default::default(), s, &ray, &mut c, 0).coverage, 1.0);
+            c.to_linear_rgb()
+        };
+
+        assert!(shade(&s) != glow);
+        if let Pair::Item(ref mut sphere) = s.group.children[0] {
+            sphere.material = Some(Material {
+                emission: glow,
+                ..Default::default()
+            });
+        }
+        assert_eq!(shade(&s), glow);
+        // The light shines onto the back of the sphere, which would leave it dark
+        s.directional_light = s.directional_light.mulfed(-1.0);
+        assert_eq!(shade(&s), glow);
+    }
+
+    #[test]
+    fn background() {
+        let mut s = Scene::unit_test_scene();
+        s.background = Box::new(|r| {
+            Vector {
+                x: 0.0,
+                y: 0.0,
+                z: r.dir.y.max(0.0),
+            }
+        });
+        let mut ray: Ray = Default::default();
+        ray.pos.z = -4.0;
+        let mut up: Color = Default::default();
+        ray.dir.y = 1.0;
+        Renderer::raytrace(&Default::default(), &s, &ray, &mut up, 0);
+        let mut down: Color = Default::default();
+        ray.dir.y = -1.0;
+        Renderer::raytrace(&Default::default(), &s, &ray, &mut down, 0);
+
+        assert_eq!(up.to_linear_rgb().z, 1.0);
+        assert!(up != down);
+    }
+
+    #[test]
+    fn ground() {
+        let grey = Vector {
+            x: 0.5,
+            y: 0.5,
+            z: 0.5,
+        };
+        let s = Scene::default().with_ground(-2.0, grey);
+        // Where the light shining through the center of the base sphere meets the ground
+        let light = s.directional_light;
+        let shadowed = Vector {
+                x: 0.0,
+                y: -1.0,
+                z: 0.0,
+            } + light.mulfed(-1.0 / light.y);
+        let away = shadowed +
+                   Vector {
+            x: 10.0,
+            y: 0.0,
+            z: 10.0,
+        };
+
+        let brightness = |target: Vector| -> RFloat {
+            // Look down at the target from behind, past the pyramid
+            let offset = Vector {
+                x: 0.0,
+                y: 1.0,
+                z: 2.0,
+            };
+            let ray = Ray {
+                pos: target + offset.mulfed(3.0),
+                dir: offset.mulfed(-1.0).normalized(),
+                differentials: None,
+                ..Default::default()
+            };
+            let mut h = Hit::missed();
+            s.intersect(&mut h, &ray);
+            assert!(h.has_missed(), "the pyramid must not be in the way");
+
+            let mut c: Color = Default::default();
+            let sample = Renderer::raytrace(&Default::default(), &s, &ray, &mut c, 0);
+            assert_eq!(sample.coverage, 1.0);
+            let c = c.to_linear_rgb();
+            c.x + c.y + c.z
+        };
+        assert!(brightness(shadowed) < brightness(away));
+        assert!(Scene::default().ground.is_none());
+    }
+
+    #[test]
+    fn depth() {
+        let s = Scene::unit_test_scene();
+        let options = RenderOptions {
+            width: W as u16,
+            height: W as u16,
+            ..Default::default()
+        };
+        assert_eq!(Renderer::depth(&options, &Hit::missed()), options.max_depth);
+
+        // The corner tile misses the unit sphere entirely
+        let mut b = RGBABuffer::new(&ImageRegion {
+            l: 0,
+            r: 8,
+            b: 0,
+            t: 8,
+        });
+        Renderer::render_depth_region(&options, &s, &mut b).unwrap();
+        assert!(b.buffer().iter().all(|&c| c == 255), "{:?}", b.buffer());
+
+        // The center looks at the front of the sphere, at distance 3
+        let mut b = RGBABuffer::new(&ImageRegion {
+            l: 32,
+            r: 33,
+            b: 32,
+            t: 33,
+        });
+        Renderer::render_depth_region(&options, &s, &mut b).unwrap();
+        let expected = (255.0 * 3.0 / options.max_depth + 0.5) as u8;
+        assert!((b.buffer()[0] as i32 - expected as i32).abs() <= 1);
+
+        // Depth is measured from the eye, not the near plane
+        let clipped = RenderOptions {
+            near: 1.0,
+            ..options
+        };
+        let mut c = RGBABuffer::new(b.region());
+        Renderer::render_depth_region(&clipped, &s, &mut c).unwrap();
+        assert_eq!(c.buffer(), b.buffer());
+    }
+
+    #[test]
+    fn empty_tiles() {
+        let mut s = Scene::unit_test_scene();
+        s.background = Box::new(|r| r.dir);
+        let options = RenderOptions {
+            width: W as u16,
+            height: W as u16,
+            samples_per_axis: 2,
+            jitter: true,
+            ..Default::default()
+        };
+        let view = View::expect(&options, &s);
+        let corner = ImageRegion::new(0, 0, 8, 8);
+        let center = ImageRegion::new(W as u16 / 2 - 4, W as u16 / 2 - 4, 8, 8);
+        assert!(Renderer::misses_scene(&options, &s, &view, &corner));
+        assert!(!Renderer::misses_scene(&options, &s, &view, &center));
+        // Covering only a corner of the silhouette of the sphere
+        let edge = ImageRegion::new(W as u16 / 2, W as u16 / 2 - 8, 8, 8);
+        assert!(!Renderer::misses_scene(&options, &s, &view, &edge));
+
+        let mut fast = RGBABuffer::new(&corner);
+        Renderer::render_region(&options, &s, &mut fast).unwrap();
+        let mut per_pixel = RGBABuffer::new(&corner);
+        Renderer::render_samples(&options, &s, &view, &mut per_pixel, false);
+        assert!(fast.buffer() == per_pixel.buffer());
+        assert!(fast.buffer().chunks(4).all(|p| p[3] == 0));
+    }
+
+    #[test]
+    fn small_root_group() {
+        let mut s = Scene::unit_test_scene();
+        s.group.children.push(Pair::Item(Sphere {
+            center: Vector::new(1.5, 0.0, 0.0),
+            radius: 0.5,
+            material: None,
+        }));
+        let options = RenderOptions {
+            width: W as u16,
+            height: W as u16,
+            ..Default::default()
+        };
+        let mut expected: ImageWriter = Default::default();
+        Renderer::render_scoped(&options, &s, &mut expected, 1).unwrap();
+        let expected = expected.image.unwrap();
+        assert!(expected.buffer().chunks(4).any(|p| p[3] == 255));
+
+        // Two spheres are too few to test the bound, so it may be anywhere
+        s.group.bound = Sphere {
+            center: Vector::new(0.0, 100.0, 0.0),
+            radius: 0.0,
+            material: None,
+        };
+        let mut iw: ImageWriter = Default::default();
+        Renderer::render_scoped(&options, &s, &mut iw, 1).unwrap();
+        assert!(iw.image.unwrap().buffer() == expected.buffer());
+    }
+
+    #[test]
+    fn tile_order() {
+        let options = RenderOptions {
+            width: 5 * CHUNK_SIZE,
+            height: 3 * CHUNK_SIZE,
+            ..Default::default()
+        };
+        let with_order = |tile_order| {
+            Renderer::tiles(&RenderOptions {
+                tile_order,
+                ..options
+            })
+        };
+        let raster = with_order(TileOrder::Raster);
+        assert_eq!(raster[0], ImageRegion::new(0, 0, CHUNK_SIZE, CHUNK_SIZE));
+
+        let spiral = with_order(TileOrder::Spiral);
+        assert_eq!(spiral[0],
+                   ImageRegion::new(2 * CHUNK_SIZE, CHUNK_SIZE, CHUNK_SIZE, CHUNK_SIZE));
+        // The ring around the center comes before the corners of the outer one
+        assert!(spiral[1..9].iter().all(|t| t.l >= CHUNK_SIZE && t.l < 4 * CHUNK_SIZE));
+
+        let shuffled = with_order(TileOrder::Shuffled(7));
+        assert!(shuffled != raster);
+        assert!(shuffled == with_order(TileOrder::Shuffled(7)));
+        for order in &mut [spiral, shuffled] {
+            order.sort_by_key(|t| (t.b, t.l));
+            assert!(*order == raster);
+        }
+
+        // Pixels are the same no matter the order
+        let s = Scene::default();
+        let mut raster_image: ImageWriter = Default::default();
+        Renderer::render_scoped(&options, &s, &mut raster_image, 2).unwrap();
+        let mut spiral_image: ImageWriter = Default::default();
+        let spiral_options = RenderOptions {
+            tile_order: TileOrder::Spiral,
+            ..options
+        };
+        Renderer::render_scoped(&spiral_options, &s, &mut spiral_image, 2).unwrap();
+        assert!(raster_image.image.unwrap().buffer() == spiral_image.image.unwrap().buffer());
+    }
+
+    #[test]
+    fn render_changed() {
+        const SIZE: u16 = 4 * CHUNK_SIZE;
+        const SENTINEL: u8 = 0x11;
+        // Spheres at x and y project to the image center plus 64 * (x, -y) pixels
+        let scene = |moving_x: RFloat| {
+            let mut s = Scene::unit_test_scene();
+            s.group.bound.radius = 4.0;
+            s.group.children.clear();
+            for &(x, y) in &[(1.5, 1.5), (moving_x, -1.5)] {
+                s.group.children.push(Pair::Item(Sphere {
+                    center: Vector { x, y, z: 0.0 },
+                    radius: 0.1,
+                    ..Default::default()
+                }));
+            }
+            s
+        };
+        let (old, new) = (scene(-1.5), scene(-0.5));
+        let options = RenderOptions {
+            width: SIZE,
+            height: SIZE,
+            ..Default::default()
+        };
+        let mut previous = RGBABuffer::new(&ImageRegion::new(0, 0, SIZE, SIZE));
+        previous.buf.iter_mut().for_each(|c| *c = SENTINEL);
+
+        let mut iw: ImageWriter = Default::default();
+        let summary = Renderer::render_changed(&options, &old, &new, &previous, &mut iw, 2)
+            .unwrap();
+        assert_eq!(summary.tiles, 2);
+        let image = iw.image.take().unwrap();
+        for tile in Renderer::tiles(&options) {
+            let (column, row) = (tile.l / CHUNK_SIZE, tile.b / CHUNK_SIZE);
+            // The moving sphere was in the first and is now in the second column
+            let rendered = row == 3 && column < 2;
+            let copied = image.crop(&tile).buffer().iter().all(|&c| c == SENTINEL);
+            assert_eq!(rendered, !copied, "tile at column {}, row {}", column, row);
+        }
+
+        let moved_eye = Scene {
+            eye: Vector {
+                x: 0.0,
+                y: 0.0,
+                z: -5.0,
+            },
+            ..scene(-0.5)
+        };
+        let summary = Renderer::render_changed(&options, &old, &moved_eye, &previous, &mut iw, 2)
+            .unwrap();
+        assert_eq!(summary.tiles, 16);
+    }
+
+    #[test]
+    fn handedness() {
+        let mirrored = |v: Vector| {
+            Vector {
+                x: v.x,
+                y: v.y,
+                z: -v.z,
+            }
+        };
+        let left = RenderOptions {
+            width: W as u16,
+            height: W as u16,
+            ..Default::default()
+        };
+        let right = RenderOptions {
+            handedness: Handedness::Right,
+            ..left
+        };
+        let image = |o: &RenderOptions, s: &Scene| {
+            Renderer::render_patch(o, s, &ImageRegion::new(0, 0, W as u16, W as u16)).unwrap()
+        };
+
+        fn mirrored_group(g: &SphericalGroup,
+                          mirrored: &dyn Fn(Vector) -> Vector)
+                          -> SphericalGroup {
+            let sphere = |s: &Sphere| {
+                Sphere {
+                    center: mirrored(s.center),
+                    ..*s
+                }
+            };
+            SphericalGroup {
+                bound: sphere(&g.bound),
+                children: g.children
+                    .iter()
+                    .map(|c| match *c {
+                        Pair::Item(ref s) => Pair::Item(sphere(s)),
+                        Pair::Group(ref g) => Pair::Group(mirrored_group(g, mirrored)),
+                    })
+                    .collect(),
+            }
+        }
+
+        // Right-handed renders of the mirrored scene look the same
+        let s = Scene::default();
+        let m = Scene {
+            eye: mirrored(s.eye),
+            directional_light: mirrored(s.directional_light),
+            group: mirrored_group(&s.group, &mirrored),
+            ..Default::default()
+        };
+        let (expected, actual) = (image(&left, &s), image(&right, &m));
+        assert!(expected.buffer().iter().zip(actual.buffer()).all(|(&e, &a)| e.abs_diff(a) <= 1));
+
+        // Only one of them sees the sphere in front of it
+        let mut s = Scene::unit_test_scene();
+        s.eye = Vector::default();
+        let center = ImageRegion::new(W as u16 / 2, W as u16 / 2, 1, 1);
+        for &(z, visible_left) in &[(3.0, true), (-3.0, false)] {
+            s.group.bound.center.z = z;
+            s.group.children = vec![Pair::Item(Sphere {
+                                        center: s.group.bound.center,
+                                        ..Default::default()
+                                    })];
+            let coverage = |o| Renderer::render_patch(o, &s, &center).unwrap().buffer()[3];
+            assert_eq!(coverage(&left) == 255, visible_left);
+            assert_eq!(coverage(&right) == 255, !visible_left);
+        }
+    }
+
+    #[test]
+    fn eye_inside_sphere() {
+        // We see the inside of the sphere all around us, which the light can't reach
+        let s = Scene::unit_test_scene();
+        let mut rng = Rng::new(3);
+        for _ in 0..100 {
+            let mut v = || rng.next_float() * 2.0 - 1.0;
+            let ray = Ray {
+                pos: Vector::default(),
+                dir: Vector {
+                        x: v(),
+                        y: v(),
+                        z: v(),
+                    }
+                    .normalized(),
+                differentials: None,
+                ..Default::default()
+            };
+            let mut c: Color = Default::default();
+            let sample = Renderer::raytrace(&Default::default(), &s, &ray, &mut c, 0);
+            assert_eq!((sample.coverage, sample.lit), (1.0, 0.0), "{:?}", ray.dir);
+            assert!(!sample.self_intersection);
+        }
+    }
+
+    #[test]
+    fn near() {
+        let mut s = Scene::unit_test_scene();
+        s.eye = Vector::default();
+        let options = RenderOptions {
+            width: W as u16,
+            height: W as u16,
+            ..Default::default()
+        };
+        let center = ImageRegion::new(W as u16 / 2, W as u16 / 2, 1, 1);
+
+        // From inside, we see the far side of the sphere
+        let b = Renderer::render_patch(&options, &s, &center).unwrap();
+        assert_eq!(b.buffer()[3], 255);
+
+        // Unless it's closer than the near plane
+        let clipped = RenderOptions {
+            near: 1.5,
+            ..options
+        };
+        let b = Renderer::render_patch(&clipped, &s, &center).unwrap();
+        let mut background = RGBABuffer::new(&center);
+        background.set_pixel_from_color(center.l,
+                                        center.b,
+                                        &Color::from_linear_rgb(BACKGROUND),
+                                        0.0,
+                                        &options.color_opts());
+        assert_eq!(b.buffer(), background.buffer());
+    }
 
-        writer.begin(o.width, o.height);
+    #[test]
+    fn cameras() {
+        let s = Scene::default();
+        let render = |camera: Option<Camera>| {
+            let options = RenderOptions {
+                width: W as u16,
+                height: W as u16,
+                camera,
+                ..Default::default()
+            };
+            let mut iw: ImageWriter = Default::default();
+            Renderer::render_scoped(&options, &s, &mut iw, 2).map(|_| iw.image.unwrap())
+        };
+        let front = render(Some(Camera::default())).unwrap();
+        let above = render(Some(Camera {
+                eye: Vector {
+                    y: 4.0,
+                    ..Vector::ZERO
+                },
+                up: Vector {
+                    z: 1.0,
+                    ..Vector::ZERO
+                },
+                ..Default::default()
+            }))
+            .unwrap();
+        assert!(front.buffer() != above.buffer());
+        // The default camera is the one of the default scene
+        assert!(render(None).unwrap().buffer() == front.buffer());
 
-        // Push all tasks
-        let (tx, rx) = sync_channel::<RGBABuffer>(4);
-        let mut count = 0usize;
-        let mut y = 0u16;
-        while y < o.height {
-            let mut x = 0u16;
-            while x < o.width {
-                let tx = tx.clone();
-                let opts = *o;
-                let tscene = scene.clone();
-
-                count += 1;
-
-                pool.execute(move || {
-                    let mut b = RGBABuffer::new(&ImageRegion {
-                        l: x,
-                        r: x + CHUNK_SIZE,
-                        b: y,
-                        t: y + CHUNK_SIZE,
-                    });
+        let nowhere = Camera {
+            look_at: s.eye,
+            ..Default::default()
+        };
+        assert!(matches!(render(Some(nowhere)),
+                         Err(RenderError::InvalidCamera(c)) if c == nowhere));
+
+        let options = RenderOptions {
+            camera: Some(nowhere),
+            ..Default::default()
+        };
+        let mut b = RGBABuffer::new(&ImageRegion::new(0, 0, 2, 2));
+        assert!(matches!(Renderer::render_region(&options, &s, &mut b),
+                         Err(RenderError::InvalidCamera(c)) if c == nowhere));
+        assert!(matches!(Renderer::render_depth_region(&options, &s, &mut b),
+                         Err(RenderError::InvalidCamera(c)) if c == nowhere));
+    }
 
-                    Renderer::render_region(&opts, tscene.deref(), &mut b);
+    #[test]
+    fn perspective() {
+        let s = Scene::unit_test_scene();
+        let view = |fov| {
+            let options = RenderOptions {
+                width: 2 * W as u16,
+                height: W as u16,
+                camera: Some(Camera {
+                    eye: Vector::new(1.0, 2.0, -3.0),
+                    look_at: Vector::new(0.5, 0.0, 1.0),
+                    fov,
+                    ..Default::default()
+                }),
+                ..Default::default()
+            };
+            let view = View::expect(&options, &s);
+            (options, view)
+        };
+        let (o, v) = view(Camera::default().fov);
+        let (width, height) = (o.width as RFloat, o.height as RFloat);
+        let center = Renderer::primary_ray(&o, &v, width / 2.0, height / 2.0);
+        let to_look_at = (v.camera.look_at - v.camera.eye).normalized();
+        assert!(center.dir.approx_eq(&to_look_at, 1e-6),
+                "{:?} != {:?}",
+                center.dir,
+                to_look_at);
 
-                    tx.send(b).ok().expect("Channel should be open !");
-                });
-                x += CHUNK_SIZE;
+        // The cosine of the angle between the rays through the left and right edge
+        let spread = |fov| {
+            let (o, v) = view(fov);
+            let left = Renderer::primary_ray(&o, &v, 0.0, height / 2.0);
+            let right = Renderer::primary_ray(&o, &v, width, height / 2.0);
+            left.dir.dot(&right.dir)
+        };
+        assert!(spread(0.5) > spread(1.0) && spread(1.0) > spread(2.0));
+    }
+
+    #[test]
+    fn orthographic() {
+        // Equally large spheres left and right, where the right one is farther away
+        let mut s = Scene::unit_test_scene();
+        s.group.children.clear();
+        s.group.bound.radius = 8.0;
+        for &(x, z) in &[(-1.5, 0.0), (1.5, 4.0)] {
+            s.group.children.push(Pair::Item(Sphere {
+                center: Vector::new(x, 0.0, z),
+                radius: 0.5,
+                material: None,
+            }));
+        }
+        // The amount of pixels covered in the left and the right half of the image
+        let sizes = |projection| {
+            let options = RenderOptions {
+                width: 2 * W as u16,
+                height: W as u16,
+                camera: Some(Camera {
+                    projection,
+                    ..Default::default()
+                }),
+                ..Default::default()
+            };
+            let view = View::expect(&options, &s);
+            let (mut left, mut right) = (0, 0);
+            for y in 0..W {
+                for x in 0..2 * W {
+                    let ray = Renderer::primary_ray(&options, &view, x as RFloat, y as RFloat);
+                    let mut c: Color = Default::default();
+                    if Renderer::raytrace(&options, &s, &ray, &mut c, 0).coverage > 0.0 {
+                        *(if x < W { &mut left } else { &mut right }) += 1;
+                    }
+                }
             }
-            y += CHUNK_SIZE;
+            (left, right)
+        };
+
+        let (near, far) = sizes(Projection::Perspective);
+        assert!(far > 0 && near > 2 * far, "{} vs. {}", near, far);
+        let (near, far) = sizes(Projection::Orthographic { scale: 6.0 });
+        assert!(far > 0 && near.max(far) - near.min(far) <= near / 20,
+                "{} vs. {}",
+                near,
+                far);
+    }
+
+    #[test]
+    fn depth_of_field() {
+        let s = Scene::unit_test_scene();
+        let o = RenderOptions {
+            width: W as u16,
+            height: W as u16,
+            samples_per_axis: 4,
+            ..Default::default()
+        };
+        let view = View::expect(&o, &s);
+        // The variance of the coverage of the samples of each pixel along the row through
+        // the center, summed up. Only pixels on the sphere's edge contribute to it.
+        let variance = |focus_distance| -> RFloat {
+            let camera = Camera {
+                aperture: 0.2,
+                focus_distance,
+                ..view.camera
+            };
+            (0..W as u16)
+                .map(|x| {
+                    let rays = Renderer::primary_rays_for_pixel(&o, &camera, x, W as u16 / 2)
+                        .unwrap();
+                    let coverage: Vec<RFloat> = rays.iter()
+                        .map(|r| {
+                            let mut c: Color = Default::default();
+                            Renderer::raytrace(&o, &s, r, &mut c, 0).coverage
+                        })
+                        .collect();
+                    let mean = coverage.iter().sum::<RFloat>() / coverage.len() as RFloat;
+                    coverage.iter().map(|c| (c - mean) * (c - mean)).sum::<RFloat>()
+                })
+                .sum()
+        };
+
+        // The edge of the unit sphere 4 units in front of the eye is 3.75 units ahead of it
+        let sharp = variance(3.75);
+        let blurred = variance(1.0);
+        assert!(blurred > 2.0 * sharp, "{} vs. {}", blurred, sharp);
+    }
+
+    #[test]
+    fn equirectangular() {
+        let s = Scene::unit_test_scene();
+        let camera = Camera {
+            projection: Projection::Equirectangular,
+            ..Default::default()
+        };
+        let mut options = RenderOptions {
+            width: 2 * W as u16,
+            height: W as u16,
+            camera: Some(camera),
+            ..Default::default()
+        };
+        let (width, height) = (options.width as RFloat, options.height as RFloat);
+        let near = |a: Vector, b: Vector| (a - b).len() < 1e-5;
+        let forward = Vector {
+            x: 0.0,
+            y: 0.0,
+            z: 1.0,
+        };
+        let back = forward.mulfed(-1.0);
+
+        for &(x, y, expected) in &[(width / 2.0, height / 2.0, forward),
+                                   (0.0, height / 2.0, back),
+                                   (width, height / 2.0, back),
+                                   (width / 4.0,
+                                    height / 2.0,
+                                    Vector {
+                                       x: -1.0,
+                                       y: 0.0,
+                                       z: 0.0,
+                                   }),
+                                   (width / 2.0,
+                                    0.0,
+                                    Vector {
+                                       x: 0.0,
+                                       y: 1.0,
+                                       z: 0.0,
+                                   })] {
+            let ray = Renderer::primary_ray(&options, &View::expect(&options, &s), x, y);
+            assert!(near(ray.dir, expected), "{:?} != {:?}", ray.dir, expected);
         }
 
-        // Read the results and pass them to the writer
-        for b in rx.iter() {
-            writer.write_rgba_buffer(&b);
-            count -= 1;
-            if count == 0 {
-                break;
-            }
+        // The eye still matters, the camera orientation too
+        let camera = Camera {
+            look_at: camera.eye + Vector { x: 1.0, ..Vector::ZERO },
+            ..camera
+        };
+        options.camera = Some(camera);
+        let view = View::expect(&options, &s);
+        let ray = Renderer::primary_ray(&options, &view, width / 2.0, height / 2.0);
+        assert_eq!(ray.pos, camera.eye);
+        assert!(near(ray.dir,
+                     Vector {
+                         x: 1.0,
+                         y: 0.0,
+                         z: 0.0,
+                     }));
+    }
+
+    #[test]
+    fn pixel_rngs() {
+        let first = |seed, x, y| pixel_rng(seed, x, y).next_u64();
+
+        assert_eq!(first(1, 0, 64), first(1, 0, 64));
+        assert!(first(1, 0, 64) != first(2, 0, 64));
+        assert!(first(1, 0, 64) != first(1, 64, 0));
+        assert!(first(1, 0, 0) != first(1, 1, 0));
+    }
+
+    #[test]
+    fn total_samples() {
+        for &(total, per_axis) in &[(16, 4), (9, 3), (10, 3), (13, 4), (1, 1), (0, 1),
+                                    (u32::MAX, MAX_SAMPLES_PER_AXIS)] {
+            assert_eq!(RenderOptions::with_total_samples(total).samples_per_axis,
+                       per_axis,
+                       "for {} samples",
+                       total);
         }
-        assert!(count == 0,
-                "We really should have processed all chunks here");
     }
-}
 
-pub enum FileOrAnyWriter {
-    AnyWriter(io::Stdout),
-    FileWriter(io::BufWriter<fs::File>),
-}
+    #[test]
+    fn max_samples() {
+        // The weights of all samples add up to 1
+        let ssf = MAX_SAMPLES_PER_AXIS as RFloat;
+        let recip = (ssf * ssf).recip();
+        let sum = (0..MAX_SAMPLES_PER_AXIS as usize * MAX_SAMPLES_PER_AXIS as usize)
+            .fold(0.0 as RFloat, |sum, _| sum + recip);
+        assert!((sum - 1.0).abs() < 1e-3, "{}", sum);
 
-// A bloated ppm writer, which could be generalized rather easily, if required
-pub struct PPMStdoutRGBABufferWriter<'a> {
-    out: &'a mut FileOrAnyWriter,
-    width: Option<u16>,
-    height: Option<u16>,
-    image: Option<RGBABuffer>,
-    rgb: bool,
-    last_written_at: Option<Instant>,
-    buffer_dirty: bool,
-}
+        // Averaging as many samples of the background yields the background
+        let s = Scene::unit_test_scene();
+        let options = RenderOptions {
+            width: W as u16,
+            height: W as u16,
+            samples_per_axis: MAX_SAMPLES_PER_AXIS,
+            ..Default::default()
+        };
+        let corner = ImageRegion::new(0, 0, 1, 1);
+        let b = Renderer::render_patch(&options, &s, &corner).unwrap();
+        let mut expected = RGBABuffer::new(&corner);
+        expected.set_pixel_from_color(0,
+                                      0,
+                                      &Color::from_linear_rgb(BACKGROUND),
+                                      0.0,
+                                      &options.color_opts());
+        assert_eq!(b.buffer(), expected.buffer());
 
-// It's required to mark it unsafe, as the compiler apparently can't verify
-// that our `out` reference is still valid (even though the bounds say just that)
-impl<'a> Drop for PPMStdoutRGBABufferWriter<'a> {
-    fn drop(&mut self) {
-        self.write_buffer_with_header();
+        for &samples_per_axis in &[0, MAX_SAMPLES_PER_AXIS + 1] {
+            let o = RenderOptions {
+                samples_per_axis,
+                ..options
+            };
+            assert!(matches!(Renderer::render_patch(&o, &s, &corner),
+                             Err(RenderError::InvalidSamplesPerAxis(n)) if n == samples_per_axis));
+            let mut dw: DummyWriter = Default::default();
+            assert!(Renderer::render_scoped(&o, &s, &mut dw, 1).is_err());
+            assert!(!dw.begin_called);
+        }
     }
-}
 
-impl<'a> PPMStdoutRGBABufferWriter<'a> {
-    pub fn new(write_rgb: bool, writer: &'a mut FileOrAnyWriter) -> PPMStdoutRGBABufferWriter<'a> {
-        PPMStdoutRGBABufferWriter {
-            out: writer,
-            image: None,
-            width: None,
-            height: None,
-            rgb: write_rgb,
-            last_written_at: None,
-            buffer_dirty: false,
+    #[test]
+    fn jitter() {
+        let s = Scene::default();
+        let options = RenderOptions {
+            width: W as u16,
+            height: H as u16,
+            samples_per_axis: 2,
+            jitter: true,
+            seed: 42,
+            ..Default::default()
+        };
+        let render = |o: &RenderOptions, num_threads| -> Vec<u8> {
+            let mut iw: ImageWriter = Default::default();
+            Renderer::render_scoped(o, &s, &mut iw, num_threads).unwrap();
+            iw.image.unwrap().buffer().clone()
+        };
+
+        let jittered = render(&options, 1);
+        assert!(jittered == render(&options, 4),
+                "the thread count must not affect the output");
+        assert!(jittered != render(&RenderOptions { jitter: false, ..options }, 1));
+        assert!(jittered != render(&RenderOptions { seed: 43, ..options }, 1));
+    }
+
+    #[test]
+    fn edges() {
+        let s = Scene::unit_test_scene();
+        let render = |edges| {
+            let options = RenderOptions {
+                width: W as u16,
+                height: W as u16,
+                samples_per_axis: 4,
+                edges,
+                ..Default::default()
+            };
+            let mut iw: ImageWriter = Default::default();
+            Renderer::render_scoped(&options, &s, &mut iw, 1).unwrap();
+            let image = iw.image.unwrap();
+            // The center row of pixels
+            let ofs = image.region().buffer_offset(0, W as u16 / 2) * RGBABuffer::components();
+            image.buffer()[ofs..ofs + W * RGBABuffer::components()].to_vec()
+        };
+        let blend = render(EdgeMode::Blend);
+        let opaque = render(EdgeMode::Opaque);
+        let green = render(EdgeMode::Color(Vector { y: 1.0, ..Vector::ZERO }));
+
+        let edge = blend.chunks(4).position(|p| p[3] > 0 && p[3] < 255).unwrap() * 4;
+        let (b, o, g) = (&blend[edge..edge + 4], &opaque[edge..edge + 4], &green[edge..edge + 4]);
+        assert_eq!(o[..3], b[..3], "the color is blended like before");
+        assert_eq!(o[3], 255);
+        assert_eq!(g[3], b[3]);
+        assert!(g[1] > b[1], "{:?} must be greener than {:?}", g, b);
+
+        // Pixels which are covered fully or not at all are the same in all modes
+        for &x in &[0, W / 2] {
+            let ofs = x * 4;
+            assert_eq!(blend[ofs..ofs + 4], opaque[ofs..ofs + 4]);
+            assert_eq!(blend[ofs..ofs + 4], green[ofs..ofs + 4]);
         }
     }
-}
 
-impl<'a> PPMStdoutRGBABufferWriter<'a> {
-    fn output_is_file(&self) -> bool {
-        match *self.out {
-            FileOrAnyWriter::FileWriter(_) => true,
-            _ => false,
+    #[test]
+    fn primary_rays_for_pixel() {
+        let s = Scene::unit_test_scene();
+        let camera = s.eye_camera(Handedness::Left);
+        for &jitter in &[false, true] {
+            let o = RenderOptions {
+                width: W as u16,
+                height: W as u16,
+                samples_per_axis: 3,
+                jitter,
+                ..Default::default()
+            };
+            let view = View::from_camera(&o, camera).unwrap();
+            let (x, y) = (20, 41);
+            let rays = Renderer::primary_rays_for_pixel(&o, &camera, x, y).unwrap();
+            assert_eq!(rays.len(), 9);
+
+            // The camera looks along +z, so the slopes of the rays grow with x and y
+            let slopes = |r: &Ray| (r.dir.x / r.dir.z, r.dir.y / r.dir.z);
+            let corner = |x: u16, y: u16| {
+                slopes(&Renderer::primary_ray(&o, &view, x as RFloat, y as RFloat))
+            };
+            let (lo, hi) = (corner(x, y), corner(x + 1, y + 1));
+            // Samples on the pixel's corner may round to either side of it
+            let within = |v: RFloat, a: RFloat, b: RFloat| {
+                v >= a.min(b) - 1e-6 && v <= a.max(b) + 1e-6
+            };
+            for r in &rays {
+                assert_eq!(r.pos, camera.eye);
+                assert!((r.dir.len() - 1.0).abs() < 1e-6);
+                let (sx, sy) = slopes(r);
+                assert!(within(sx, lo.0, hi.0) && within(sy, lo.1, hi.1),
+                        "{:?} is outside the pixel",
+                        r);
+            }
+            assert!(rays.iter().skip(1).all(|r| r.dir != rays[0].dir));
         }
+
+        let mut degenerate = camera;
+        degenerate.look_at = degenerate.eye;
+        let o: RenderOptions = Default::default();
+        assert!(matches!(Renderer::primary_rays_for_pixel(&o, &degenerate, 0, 0),
+                         Err(RenderError::InvalidCamera(_))));
     }
 
-    fn write_buffer_with_header(&mut self) {
-        if !self.buffer_dirty {
-            return;
+    #[test]
+    fn post_aa() {
+        let s = Scene::unit_test_scene();
+        let render = |post_aa| {
+            let options = RenderOptions {
+                width: W as u16,
+                height: W as u16,
+                post_aa,
+                ..Default::default()
+            };
+            let mut iw: ImageWriter = Default::default();
+            Renderer::render_scoped(&options, &s, &mut iw, 2).unwrap();
+            iw.image.unwrap().buffer().clone()
+        };
+        let (raw, smooth) = (render(PostAA::Off), render(PostAA::Morphological));
+        let alpha = |image: &[u8], x: usize, y: usize| image[(y * W + x) * 4 + 3];
+        // Pairs of neighbours of which one is fully covered, and the other not at all
+        let steps = |image: &[u8]| {
+            let mut count = 0;
+            for y in 0..W - 1 {
+                for x in 0..W - 1 {
+                    let a = alpha(image, x, y);
+                    for &b in &[alpha(image, x + 1, y), alpha(image, x, y + 1)] {
+                        count += (a == 0 && b == 255 || a == 255 && b == 0) as usize;
+                    }
+                }
+            }
+            count
+        };
+        assert!(steps(&smooth) < steps(&raw),
+                "{} steps remain of {}",
+                steps(&smooth),
+                steps(&raw));
+
+        // Pixels at least two away from the edge are unchanged, inside and outside the sphere
+        let uniform = |x: usize, y: usize, a: u8| {
+            (x - 2..x + 3).all(|x| (y - 2..y + 3).all(|y| alpha(&raw, x, y) == a))
+        };
+        let (mut interior, mut background) = (0, 0);
+        for y in 2..W - 2 {
+            for x in 2..W - 2 {
+                let ofs = (y * W + x) * 4;
+                let (inside, outside) = (uniform(x, y, 255), uniform(x, y, 0));
+                if inside || outside {
+                    assert_eq!(raw[ofs..ofs + 4], smooth[ofs..ofs + 4], "at {},{}", x, y);
+                }
+                interior += inside as usize;
+                background += outside as usize;
+            }
         }
+        assert!(interior > 0 && background > 0);
+    }
+
+    #[test]
+    fn denoise() {
+        let mut s = Scene::unit_test_scene();
+        // A noisy background, which differs for every ray
+        s.background = Box::new(|r| {
+            let bits = (r.dir.x.to_bits() as u64) << 32 | r.dir.y.to_bits() as u64;
+            Vector::splat(0.3 + 0.2 * Rng::new(bits).next_float())
+        });
+        let render = |denoise| {
+            let options = RenderOptions {
+                width: W as u16,
+                height: W as u16,
+                denoise,
+                ..Default::default()
+            };
+            let mut iw: ImageWriter = Default::default();
+            Renderer::render_scoped(&options, &s, &mut iw, 2).unwrap();
+            iw.image.unwrap().buffer().clone()
+        };
+        let (noisy, denoised) = (render(false), render(true));
 
-        let out: &mut io::Write = match *self.out {
-            FileOrAnyWriter::FileWriter(ref mut w) => {
-                w.get_mut().set_len(0).unwrap();
-                w.get_mut().seek(io::SeekFrom::Start(0)).unwrap();
-                w
+        // The average difference of horizontally neighbouring background pixels
+        let noise = |image: &[u8]| {
+            let (mut sum, mut count) = (0, 0);
+            for y in 0..W {
+                for x in 0..W - 1 {
+                    let (a, b) = ((y * W + x) * 4, (y * W + x + 1) * 4);
+                    if image[a + 3] == 0 && image[b + 3] == 0 {
+                        sum += (image[a] as i32 - image[b] as i32).abs();
+                        count += 1;
+                    }
+                }
             }
-            FileOrAnyWriter::AnyWriter(ref mut w) => w,
+            sum as RFloat / count as RFloat
         };
+        assert!(noise(&denoised) < noise(&noisy) / 2.0,
+                "{} remains of a noise of {}",
+                noise(&denoised),
+                noise(&noisy));
 
-        let mut ptype: &str = "P5";
-        if self.rgb {
-            ptype = "P6"
+        // The silhouette stays as sharp as it was, and the sphere doesn't take on any of
+        // the background
+        let alpha = |image: &[u8]| image.chunks(4).map(|p| p[3]).collect::<Vec<_>>();
+        assert!(alpha(&denoised) == alpha(&noisy));
+        let center = (W / 2 * W + W / 2) * 4;
+        for (d, n) in denoised[center..center + 3].iter().zip(&noisy[center..center + 3]) {
+            assert!((*d as i32 - *n as i32).abs() <= 1, "{} became {}", n, d);
         }
-        writeln!(out, "{}", ptype).unwrap();
-        writeln!(out,
-                 "{} {}",
-                 self.width.expect("begin() called"),
-                 self.height.expect("begin() called"))
+    }
+
+    #[test]
+    fn render_progressive() {
+        let s = Scene::unit_test_scene();
+        let options = RenderOptions {
+            width: W as u16,
+            height: W as u16,
+            jitter: true,
+            ..Default::default()
+        };
+        let refinement = Refinement {
+            budget: Duration::from_secs(60),
+            threshold: 0.0,
+            max_passes: 2,
+        };
+        let mut iw: ImageWriter = Default::default();
+        let summary = Renderer::render_progressive(&options, &s, &mut iw, 2, &refinement)
             .unwrap();
-        writeln!(out, "255").unwrap();
+        assert_eq!(summary.tiles, 2);
+        let image = iw.image.take().unwrap();
 
-        // We always write our entire buffer - it will just be zero initially
+        // The average of the single sample of the first pass and the four of the second
+        let view = View::expect(&options, &s);
+        let region = ImageRegion::new(0, 0, W as u16, W as u16);
+        let pass = |n| {
+            let o = Renderer::pass_options(&options, n);
+            Renderer::trace_pixels(&o, &s, &view, &region, false).0
+        };
+        let (first, second) = (pass(1), pass(2));
+        let mut expected = RGBABuffer::new(&region);
+        for y in 0..W as u16 {
+            for x in 0..W as u16 {
+                let ((g1, a1), (g2, a2)) = (first[region.buffer_offset(x, y)],
+                                            second[region.buffer_offset(x, y)]);
+                let g = (g1 + g2.mulfed(4.0)).mulfed(0.2);
+                let alpha = (a1 + a2 * 4.0) * 0.2;
+                expected.set_pixel_from_color(x, y, &g, alpha, &options.color_opts());
+            }
+        }
+        for (&c, &e) in image.buffer().iter().zip(expected.buffer().iter()) {
+            assert!((c as i32 - e as i32).abs() <= 1, "{} != {}", c, e);
+        }
 
+        // Converged right away, as nothing can change by more than 1
+        let converged = Refinement {
+            threshold: 1.0,
+            max_passes: 8,
+            ..refinement
+        };
+        let summary = Renderer::render_progressive(&options, &s, &mut iw, 1, &converged).unwrap();
+        assert_eq!(summary.tiles, 2);
 
-        let buf = self.image.as_ref().unwrap().buffer();
-        // Can't write entire buffer :( thanks to alpha channel.
-        let mut po = 0;
-        let step = RGBABuffer::components();
-        let po_max = buf.len();
-        while po < po_max {
-            let b = &buf[po..po + 3];
+        // Without any pass, nothing would ever be written
+        let mut iw: ImageWriter = Default::default();
+        let none = Refinement {
+            max_passes: 0,
+            ..refinement
+        };
+        assert_eq!(Renderer::render_progressive(&options, &s, &mut iw, 1, &none),
+                   Err(RenderError::InvalidMaxPasses));
+        assert!(iw.image.is_none());
+    }
 
-            if self.rgb {
-                out.write_all(b).unwrap();
-            } else {
-                let avg = ((b[0] as f32 + b[1] as f32 + b[2] as f32) / 3.0f32) as u8;
-                out.write(&[avg]).unwrap();
+    #[test]
+    fn color_opts() {
+        let s = Scene::unit_test_scene();
+        let options = RenderOptions {
+            width: W as u16,
+            height: W as u16,
+            samples_per_axis: 2,
+            color: ColorOpts {
+                srgb: true,
+                gamma: 2.2,
+                exposure: 0.5,
+                dither: true,
+            },
+            ..Default::default()
+        };
+        let region = ImageRegion::new(0, 0, W as u16, W as u16);
+        let view = View::expect(&options, &s);
+        for &target in &[RenderTarget::Beauty, RenderTarget::Coverage] {
+            let o = RenderOptions { target, ..options };
+            let b = Renderer::render_patch(&o, &s, &region).unwrap();
+            let (pixels, ..) = Renderer::trace_pixels(&o, &s, &view, &region, false);
+            for (p, &(g, alpha)) in b.buffer().chunks(4).zip(pixels.iter()) {
+                let (c, alpha) = match target {
+                    RenderTarget::Coverage => (Color::grey(alpha), 1.0),
+                    _ => (g, alpha),
+                };
+                let expected = color_to_rgba(&c.to_linear_rgb(), alpha, &o.color_opts());
+                assert_eq!(p, expected);
             }
-            po += step;
         }
+        assert_eq!(options.color_opts(), options.color);
+    }
 
-        out.flush().ok();
-        self.buffer_dirty = false;
+    #[test]
+    fn coverage_target() {
+        let s = Scene::unit_test_scene();
+        let options = RenderOptions {
+            width: W as u16,
+            height: W as u16,
+            samples_per_axis: 4,
+            target: RenderTarget::Coverage,
+            ..Default::default()
+        };
+        let mut iw: ImageWriter = Default::default();
+        Renderer::render_scoped(&options, &s, &mut iw, 1).unwrap();
+        let image = iw.image.unwrap();
+        let pixel = |x, y| {
+            let ofs = image.region().buffer_offset(x, y) * RGBABuffer::components();
+            let p = &image.buffer()[ofs..ofs + RGBABuffer::components()];
+            assert!(p[0] == p[1] && p[1] == p[2] && p[3] == 255, "{:?}", p);
+            p[0]
+        };
+        assert_eq!(pixel(W as u16 / 2, W as u16 / 2), 255);
+        assert_eq!(pixel(0, 0), 0);
+        let gray = (0..W as u16).map(|x| pixel(x, W as u16 / 2)).filter(|&v| v > 0 && v < 255);
+        assert!(gray.count() > 0, "the silhouette must be anti-aliased");
     }
-}
 
-impl<'a> RGBABufferWriter for PPMStdoutRGBABufferWriter<'a> {
-    fn begin(&mut self, x: u16, y: u16) {
-        self.width = Some(x);
-        self.height = Some(y);
-        self.image = Some(RGBABuffer::new(&ImageRegion {
-            l: 0,
-            r: x,
-            b: 0,
-            t: y,
-        }));
+    #[test]
+    fn unlit() {
+        let ground = Vector {
+            x: 0.5,
+            y: 0.25,
+            z: 0.0,
+        };
+        let s = Scene::default().with_ground(-2.0, ground);
+        let beauty = RenderOptions {
+            width: W as u16,
+            height: W as u16,
+            ..Default::default()
+        };
+        let unlit = RenderOptions { target: RenderTarget::Unlit, ..beauty };
+        let view = View::expect(&Default::default(), &s);
+
+        let mut shadowed = 0;
+        for y in 0..W {
+            for x in 0..W {
+                let ray = Renderer::primary_ray(&beauty, &view, x as RFloat, y as RFloat);
+                let mut c: Color = Default::default();
+                let sample = Renderer::raytrace(&beauty, &s, &ray, &mut c, 0);
+
+                let mut c: Color = Default::default();
+                Renderer::raytrace(&unlit, &s, &ray, &mut c, 0);
+                let c = c.to_linear_rgb();
+                if sample.coverage == 0.0 {
+                    assert_eq!(c, BACKGROUND);
+                } else {
+                    assert!(c == OBJECT || c == ground, "{:?} at {}x{}", c, x, y);
+                    if sample.lit == 0.0 {
+                        shadowed += 1;
+                    }
+                }
+            }
+        }
+        assert!(shadowed > 0);
     }
 
-    fn write_rgba_buffer(&mut self, buffer: &RGBABuffer) {
-        self.image.as_mut().unwrap().set_pixels_from_buffer(buffer);
-        self.buffer_dirty = true;
+    #[test]
+    fn shadows() {
+        let s: Scene = Default::default();
+        let shadowed = RenderOptions {
+            width: W as u16,
+            height: W as u16,
+            ..Default::default()
+        };
+        let lit = RenderOptions { shadows: false, ..shadowed };
+        let view = View::expect(&Default::default(), &s);
 
-        // Flush full image right away
-        if self.output_is_file() &&
-           (self.last_written_at.is_none() ||
-            self.last_written_at.unwrap() + Duration::from_secs(1) <= Instant::now()) {
-            self.last_written_at = Some(Instant::now());
-            self.write_buffer_with_header();
+        let mut became_lit = 0;
+        for y in 0..W {
+            for x in 0..W {
+                let ray = Renderer::primary_ray(&shadowed, &view, x as RFloat, y as RFloat);
+                let mut c: Color = Default::default();
+                let before = Renderer::raytrace(&shadowed, &s, &ray, &mut c, 0).lit;
+                let after = Renderer::raytrace(&lit, &s, &ray, &mut c, 0).lit;
+                assert!(before <= after, "nothing may become darker without shadows");
+                if before < after {
+                    became_lit += 1;
+                }
+            }
         }
+        assert!(became_lit > 0, "the pyramid must shadow some of its own spheres");
     }
-}
 
+    #[test]
+    fn coverage() {
+        // Every pixel seeing the pyramid is covered, no matter if it is lit or not
+        let s: Scene = Default::default();
+        let options = RenderOptions {
+            width: W as u16,
+            height: W as u16,
+            ..Default::default()
+        };
+        let mut b = RGBABuffer::new(&ImageRegion {
+            l: 0,
+            r: W as u16,
+            b: 0,
+            t: W as u16,
+        });
+        Renderer::render_region(&options, &s, &mut b).unwrap();
+        let view = View::expect(&Default::default(), &s);
 
-#[cfg(test)]
-mod tests {
-    extern crate test;
-    extern crate threadpool;
+        let mut unlit = 0;
+        for y in 0..W {
+            for x in 0..W {
+                let ray = Renderer::primary_ray(&options, &view, x as RFloat, y as RFloat);
+                let mut c: Color = Default::default();
+                let sample = Renderer::raytrace(&options, &s, &ray, &mut c, 0);
+                let alpha = b.buffer()[b.region().buffer_offset(x as u16, y as u16) *
+                                       RGBABuffer::components() + 3];
+                assert_eq!(alpha > 0, sample.coverage > 0.0, "at {}x{}", x, y);
+                if sample.coverage > 0.0 && sample.lit == 0.0 {
+                    unlit += 1;
+                }
+            }
+        }
+        assert!(unlit > 0, "the pyramid must have unlit pixels");
+    }
 
-    use super::*;
-    use std::sync::Arc;
-    use std::default::Default;
+    #[test]
+    fn footprint() {
+        // A sphere this large is a flat floor below the eye, receding towards the horizon
+        let mut s = Scene::unit_test_scene();
+        s.group.children.clear();
+        s.group.bound.radius = 1e5;
+        s.group.children.push(Pair::Item(Sphere {
+            center: Vector {
+                x: 0.0,
+                y: -1e4 - 1.0,
+                z: 0.0,
+            },
+            radius: 1e4,
+            material: None,
+        }));
+        let options = RenderOptions {
+            width: 64,
+            height: 64,
+            ..Default::default()
+        };
 
-    use self::threadpool::ThreadPool;
+        let view = View::expect(&Default::default(), &s);
+        let mut last = 0.0;
+        // From the bottom row upwards, each row hits the floor farther away
+        for y in (40..64).rev() {
+            let ray = Renderer::primary_ray(&options, &view, 32.0, y as RFloat);
+            let mut h = Hit::missed();
+            s.intersect(&mut h, &ray);
+            assert!(!h.has_missed());
+            let footprint = ray.footprint(&h);
+            assert!(footprint > last, "{} <= {} at row {}", footprint, last, y);
+            last = footprint;
+        }
 
-    #[derive(Default)]
-    struct DummyWriter {
-        begin_called: bool,
-        write_count: usize,
+        let mut ray = Renderer::primary_ray(&options, &view, 32.0, 63.0);
+        ray.differentials = None;
+        let mut h = Hit::missed();
+        s.intersect(&mut h, &ray);
+        assert_eq!(ray.footprint(&h), 0.0);
     }
 
-    impl RGBABufferWriter for DummyWriter {
-        fn begin(&mut self, _: u16, _: u16) {
-            self.begin_called = true;
-        }
-        fn write_rgba_buffer(&mut self, _: &RGBABuffer) {
-            self.write_count += 1;
+    #[test]
+    fn vignette() {
+        // Without any geometry, all pixels have the background color
+        let mut s = Scene::unit_test_scene();
+        s.group.children.clear();
+        s.background = Box::new(|_| Vector::splat(1.0));
+        // The second camera sees more, and the falloff of a portrait image depends on its
+        // shorter side
+        for &(width, height, fov) in &[(64, 64, Camera::default().fov),
+                                       (32, 64, f32::consts::FRAC_PI_2)] {
+            let r = ImageRegion::new(0, 0, width, height);
+            let mut options = RenderOptions {
+                width,
+                height,
+                camera: Some(Camera {
+                    fov,
+                    ..Default::default()
+                }),
+                color: ColorOpts::default(),
+                ..Default::default()
+            };
+
+            let mut flat = RGBABuffer::new(&r);
+            Renderer::render_region(&options, &s, &mut flat).unwrap();
+            let pixel = |b: &RGBABuffer, x, y| {
+                let ofs = r.buffer_offset(x, y) * RGBABuffer::components();
+                b.buffer()[ofs..ofs + 3].to_vec()
+            };
+            assert_eq!(pixel(&flat, 0, 0), pixel(&flat, width / 2, height / 2));
+
+            options.vignette = 1.0;
+            let mut vignetted = RGBABuffer::new(&r);
+            Renderer::render_region(&options, &s, &mut vignetted).unwrap();
+            // Pixels fall off with the angle of their rays, which is smallest at the center
+            let view = View::expect(&options, &s);
+            let dir = |x: RFloat, y: RFloat| Renderer::primary_ray(&options, &view, x, y).dir;
+            let center = dir(width as RFloat / 2.0, height as RFloat / 2.0);
+            let corners = [(0, 0), (width - 1, 0), (0, height - 1), (width - 1, height - 1)];
+            for &(x, y) in corners.iter().chain(&[(width / 2, height / 2)]) {
+                let cos = dir(x as RFloat + 0.5, y as RFloat + 0.5).dot(&center);
+                let expected = 255.0 * cos.powi(4);
+                let p = pixel(&vignetted, x, y);
+                assert!(p.iter().all(|&c| (c as RFloat - expected).abs() <= 1.0),
+                        "{:?} at {},{} should be {}",
+                        p,
+                        x,
+                        y,
+                        expected);
+            }
         }
     }
 
-    const W: usize = 64;
-    const H: usize = 128;
-
     #[test]
-    fn basic_rendering() {
-        let s: Arc<Scene> = Arc::new(Default::default());
-        let pool = ThreadPool::new(1);
-        let options = RenderOptions {
-            width: W as u16,
-            height: H as u16,
-            samples_per_pixel: 2,
-        };
+    fn patch() {
+        let s = Scene::default();
+        // Jittered samples are seeded per pixel, and don't depend on the tiles either
+        for &jitter in &[false, true] {
+            let options = RenderOptions {
+                width: W as u16,
+                height: W as u16,
+                samples_per_axis: 2,
+                jitter,
+                ..Default::default()
+            };
+            let mut iw: ImageWriter = Default::default();
+            Renderer::render_scoped(&options, &s, &mut iw, 1).unwrap();
+            let full = iw.image.unwrap();
 
-        let mut dw: DummyWriter = Default::default();
-        Renderer::render(&options, s.clone(), &mut dw, &pool);
-
-        assert!(dw.begin_called);
-        assert_eq!(dw.write_count, 2);
+            let region = ImageRegion::new(5, 9, 17, 23);
+            let patch = Renderer::render_patch(&options, &s, &region).unwrap();
+            assert_eq!(*patch.region(), region);
+            assert_eq!(patch.buffer().len(), 17 * 23 * RGBABuffer::components());
+            for y in 9..9 + 23 {
+                for x in 5..5 + 17 {
+                    let ofs = |r: &ImageRegion| r.buffer_offset(x, y) * RGBABuffer::components();
+                    assert_eq!(&patch.buffer()[ofs(&region)..ofs(&region) + 4],
+                               &full.buffer()[ofs(full.region())..ofs(full.region()) + 4],
+                               "at {}x{}",
+                               x,
+                               y);
+                }
+            }
+        }
     }
 
     #[test]
@@ -496,9 +5478,63 @@ mod tests {
         l.l = 1;
         assert!(l.contains(&r));
         assert!(!r.contains(&l));
+
+        let r = ImageRegion::from_xywh(2, 2, 32, 16).unwrap();
+        assert_eq!((r.width(), r.height(), r.area()), (32, 16, 512));
+        assert_eq!(ImageRegion::from_xywh(2, 2, 0, 16), None);
+        assert_eq!(ImageRegion::from_xywh(2, 2, 32, 0), None);
+        assert_eq!(ImageRegion::from_xywh(u16::MAX, 2, 1, 16), None);
+    }
+
+    #[test]
+    fn image_region_relations() {
+        let r = ImageRegion {
+            l: 0,
+            t: 64,
+            r: 64,
+            b: 0,
+        };
+
+        // adjacent, sharing the right edge
+        let mut adjacent = r;
+        adjacent.l = 64;
+        adjacent.r = 128;
+        assert!(!r.contains(&adjacent));
+        assert!(!r.overlaps(&adjacent) && !adjacent.overlaps(&r));
+
+        // overlapping by one column
+        let mut overlapping = adjacent;
+        overlapping.l = 63;
+        assert!(!r.contains(&overlapping));
+        assert!(r.overlaps(&overlapping) && overlapping.overlaps(&r));
+
+        // nested
+        let nested = ImageRegion {
+            l: 16,
+            t: 48,
+            r: 48,
+            b: 16,
+        };
+        assert!(r.contains(&nested) && !nested.contains(&r));
+        assert!(r.overlaps(&nested) && nested.overlaps(&r));
+
+        // touching the boundary from the inside
+        let mut inside = r;
+        inside.l = 63;
+        inside.b = 63;
+        assert!(r.contains(&inside));
+        assert!(r.overlaps(&inside));
+
+        // empty regions on the boundary have no pixels in common with us
+        let mut empty = r;
+        empty.l = 64;
+        assert!(empty.is_empty());
+        assert!(!r.contains(&empty));
+        assert!(!r.overlaps(&empty));
     }
 
     #[bench]
+    #[cfg(feature = "threadpool")]
     fn bench_rendering(b: &mut test::Bencher) {
         const SPP: usize = 1;
         let pool = ThreadPool::new(4);
@@ -506,12 +5542,70 @@ mod tests {
         let options = RenderOptions {
             width: H as u16,
             height: H as u16,
-            samples_per_pixel: SPP as u16,
+            samples_per_axis: SPP as u16,
+            ..Default::default()
+        };
+
+        let mut dw: DummyWriter = Default::default();
+        b.iter(|| {
+            Renderer::render(&options, s.clone(), &mut dw, &pool).unwrap();
+        });
+        b.bytes = (H * H * SPP * SPP) as u64;
+    }
+
+    // Shadow rays from all lit surface points seen by a small image of the default scene
+    fn shadow_rays(s: &Scene) -> Vec<Ray> {
+        let o = RenderOptions {
+            width: W as u16,
+            height: W as u16,
+            ..Default::default()
+        };
+        let view = View::expect(&o, s);
+        let mut rays = Vec::new();
+        for y in 0..W {
+            for x in 0..W {
+                let ray = Renderer::primary_ray(&o, &view, x as RFloat, y as RFloat);
+                let mut h = Hit::missed();
+                s.intersect(&mut h, &ray);
+                if !h.has_missed() && h.normal.dot(&s.directional_light) < 0.0 {
+                    rays.push(Ray {
+                        pos: h.point + h.normal.mulfed(h.scale * SHADOW_BIAS),
+                        dir: s.directional_light.mulfed(-1.0),
+                        differentials: None,
+                        ..Default::default()
+                    });
+                }
+            }
+        }
+        rays
+    }
+
+    #[bench]
+    fn bench_shadow_rays(b: &mut test::Bencher) {
+        let s = Scene::default();
+        let rays = shadow_rays(&s);
+        b.iter(|| {
+            for ray in &rays {
+                test::black_box(s.is_occluded(ray, f32::INFINITY));
+            }
+        });
+        b.bytes = rays.len() as u64;
+    }
+
+    #[bench]
+    fn bench_rendering_scoped(b: &mut test::Bencher) {
+        const SPP: usize = 1;
+        let s: Scene = Default::default();
+        let options = RenderOptions {
+            width: H as u16,
+            height: H as u16,
+            samples_per_axis: SPP as u16,
+            ..Default::default()
         };
 
         let mut dw: DummyWriter = Default::default();
         b.iter(|| {
-            Renderer::render(&options, s.clone(), &mut dw, &pool);
+            Renderer::render_scoped(&options, &s, &mut dw, 4).unwrap();
         });
         b.bytes = (H * H * SPP * SPP) as u64;
     }
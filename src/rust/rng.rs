@@ -0,0 +1,55 @@
+//! A small pseudo random number generator for stochastic sampling.
+
+use super::vec::RFloat;
+
+/// A xorshift64* generator - fast and good enough to jitter samples, but nothing more.
+#[derive(Clone, Debug)]
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Rng {
+        // xorshift gets stuck at 0, which the hash never maps any seed to
+        Rng { state: splitmix64(seed) | 1 }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        self.state.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    /// Returns a number uniformly distributed in [0, 1)
+    pub fn next_float(&mut self) -> RFloat {
+        // Only as many bits as fit into the mantissa, or we could round up to 1
+        (self.next_u64() >> 40) as RFloat / (1u64 << 24) as RFloat
+    }
+}
+
+/// Scrambles all bits of x, see http://prng.di.unimi.it/splitmix64.c
+pub fn splitmix64(x: u64) -> u64 {
+    let mut z = x.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn floats() {
+        let mut rng = Rng::new(0);
+        let mut sum = 0.0;
+        for _ in 0..1000 {
+            let f = rng.next_float();
+            assert!((0.0..1.0).contains(&f));
+            sum += f;
+        }
+        assert!((sum / 1000.0 - 0.5).abs() < 0.05);
+    }
+}
@@ -0,0 +1,453 @@
+//! Reads and writes scenes as text with one statement per line, which makes them easy to
+//! edit by hand. Vectors are written as "x,y,z", and comments run from # to the end of the
+//! line.
+//!
+//! ```text
+//! light -1,-3,2
+//! light-size 0
+//! eye 0,0,-4
+//! camera 1 0 0 0 0 1 0 0 0 0 1 0
+//! palette classic-red
+//! ground 0,-2,0 0,1,0 0.5,0.5,0.5
+//! lattice 4 0,0,0 0.5
+//! group 0,-1,0 3
+//!   sphere 0,-1,0 1
+//!   sphere 0,1,0 0.5 material 1,1,1 0.8 0,0,0 cutout stripes 8 0.5
+//! end
+//! ```
+//!
+//! * `light`, `eye` and exactly one top-level `group` are required, everything else
+//!   is optional.
+//! * `camera` is the first three rows of `Scene::camera`.
+//! * `palette` is `classic-red`, `grayscale` or `custom <object> <background>`, and also
+//!   sets the background, like `Scene::with_palette()`.
+//! * `ground` is a point on the plane, its normal and its color.
+//! * `lattice` is the period, followed by a sphere.
+//! * `group` is the center and radius of its bound, and is closed by `end`. It may contain
+//!   spheres and other groups.
+//! * `sphere` is its center and radius, optionally followed by `material`, with the
+//!   diffuse color, reflectivity and emission, which is optionally followed by `cutout`,
+//!   with either `stripes <count> <threshold>` or `vertical <threshold>`.
+
+use super::vec::{Vector, RFloat};
+use super::primitive::{Sphere, Material, Cutout, AlphaMask, Plane, Lattice};
+use super::group::{SphericalGroup, Pair};
+use super::transform::Transform;
+use super::render::{Scene, Palette, Ground};
+use std::io::{self, BufRead};
+use std::iter::Peekable;
+use std::str::SplitWhitespace;
+use std::fmt;
+use std::error::Error;
+
+#[derive(Debug)]
+pub enum SceneFileError {
+    Io(io::Error),
+    /// The given line can't be read, with a description of what's wrong
+    Malformed { line: usize, what: String },
+    /// The statement starting with the given keyword is required, but missing
+    Missing(&'static str),
+}
+
+impl fmt::Display for SceneFileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SceneFileError::Io(ref err) => write!(f, "Could not read scene: {}", err),
+            SceneFileError::Malformed { line, ref what } => {
+                write!(f, "Malformed scene in line {}: {}", line, what)
+            }
+            SceneFileError::Missing(keyword) => write!(f, "The scene lacks '{}'", keyword),
+        }
+    }
+}
+
+impl Error for SceneFileError {}
+
+impl From<io::Error> for SceneFileError {
+    fn from(err: io::Error) -> SceneFileError {
+        SceneFileError::Io(err)
+    }
+}
+
+/// Writes the scene in the format `read_scene()` reads, see the module documentation.
+/// Instances can't be written, and neither can the background, which is the one of the
+/// palette once the scene is read again.
+pub fn write_scene(out: &mut dyn io::Write, scene: &Scene) -> io::Result<()> {
+    if !scene.instances.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                  "instances can't be written to scene files"));
+    }
+    writeln!(out, "light {}", vector(&scene.directional_light))?;
+    writeln!(out, "light-size {}", scene.light_size)?;
+    writeln!(out, "eye {}", vector(&scene.eye))?;
+    write!(out, "camera")?;
+    for row in scene.camera.m.iter().take(3) {
+        for v in row {
+            write!(out, " {}", v)?;
+        }
+    }
+    writeln!(out)?;
+    match scene.palette {
+        Palette::ClassicRed => writeln!(out, "palette classic-red")?,
+        Palette::Grayscale => writeln!(out, "palette grayscale")?,
+        Palette::Custom { object, background } => {
+            writeln!(out, "palette custom {} {}", vector(&object), vector(&background))?
+        }
+    }
+    if let Some(ref ground) = scene.ground {
+        writeln!(out,
+                 "ground {} {} {}",
+                 vector(&ground.plane.point),
+                 vector(&ground.plane.normal),
+                 vector(&ground.color))?;
+    }
+    if let Some(ref lattice) = scene.lattice {
+        writeln!(out, "lattice {} {}", lattice.period, sphere(&lattice.sphere))?;
+    }
+    write_group(out, &scene.group, 0)
+}
+
+fn write_group(out: &mut dyn io::Write, g: &SphericalGroup, depth: usize) -> io::Result<()> {
+    let indent = "  ".repeat(depth);
+    writeln!(out,
+             "{}group {} {}",
+             indent,
+             vector(&g.bound.center),
+             g.bound.radius)?;
+    for child in &g.children {
+        match *child {
+            Pair::Item(ref s) => writeln!(out, "{}  sphere {}", indent, sphere(s))?,
+            Pair::Group(ref g) => write_group(out, g, depth + 1)?,
+        }
+    }
+    writeln!(out, "{}end", indent)
+}
+
+// Floats are written with as many digits as it takes to read them back exactly
+fn vector(v: &Vector) -> String {
+    format!("{},{},{}", v.x, v.y, v.z)
+}
+
+fn sphere(s: &Sphere) -> String {
+    let mut line = format!("{} {}", vector(&s.center), s.radius);
+    if let Some(ref m) = s.material {
+        line += &format!(" material {} {} {}",
+                         vector(&m.diffuse),
+                         m.reflectivity,
+                         vector(&m.emission));
+        match m.cutout {
+            Some(Cutout { mask: AlphaMask::Stripes(count), threshold }) => {
+                line += &format!(" cutout stripes {} {}", count, threshold)
+            }
+            Some(Cutout { mask: AlphaMask::Vertical, threshold }) => {
+                line += &format!(" cutout vertical {}", threshold)
+            }
+            None => {}
+        }
+    }
+    line
+}
+
+/// Reads a scene written by `write_scene()`, or by hand, see the module documentation.
+/// The scene isn't validated, which renderers do, see `Scene::validate()`.
+pub fn read_scene<R: BufRead>(r: R) -> Result<Scene, SceneFileError> {
+    let mut reader = SceneReader::default();
+    for (index, line) in r.lines().enumerate() {
+        let line = line?;
+        let statement = line.split('#').next().unwrap_or("");
+        reader.statement(statement).map_err(|what| {
+                SceneFileError::Malformed {
+                    line: index + 1,
+                    what,
+                }
+            })?;
+    }
+    reader.finish()
+}
+
+// The statements read so far
+#[derive(Default)]
+struct SceneReader {
+    light: Option<Vector>,
+    light_size: RFloat,
+    eye: Option<Vector>,
+    camera: Transform,
+    palette: Palette,
+    ground: Option<Ground>,
+    lattice: Option<Lattice>,
+    // The innermost group is last
+    open_groups: Vec<SphericalGroup>,
+    group: Option<SphericalGroup>,
+}
+
+impl SceneReader {
+    fn statement(&mut self, statement: &str) -> Result<(), String> {
+        let mut tokens = Tokens(statement.split_whitespace().peekable());
+        let keyword = match tokens.0.next() {
+            Some(keyword) => keyword,
+            None => return Ok(()),
+        };
+        match keyword {
+            "light" => self.light = Some(tokens.vector("light direction")?),
+            "light-size" => self.light_size = tokens.float("light size")?,
+            "eye" => self.eye = Some(tokens.vector("eye position")?),
+            "camera" => {
+                for row in self.camera.m.iter_mut().take(3) {
+                    for v in row.iter_mut() {
+                        *v = tokens.float("camera transform")?;
+                    }
+                }
+            }
+            "palette" => {
+                self.palette = match tokens.next("palette")? {
+                    "classic-red" => Palette::ClassicRed,
+                    "grayscale" => Palette::Grayscale,
+                    "custom" => {
+                        Palette::Custom {
+                            object: tokens.vector("object color")?,
+                            background: tokens.vector("background color")?,
+                        }
+                    }
+                    palette => return Err(format!("unknown palette '{}'", palette)),
+                }
+            }
+            "ground" => {
+                self.ground = Some(Ground {
+                    plane: Plane {
+                        point: tokens.vector("ground point")?,
+                        normal: tokens.vector("ground normal")?,
+                    },
+                    color: tokens.vector("ground color")?,
+                })
+            }
+            "lattice" => {
+                let period = tokens.float("lattice period")?;
+                self.lattice = Some(Lattice {
+                    sphere: tokens.sphere()?,
+                    period,
+                });
+            }
+            "group" => {
+                if self.group.is_some() {
+                    return Err("there can only be one top-level group".to_string());
+                }
+                let mut g = SphericalGroup::default();
+                g.bound.center = tokens.vector("bound center")?;
+                g.bound.radius = tokens.float("bound radius")?;
+                self.open_groups.push(g);
+            }
+            "end" => {
+                let g = self.open_groups.pop().ok_or("'end' without a group")?;
+                match self.open_groups.last_mut() {
+                    Some(parent) => parent.children.push(Pair::Group(g)),
+                    None => self.group = Some(g),
+                }
+            }
+            "sphere" => {
+                let s = tokens.sphere()?;
+                self.open_groups
+                    .last_mut()
+                    .ok_or("spheres must be within a group")?
+                    .children
+                    .push(Pair::Item(s));
+            }
+            keyword => return Err(format!("unknown statement '{}'", keyword)),
+        }
+        match tokens.0.next() {
+            Some(token) => Err(format!("unexpected '{}' after '{}'", token, keyword)),
+            None => Ok(()),
+        }
+    }
+
+    fn finish(self) -> Result<Scene, SceneFileError> {
+        if !self.open_groups.is_empty() {
+            return Err(SceneFileError::Missing("end"));
+        }
+        let background = self.palette.background();
+        Ok(Scene {
+            group: self.group.ok_or(SceneFileError::Missing("group"))?,
+            instances: Vec::new(),
+            lattice: self.lattice,
+            directional_light: self.light.ok_or(SceneFileError::Missing("light"))?,
+            light_size: self.light_size,
+            eye: self.eye.ok_or(SceneFileError::Missing("eye"))?,
+            camera: self.camera,
+            background: Box::new(move |_| background),
+            ground: self.ground,
+            palette: self.palette,
+        })
+    }
+}
+
+// The whitespace-separated tokens of a statement, after its keyword
+struct Tokens<'a>(Peekable<SplitWhitespace<'a>>);
+
+impl<'a> Tokens<'a> {
+    fn next(&mut self, what: &str) -> Result<&'a str, String> {
+        self.0.next().ok_or_else(|| format!("missing {}", what))
+    }
+
+    fn float(&mut self, what: &str) -> Result<RFloat, String> {
+        let token = self.next(what)?;
+        token.parse().map_err(|_| format!("{} '{}' is not a number", what, token))
+    }
+
+    fn vector(&mut self, what: &str) -> Result<Vector, String> {
+        let token = self.next(what)?;
+        token.parse().map_err(|err| format!("{} '{}': {}", what, token, err))
+    }
+
+    fn sphere(&mut self) -> Result<Sphere, String> {
+        let center = self.vector("sphere center")?;
+        let radius = self.float("sphere radius")?;
+        let material = if self.0.peek() == Some(&"material") {
+            self.0.next();
+            Some(self.material()?)
+        } else {
+            None
+        };
+        Ok(Sphere {
+            center,
+            radius,
+            material,
+        })
+    }
+
+    fn material(&mut self) -> Result<Material, String> {
+        let diffuse = self.vector("diffuse color")?;
+        let reflectivity = self.float("reflectivity")?;
+        let emission = self.vector("emission")?;
+        let cutout = if self.0.peek() == Some(&"cutout") {
+            self.0.next();
+            let mask = match self.next("cutout mask")? {
+                "stripes" => {
+                    let count = self.next("stripe count")?;
+                    AlphaMask::Stripes(count.parse()
+                        .map_err(|_| format!("stripe count '{}' is out of range", count))?)
+                }
+                "vertical" => AlphaMask::Vertical,
+                mask => return Err(format!("unknown cutout mask '{}'", mask)),
+            };
+            Some(Cutout {
+                mask,
+                threshold: self.float("cutout threshold")?,
+            })
+        } else {
+            None
+        };
+        Ok(Material {
+            diffuse,
+            reflectivity,
+            emission,
+            cutout,
+        })
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::render::{Renderer, RenderOptions, ImageRegion};
+    use super::super::group::Instance;
+    use std::sync::Arc;
+
+    fn written(scene: &Scene) -> String {
+        let mut out = Vec::new();
+        write_scene(&mut out, scene).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn default_scene() {
+        let scene = Scene::default();
+        let text = written(&scene);
+        let read = read_scene(text.as_bytes()).unwrap();
+        assert_eq!(read.group.count(), scene.group.count());
+        assert_eq!(written(&read), text);
+
+        let options = RenderOptions {
+            width: 64,
+            height: 64,
+            ..Default::default()
+        };
+        let region = ImageRegion::new(0, 0, options.width, options.height);
+        let render = |s: &Scene| Renderer::render_patch(&options, s, &region).unwrap();
+        assert!(render(&read).buffer() == render(&scene).buffer());
+    }
+
+    #[test]
+    fn everything() {
+        let text = "light -1,-3,2 # comments are ignored\n\
+                    light-size 0.1\n\
+                    \n\
+                    eye 0,1,-4\n\
+                    camera 1 0 0 0.5 0 1 0 0 0 0 1 0\n\
+                    palette custom 1,0,0 0,0,1\n\
+                    ground 0,-2,0 0,1,0 0.5,0.5,0.5\n\
+                    lattice 4 0,0,0 0.5 material 1,1,1 0 0,0,0 cutout vertical 0.5\n\
+                    group 0,0,0 3\n\
+                    \x20 sphere 0,-1,0 1\n\
+                    \x20 group 0,1,0 1\n\
+                    \x20   sphere 0,1,0 0.5 material 1,1,1 0.8 0,0,0 cutout stripes 8 0.5\n\
+                    \x20 end\n\
+                    end\n";
+        let scene = read_scene(text.as_bytes()).unwrap();
+        assert_eq!(scene.light_size, 0.1);
+        assert_eq!(scene.camera.m[0][3], 0.5);
+        assert_eq!(scene.group.count(), (2, 2));
+        assert_eq!(scene.lattice.unwrap().period, 4.0);
+        assert_eq!((scene.background)(&Default::default()), Vector::new(0.0, 0.0, 1.0));
+        let inner = match scene.group.children[1] {
+            Pair::Group(ref g) => g,
+            Pair::Item(_) => panic!("expected a group"),
+        };
+        assert_eq!(inner.bound.radius, 1.0);
+
+        let rewritten = written(&scene);
+        assert_eq!(written(&read_scene(rewritten.as_bytes()).unwrap()), rewritten);
+    }
+
+    #[test]
+    fn malformed() {
+        let scene = "light -1,-3,2\neye 0,0,-4\ngroup 0,0,0 1\nsphere 0,0,0 1\nend\n";
+        assert!(read_scene(scene.as_bytes()).is_ok());
+
+        for &(text, line) in &[("light -1,-3\n", 1),
+                               ("eye 0,0,-4\nlight -1,-3,2 0\n", 2),
+                               ("sphere 0,0,0 1\n", 1),
+                               ("end\n", 1),
+                               ("group 0,0,0 1\nend\ngroup 0,0,0 1\n", 3),
+                               ("palette blue\n", 1),
+                               ("lattice 4 0,0,0 x\n", 1),
+                               ("group 0,0,0 1\nsphere 0,0,0 1 material 1,1,1 0 0,0,0 \
+                                 cutout stripes -1 0.5\n",
+                                2),
+                               ("fog 0.5\n", 1)] {
+            match read_scene(text.as_bytes()) {
+                Err(SceneFileError::Malformed { line: l, .. }) if l == line => {}
+                res => panic!("{:?} must be malformed in line {}, got {:?}", text, line, res.err()),
+            }
+        }
+
+        for &(text, keyword) in &[("light -1,-3,2\neye 0,0,-4\n", "group"),
+                                  ("eye 0,0,-4\ngroup 0,0,0 1\nend\n", "light"),
+                                  ("light -1,-3,2\ngroup 0,0,0 1\nend\n", "eye"),
+                                  ("light -1,-3,2\neye 0,0,-4\ngroup 0,0,0 1\n", "end")] {
+            match read_scene(text.as_bytes()) {
+                Err(SceneFileError::Missing(k)) if k == keyword => {}
+                res => panic!("{:?} must lack '{}', got {:?}", text, keyword, res.err()),
+            }
+        }
+    }
+
+    #[test]
+    fn instances() {
+        let mut scene = read_scene("light 0,0,1\neye 0,0,-4\ngroup 0,0,0 1\nend\n".as_bytes())
+            .unwrap();
+        let group = Arc::new(SphericalGroup::default());
+        scene.instances.push(Instance::new(Transform::identity(), group).unwrap());
+        let err = write_scene(&mut Vec::new(), &scene).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+}
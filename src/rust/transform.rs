@@ -0,0 +1,299 @@
+//! A module implementing an affine Transform, stored as a 4x4 matrix whose last row
+//! is always (0, 0, 0, 1).
+
+use super::vec::{Vector, RFloat};
+use std::default::Default;
+use std::ops::Mul;
+
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub struct Transform {
+    /// Row-major, column vectors are multiplied from the right
+    pub m: [[RFloat; 4]; 4],
+}
+
+impl Default for Transform {
+    fn default() -> Transform {
+        Transform::identity()
+    }
+}
+
+/// `a * b` yields a transform applying `b` first, then `a`
+impl Mul for Transform {
+    type Output = Transform;
+
+    fn mul(self, rhs: Transform) -> Transform {
+        let mut r = Transform::identity();
+        for row in 0..3 {
+            for col in 0..4 {
+                r.m[row][col] = self.m[row][0] * rhs.m[0][col] + self.m[row][1] * rhs.m[1][col] +
+                                self.m[row][2] * rhs.m[2][col];
+            }
+            r.m[row][3] += self.m[row][3];
+        }
+        r
+    }
+}
+
+impl Transform {
+    pub fn identity() -> Transform {
+        Transform {
+            m: [[1.0, 0.0, 0.0, 0.0],
+                [0.0, 1.0, 0.0, 0.0],
+                [0.0, 0.0, 1.0, 0.0],
+                [0.0, 0.0, 0.0, 1.0]],
+        }
+    }
+
+    pub fn translation(t: &Vector) -> Transform {
+        let mut r = Transform::identity();
+        r.m[0][3] = t.x;
+        r.m[1][3] = t.y;
+        r.m[2][3] = t.z;
+        r
+    }
+
+    pub fn scale(s: &Vector) -> Transform {
+        let mut r = Transform::identity();
+        r.m[0][0] = s.x;
+        r.m[1][1] = s.y;
+        r.m[2][2] = s.z;
+        r
+    }
+
+    /// All rotations are right-handed and take the angle in radians
+    pub fn rotation_x(angle: RFloat) -> Transform {
+        let (s, c) = angle.sin_cos();
+        let mut r = Transform::identity();
+        r.m[1][1] = c;
+        r.m[1][2] = -s;
+        r.m[2][1] = s;
+        r.m[2][2] = c;
+        r
+    }
+
+    pub fn rotation_y(angle: RFloat) -> Transform {
+        let (s, c) = angle.sin_cos();
+        let mut r = Transform::identity();
+        r.m[0][0] = c;
+        r.m[0][2] = s;
+        r.m[2][0] = -s;
+        r.m[2][2] = c;
+        r
+    }
+
+    pub fn rotation_z(angle: RFloat) -> Transform {
+        let (s, c) = angle.sin_cos();
+        let mut r = Transform::identity();
+        r.m[0][0] = c;
+        r.m[0][1] = -s;
+        r.m[1][0] = s;
+        r.m[1][1] = c;
+        r
+    }
+
+    /// Rotates about x first, then y, then z, taking angles in degrees
+    pub fn from_euler_degrees(x: RFloat, y: RFloat, z: RFloat) -> Transform {
+        Transform::rotation_z(z.to_radians()) * Transform::rotation_y(y.to_radians()) *
+        Transform::rotation_x(x.to_radians())
+    }
+
+    #[inline(always)]
+    pub fn transform_point(&self, p: &Vector) -> Vector {
+        let m = &self.m;
+        Vector {
+            x: m[0][0] * p.x + m[0][1] * p.y + m[0][2] * p.z + m[0][3],
+            y: m[1][0] * p.x + m[1][1] * p.y + m[1][2] * p.z + m[1][3],
+            z: m[2][0] * p.x + m[2][1] * p.y + m[2][2] * p.z + m[2][3],
+        }
+    }
+
+    /// Like `transform_point`, but ignores the translation
+    #[inline(always)]
+    pub fn transform_vector(&self, v: &Vector) -> Vector {
+        let m = &self.m;
+        Vector {
+            x: m[0][0] * v.x + m[0][1] * v.y + m[0][2] * v.z,
+            y: m[1][0] * v.x + m[1][1] * v.y + m[1][2] * v.z,
+            z: m[2][0] * v.x + m[2][1] * v.y + m[2][2] * v.z,
+        }
+    }
+
+    /// Normals need the inverse-transpose to stay perpendicular to their surface.
+    /// The inverse is computed on each call - on hot paths, keep `normal_transform()` around
+    /// and use its `transform_vector()` instead.
+    /// The result is not normalized.
+    pub fn transform_normal(&self, n: &Vector) -> Vector {
+        self.normal_transform().transform_vector(n)
+    }
+
+    /// Returns the transform to apply to normals, see `transform_normal()`
+    pub fn normal_transform(&self) -> Transform {
+        self.inverse().expect("Transform must be invertible to transform normals").transpose()
+    }
+
+    /// Only transposes the upper 3x3 part, as a transposed translation is meaningless
+    /// for affine transforms
+    pub fn transpose(&self) -> Transform {
+        let mut r = Transform::identity();
+        for row in 0..3 {
+            for col in 0..3 {
+                r.m[row][col] = self.m[col][row];
+            }
+        }
+        r
+    }
+
+    /// Returns None if the transform is singular, e.g. has a scale of zero
+    pub fn inverse(&self) -> Option<Transform> {
+        let m = &self.m;
+        // cofactors of the upper 3x3
+        let c00 = m[1][1] * m[2][2] - m[1][2] * m[2][1];
+        let c01 = m[1][2] * m[2][0] - m[1][0] * m[2][2];
+        let c02 = m[1][0] * m[2][1] - m[1][1] * m[2][0];
+        let det = m[0][0] * c00 + m[0][1] * c01 + m[0][2] * c02;
+        if det.abs() < RFloat::EPSILON {
+            return None;
+        }
+        let id = det.recip();
+
+        let mut r = Transform::identity();
+        r.m[0][0] = c00 * id;
+        r.m[0][1] = (m[0][2] * m[2][1] - m[0][1] * m[2][2]) * id;
+        r.m[0][2] = (m[0][1] * m[1][2] - m[0][2] * m[1][1]) * id;
+        r.m[1][0] = c01 * id;
+        r.m[1][1] = (m[0][0] * m[2][2] - m[0][2] * m[2][0]) * id;
+        r.m[1][2] = (m[0][2] * m[1][0] - m[0][0] * m[1][2]) * id;
+        r.m[2][0] = c02 * id;
+        r.m[2][1] = (m[0][1] * m[2][0] - m[0][0] * m[2][1]) * id;
+        r.m[2][2] = (m[0][0] * m[1][1] - m[0][1] * m[1][0]) * id;
+
+        // The inverse translation is the negated translation, run through the inverse 3x3
+        let t = r.transform_vector(&Vector {
+            x: m[0][3],
+            y: m[1][3],
+            z: m[2][3],
+        });
+        r.m[0][3] = -t.x;
+        r.m[1][3] = -t.y;
+        r.m[2][3] = -t.z;
+        Some(r)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::vec::{Vector, RFloat};
+    use std::f32::consts::FRAC_PI_2;
+
+    const EPS: RFloat = 1e-5;
+
+    fn assert_near(a: &Vector, b: &Vector) {
+        assert!((*a - *b).len() < EPS, "{:?} != {:?}", a, b);
+    }
+
+    fn setup_transform() -> Transform {
+        Transform::translation(&Vector {
+            x: 1.0,
+            y: -2.0,
+            z: 3.0,
+        }) * Transform::rotation_x(0.3) * Transform::rotation_y(-1.2) *
+        Transform::scale(&Vector {
+            x: 2.0,
+            y: 0.5,
+            z: 1.5,
+        })
+    }
+
+    #[test]
+    fn inverse() {
+        let t = setup_transform();
+        let id = t * t.inverse().unwrap();
+        for row in 0..4 {
+            for col in 0..4 {
+                let expected = if row == col { 1.0 } else { 0.0 };
+                assert!((id.m[row][col] - expected).abs() < EPS,
+                        "{:?} is not the identity",
+                        id);
+            }
+        }
+
+        let p = Vector {
+            x: 4.0,
+            y: 5.0,
+            z: -6.0,
+        };
+        assert_near(&t.inverse().unwrap().transform_point(&t.transform_point(&p)), &p);
+        assert!(Transform::scale(&Default::default()).inverse().is_none());
+    }
+
+    #[test]
+    fn rotation() {
+        let x = Vector {
+            x: 1.0,
+            y: 0.0,
+            z: 0.0,
+        };
+        let r = Transform::rotation_y(FRAC_PI_2);
+        assert_near(&r.transform_point(&x),
+                    &Vector {
+                        x: 0.0,
+                        y: 0.0,
+                        z: -1.0,
+                    });
+
+        // Vectors ignore translation, points don't
+        let t = Transform::translation(&x) * r;
+        assert_near(&t.transform_vector(&x), &r.transform_vector(&x));
+        assert_near(&t.transform_point(&x),
+                    &Vector {
+                        x: 1.0,
+                        y: 0.0,
+                        z: -1.0,
+                    });
+    }
+
+    #[test]
+    fn euler() {
+        let forward = Vector {
+            x: 0.0,
+            y: 0.0,
+            z: 1.0,
+        };
+        assert_near(&Transform::from_euler_degrees(0.0, 90.0, 0.0).transform_vector(&forward),
+                    &Vector {
+                        x: 1.0,
+                        y: 0.0,
+                        z: 0.0,
+                    });
+        // x is applied before z - the other way around, we would look down -y
+        assert_near(&Transform::from_euler_degrees(90.0, 0.0, 90.0).transform_vector(&forward),
+                    &Vector {
+                        x: 1.0,
+                        y: 0.0,
+                        z: 0.0,
+                    });
+    }
+
+    #[test]
+    fn normal() {
+        // A plane tilted by 45 degrees, squashed along y, must keep a perpendicular normal
+        let t = Transform::scale(&Vector {
+            x: 1.0,
+            y: 0.5,
+            z: 1.0,
+        });
+        let tangent = t.transform_vector(&Vector {
+            x: 1.0,
+            y: 1.0,
+            z: 0.0,
+        });
+        let n = t.transform_normal(&Vector {
+            x: 1.0,
+            y: -1.0,
+            z: 0.0,
+        });
+        assert!(tangent.dot(&n).abs() < EPS);
+    }
+}
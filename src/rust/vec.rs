@@ -1,23 +1,86 @@
 //! A module implementing a Vector type which can be parametized to support different
 //! floating point precision.
 
-use std::ops::{Add, Sub, Mul};
+use std::ops::{Add, Sub, Mul, Div, Neg, AddAssign, SubAssign, MulAssign, Index, IndexMut};
+use std::str::FromStr;
+use std::fmt;
 
+/// The precision used throughout the renderer, and the default of `Vector`
 pub type RFloat = f32;
 
+/// The floating point types a `Vector` can be made of, i.e. `f32` and `f64`
+pub trait Float
+    : Copy
+    + PartialOrd
+    + Default
+    + fmt::Debug
+    + FromStr
+    + Add<Output = Self>
+    + Sub<Output = Self>
+    + Mul<Output = Self>
+    + Div<Output = Self>
+    + Neg<Output = Self> {
+    const ZERO: Self;
+    const ONE: Self;
+    const EPSILON: Self;
+
+    fn sqrt(self) -> Self;
+    fn recip(self) -> Self;
+    fn is_finite(self) -> bool;
+    fn min(self, o: Self) -> Self;
+    fn max(self, o: Self) -> Self;
+}
+
+macro_rules! impl_float {
+    ($($t:ident),*) => {$(
+        impl Float for $t {
+            const ZERO: $t = 0.0;
+            const ONE: $t = 1.0;
+            const EPSILON: $t = $t::EPSILON;
+
+            #[inline(always)]
+            fn sqrt(self) -> $t {
+                $t::sqrt(self)
+            }
+
+            #[inline(always)]
+            fn recip(self) -> $t {
+                $t::recip(self)
+            }
+
+            #[inline(always)]
+            fn is_finite(self) -> bool {
+                $t::is_finite(self)
+            }
+
+            #[inline(always)]
+            fn min(self, o: $t) -> $t {
+                $t::min(self, o)
+            }
+
+            #[inline(always)]
+            fn max(self, o: $t) -> $t {
+                $t::max(self, o)
+            }
+        }
+    )*}
+}
+
+impl_float!(f32, f64);
+
 #[derive(PartialEq, Clone, Copy, Default, Debug)]
-pub struct Vector {
-    pub x: RFloat,
-    pub y: RFloat,
-    pub z: RFloat,
+pub struct Vector<T = RFloat> {
+    pub x: T,
+    pub y: T,
+    pub z: T,
 }
 
-impl Add for Vector {
-    type Output = Vector;
+impl<T: Float> Add for Vector<T> {
+    type Output = Vector<T>;
 
     // Probably it will be optimized to not actually copy self and rhs for each call !
     #[inline(always)]
-    fn add(self, rhs: Vector) -> Vector {
+    fn add(self, rhs: Vector<T>) -> Vector<T> {
         Vector {
             x: self.x + rhs.x,
             y: self.y + rhs.y,
@@ -26,11 +89,11 @@ impl Add for Vector {
     }
 }
 
-impl Sub for Vector {
-    type Output = Vector;
+impl<T: Float> Sub for Vector<T> {
+    type Output = Vector<T>;
 
     #[inline(always)]
-    fn sub(self, rhs: Vector) -> Vector {
+    fn sub(self, rhs: Vector<T>) -> Vector<T> {
         Vector {
             x: self.x - rhs.x,
             y: self.y - rhs.y,
@@ -39,11 +102,11 @@ impl Sub for Vector {
     }
 }
 
-impl Mul for Vector {
-    type Output = Vector;
+impl<T: Float> Mul for Vector<T> {
+    type Output = Vector<T>;
 
     #[inline(always)]
-    fn mul(self, rhs: Vector) -> Vector {
+    fn mul(self, rhs: Vector<T>) -> Vector<T> {
         Vector {
             x: self.x * rhs.x,
             y: self.y * rhs.y,
@@ -52,9 +115,144 @@ impl Mul for Vector {
     }
 }
 
-impl<'a> Vector {
+impl<T: Float> Mul<T> for Vector<T> {
+    type Output = Vector<T>;
+
+    #[inline(always)]
+    fn mul(self, rhs: T) -> Vector<T> {
+        self.mulfed(rhs)
+    }
+}
+
+impl<T: Float> AddAssign for Vector<T> {
+    #[inline(always)]
+    fn add_assign(&mut self, rhs: Vector<T>) {
+        *self = *self + rhs;
+    }
+}
+
+impl<T: Float> SubAssign for Vector<T> {
+    #[inline(always)]
+    fn sub_assign(&mut self, rhs: Vector<T>) {
+        *self = *self - rhs;
+    }
+}
+
+impl<T: Float> MulAssign<T> for Vector<T> {
+    #[inline(always)]
+    fn mul_assign(&mut self, rhs: T) {
+        self.mulf(rhs);
+    }
+}
+
+impl<T: Float> Div<T> for Vector<T> {
+    type Output = Vector<T>;
+
+    /// Multiplies by the reciprocal, which is faster but may differ in the last bit
+    #[inline(always)]
+    fn div(self, rhs: T) -> Vector<T> {
+        self.mulfed(rhs.recip())
+    }
+}
+
+/// Components by axis, i.e. 0 is x, 1 is y and 2 is z
+impl<T> Index<usize> for Vector<T> {
+    type Output = T;
+
+    #[inline(always)]
+    fn index(&self, axis: usize) -> &T {
+        match axis {
+            0 => &self.x,
+            1 => &self.y,
+            2 => &self.z,
+            _ => panic!("Vector axis must be 0, 1 or 2, got {}", axis),
+        }
+    }
+}
+
+impl<T> IndexMut<usize> for Vector<T> {
+    #[inline(always)]
+    fn index_mut(&mut self, axis: usize) -> &mut T {
+        match axis {
+            0 => &mut self.x,
+            1 => &mut self.y,
+            2 => &mut self.z,
+            _ => panic!("Vector axis must be 0, 1 or 2, got {}", axis),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ParseVectorError {
+    /// There were the given amount of comma-separated components, instead of 3
+    WrongArity(usize),
+    /// The given component wasn't a number
+    InvalidComponent(String),
+}
+
+impl fmt::Display for ParseVectorError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ParseVectorError::WrongArity(n) => {
+                write!(f, "Expected 3 comma-separated components like 'x,y,z', got {}", n)
+            }
+            ParseVectorError::InvalidComponent(ref c) => write!(f, "'{}' is not a number", c),
+        }
+    }
+}
+
+/// Parses "x,y,z", e.g. "0,-1.5,2"
+impl<T: Float> FromStr for Vector<T> {
+    type Err = ParseVectorError;
+
+    fn from_str(s: &str) -> Result<Vector<T>, ParseVectorError> {
+        let components: Vec<&str> = s.split(',').map(|c| c.trim()).collect();
+        if components.len() != 3 {
+            return Err(ParseVectorError::WrongArity(components.len()));
+        }
+        let mut v = [T::ZERO; 3];
+        for (c, s) in v.iter_mut().zip(components.iter()) {
+            *c = s.parse().map_err(|_| ParseVectorError::InvalidComponent(s.to_string()))?;
+        }
+        Ok(Vector {
+            x: v[0],
+            y: v[1],
+            z: v[2],
+        })
+    }
+}
+
+impl<T: Float> Vector<T> {
+    pub const ZERO: Vector<T> = Vector {
+        x: T::ZERO,
+        y: T::ZERO,
+        z: T::ZERO,
+    };
+    pub const ONE: Vector<T> = Vector {
+        x: T::ONE,
+        y: T::ONE,
+        z: T::ONE,
+    };
+    /// The positive y axis
+    pub const UP: Vector<T> = Vector {
+        x: T::ZERO,
+        y: T::ONE,
+        z: T::ZERO,
+    };
+
+    #[inline(always)]
+    pub fn new(x: T, y: T, z: T) -> Vector<T> {
+        Vector { x, y, z }
+    }
+
+    /// A vector whose components are all v
     #[inline(always)]
-    pub fn mulfed(&self, m: RFloat) -> Vector {
+    pub fn splat(v: T) -> Vector<T> {
+        Vector { x: v, y: v, z: v }
+    }
+
+    #[inline(always)]
+    pub fn mulfed(&self, m: T) -> Vector<T> {
         Vector {
             x: self.x * m,
             y: self.y * m,
@@ -64,7 +262,7 @@ impl<'a> Vector {
 
     // in ruby, you can use ! to signal it's in-place - here we have to find another way
     #[inline(always)]
-    pub fn mulf(&'a mut self, m: RFloat) -> &'a mut Vector {
+    pub fn mulf(&mut self, m: T) -> &mut Vector<T> {
         self.x = self.x * m;
         self.y = self.y * m;
         self.z = self.z * m;
@@ -74,25 +272,101 @@ impl<'a> Vector {
     // The dot product - should we keep going and use  &ref type as self ?
     // Or just keep copying self around as in sub, add, mul ?
     #[inline(always)]
-    pub fn dot(&self, r: &Vector) -> RFloat {
+    pub fn dot(&self, r: &Vector<T>) -> T {
         self.x * r.x + self.y * r.y + self.z * r.z
     }
 
+    /// The cross product, which is perpendicular to both of us
+    #[inline(always)]
+    pub fn cross(&self, r: &Vector<T>) -> Vector<T> {
+        Vector {
+            x: self.y * r.z - self.z * r.y,
+            y: self.z * r.x - self.x * r.z,
+            z: self.x * r.y - self.y * r.x,
+        }
+    }
+
+    /// Mirrors us at the plane with the given normal, which must be normalized, like a
+    /// direction bouncing off a mirror
+    #[inline(always)]
+    pub fn reflect(&self, normal: &Vector<T>) -> Vector<T> {
+        *self - normal.mulfed((T::ONE + T::ONE) * self.dot(normal))
+    }
+
+    /// The smaller of each of our components and the one of o
+    #[inline(always)]
+    pub fn min(&self, o: &Vector<T>) -> Vector<T> {
+        Vector {
+            x: self.x.min(o.x),
+            y: self.y.min(o.y),
+            z: self.z.min(o.z),
+        }
+    }
+
+    /// The larger of each of our components and the one of o
     #[inline(always)]
-    pub fn len(&self) -> RFloat {
+    pub fn max(&self, o: &Vector<T>) -> Vector<T> {
+        Vector {
+            x: self.x.max(o.x),
+            y: self.y.max(o.y),
+            z: self.z.max(o.z),
+        }
+    }
+
+    /// Each component limited to lo..=hi. NaN components become lo.
+    #[inline(always)]
+    pub fn clamp(&self, lo: T, hi: T) -> Vector<T> {
+        self.max(&Vector::splat(lo)).min(&Vector::splat(hi))
+    }
+
+    #[inline(always)]
+    pub fn len(&self) -> T {
         self.dot(self).sqrt()
     }
 
+    /// The square of len(), which is cheaper and enough to compare lengths
+    #[inline(always)]
+    pub fn len_squared(&self) -> T {
+        self.dot(self)
+    }
+
+    /// The squared distance between us and other, see `len_squared()`
     #[inline(always)]
-    pub fn normalize(&'a mut self) -> &'a mut Vector {
+    pub fn distance_squared(&self, other: &Vector<T>) -> T {
+        (*self - *other).len_squared()
+    }
+
+    #[inline(always)]
+    pub fn normalize(&mut self) -> &mut Vector<T> {
         let len = self.len();
         self.mulf(len.recip())
     }
 
     #[inline(always)]
-    pub fn normalized(&self) -> Vector {
+    pub fn normalized(&self) -> Vector<T> {
         self.mulfed(self.len().recip())
     }
+
+    /// True if each of our components differs from the one of o by at most eps
+    pub fn approx_eq(&self, o: &Vector<T>, eps: T) -> bool {
+        let close = |a: T, b: T| a - b <= eps && b - a <= eps;
+        close(self.x, o.x) && close(self.y, o.y) && close(self.z, o.z)
+    }
+
+    /// True if no component is infinite or NaN
+    pub fn is_finite(&self) -> bool {
+        self.x.is_finite() && self.y.is_finite() && self.z.is_finite()
+    }
+
+    /// Like normalized(), but returns None instead of a vector of NaNs if we are (almost) zero.
+    /// Use it where the input isn't known to be non-zero, like user input.
+    pub fn try_normalized(&self) -> Option<Vector<T>> {
+        let len = self.len();
+        if len < T::EPSILON {
+            return None;
+        }
+        Some(self.mulfed(len.recip()))
+    }
 }
 
 
@@ -104,7 +378,7 @@ mod tests {
 
     #[test]
     fn basics() {
-        let v32 = Vector {
+        let v32: Vector<f32> = Vector {
             x: 5.0,
             y: 4.0,
             z: 0.0,
@@ -118,7 +392,7 @@ mod tests {
             copy.x = 10.0;
         }
 
-        let v64 = Vector {
+        let v64: Vector<f64> = Vector {
             x: 1.0,
             y: 2.0,
             z: 3.0,
@@ -150,8 +424,27 @@ mod tests {
     #[test]
     fn default() {
         let v1: Vector = Default::default();
-        let v2 = <Vector as Default>::default();
+        let v2 = <Vector<f32> as Default>::default();
         assert_eq!(v1, v2);
+        assert_eq!(<Vector<f64> as Default>::default(), Vector::ZERO);
+    }
+
+    #[test]
+    fn precision() {
+        fn unit_len<T: Float>(x: T) -> T {
+            Vector { x, y: T::ONE, z: T::ZERO }.normalized().len()
+        }
+        assert!((unit_len(3.0f32) - 1.0).abs() <= f32::EPSILON);
+        assert!((unit_len(3.0f64) - 1.0).abs() <= f64::EPSILON);
+
+        // A step which f32 can't represent next to 1, but f64 can
+        let (a, b) = (Vector { x: 1.0f32, ..Vector::ZERO }, Vector { x: 1e-9f32, ..Vector::ZERO });
+        assert_eq!((a + b - a).len(), 0.0);
+        let (a, b) = (Vector { x: 1.0f64, ..Vector::ZERO }, Vector { x: 1e-9f64, ..Vector::ZERO });
+        assert!(((a + b - a).len() - 1e-9).abs() < 1e-15);
+
+        assert_eq!("0.1,0,0".parse::<Vector<f64>>().map(|v| v.x), Ok(0.1f64));
+        assert_eq!("0.1,0,0".parse::<Vector<f32>>().map(|v| v.x), Ok(0.1f32));
     }
 
     #[test]
@@ -162,10 +455,231 @@ mod tests {
             z: 0.0,
         };
         assert_eq!(v.len(), 2.0);
-        assert_eq!(v.normalized().len(), 1.0);
+        assert!(v.normalized().approx_eq(&Vector::new(1.0, 0.0, 0.0), 1e-6));
 
         let mut v = v;
-        assert_eq!(v.normalize().len(), 1.0);
+        assert!(v.normalize().approx_eq(&Vector::new(1.0, 0.0, 0.0), 1e-6));
+
+        let v: Vector = Vector::new(1.0, -2.5, 3.0);
+        assert!((v.normalized().len() - 1.0).abs() < 1e-6);
+        assert!(v.normalized().mulfed(v.len()).approx_eq(&v, 1e-6));
+    }
+
+    #[test]
+    fn approx_eq() {
+        let v: Vector = Vector::new(0.0, 0.5, -1e-7);
+        let w = Vector::new(1e-7, 0.5, 0.0);
+        assert!(v.approx_eq(&w, 1e-5));
+        assert!(w.approx_eq(&v, 1e-5));
+        assert!(!v.approx_eq(&w, 1e-9));
+        assert!(v.approx_eq(&v, 0.0));
+        assert!(!v.approx_eq(&Vector::new(0.0, 0.5, 1.0), 1e-5));
+        assert!(!Vector::new(RFloat::NAN, 0.0, 0.0).approx_eq(&Vector::ZERO, 1.0));
+    }
+
+    #[test]
+    fn cross() {
+        let (x, y, z) = (Vector { x: 1.0, ..Vector::ZERO },
+                         Vector { y: 1.0, ..Vector::ZERO },
+                         Vector { z: 1.0, ..Vector::ZERO });
+        assert_eq!(x.cross(&y), z);
+        assert_eq!(y.cross(&x), z.mulfed(-1.0));
+        let v = Vector {
+            x: 1.0,
+            y: 2.0,
+            z: 3.0,
+        };
+        let c = v.cross(&x);
+        assert_eq!((c.dot(&v), c.dot(&x)), (0.0, 0.0));
+    }
+
+    #[test]
+    fn scalar_ops() {
+        let v = Vector {
+            x: 1.0,
+            y: -2.5,
+            z: 3.0,
+        };
+        assert_eq!(v * 2.0, v.mulfed(2.0));
+        assert_eq!(v / 4.0, v.mulfed(0.25));
+        for &d in &[3.0, 7.0, 1e-3, 1e5] {
+            let r = v / d * d;
+            assert!((r - v).len() < v.len() * RFloat::EPSILON * 4.0, "{:?} != {:?}", r, v);
+        }
+    }
+
+    #[test]
+    fn assign_ops() {
+        let (a, b) = (Vector {
+                          x: 1.0,
+                          y: -2.5,
+                          z: 3.0,
+                      },
+                      Vector {
+                          x: 0.1,
+                          y: 0.2,
+                          z: -0.3,
+                      });
+        let mut v = a;
+        v += b;
+        assert_eq!(v, a + b);
+        v -= b;
+        assert_eq!(v, a + b - b);
+        v *= 0.3;
+        let mut expected = a + b - b;
+        expected.mulf(0.3);
+        assert_eq!(v, expected);
+    }
+
+    #[test]
+    fn constructors() {
+        assert_eq!(Vector::new(1.0, -2.5, 3.0),
+                   Vector {
+                       x: 1.0,
+                       y: -2.5,
+                       z: 3.0,
+                   });
+        let v: Vector = Vector::splat(2.0);
+        assert_eq!(v.x, 2.0);
+        assert_eq!(v, Vector::new(2.0, 2.0, 2.0));
+        assert_eq!(Vector::<RFloat>::ONE, Vector::splat(1.0));
+        assert_eq!(Vector::<RFloat>::UP, Vector::new(0.0, 1.0, 0.0));
+        assert_eq!(Vector::<f64>::UP.len(), 1.0);
+    }
+
+    #[test]
+    fn min_max_clamp() {
+        let (a, b): (Vector, Vector) = (Vector::new(1.0, -2.5, 3.0), Vector::new(-1.0, 0.5, 3.5));
+        assert_eq!(a.min(&b), Vector::new(-1.0, -2.5, 3.0));
+        assert_eq!(a.max(&b), Vector::new(1.0, 0.5, 3.5));
+        assert_eq!(a.min(&b), b.min(&a));
+        assert_eq!(a.min(&a), a);
+
+        assert_eq!(a.clamp(0.0, 1.0), Vector::new(1.0, 0.0, 1.0));
+        assert_eq!(b.clamp(-0.5, 0.5), Vector::new(-0.5, 0.5, 0.5));
+        assert_eq!(Vector::new(0.25, 0.5, 0.75).clamp(0.0, 1.0), Vector::new(0.25, 0.5, 0.75));
+        assert_eq!(Vector::new(RFloat::NAN, -1.0, 2.0).clamp(0.0, 1.0), Vector::new(0.0, 0.0, 1.0));
+    }
+
+    #[test]
+    fn index() {
+        let mut v: Vector = Vector {
+            x: 1.0,
+            y: -2.5,
+            z: 3.0,
+        };
+        let expected = [1.0, -2.5, 3.0];
+        for i in 0..3 {
+            assert_eq!(v[i], expected[i]);
+        }
+
+        v[1] = 5.0;
+        assert_eq!(v.y, 5.0);
+        assert_eq!(v,
+                   Vector {
+                       x: 1.0,
+                       y: 5.0,
+                       z: 3.0,
+                   });
+    }
+
+    #[test]
+    #[should_panic(expected = "Vector axis must be 0, 1 or 2, got 3")]
+    fn index_out_of_bounds() {
+        let _ = Vector::<RFloat>::ZERO[3];
+    }
+
+    #[test]
+    fn reflect() {
+        let up = Vector { y: 1.0, ..Vector::ZERO };
+        assert_eq!(up.mulfed(-1.0).reflect(&up), up);
+        let incoming = Vector {
+            x: 1.0,
+            y: -1.0,
+            z: 0.0,
+        };
+        let reflected = incoming.reflect(&up);
+        assert_eq!(reflected,
+                   Vector {
+                       x: 1.0,
+                       y: 1.0,
+                       z: 0.0,
+                   });
+        assert_eq!(reflected.dot(&up), -incoming.dot(&up));
+    }
+
+    #[test]
+    fn from_str() {
+        assert_eq!("1, -2.5,3".parse::<Vector>(),
+                   Ok(Vector {
+                       x: 1.0,
+                       y: -2.5,
+                       z: 3.0,
+                   }));
+        assert_eq!("1,2".parse::<Vector>(),
+                   Err(ParseVectorError::WrongArity(2)));
+        assert_eq!("1,2,3,4".parse::<Vector>(),
+                   Err(ParseVectorError::WrongArity(4)));
+        assert_eq!("1,y,3".parse::<Vector>(),
+                   Err(ParseVectorError::InvalidComponent("y".to_string())));
+    }
+
+    #[test]
+    fn try_normalized() {
+        assert_eq!(Vector::<RFloat>::ZERO.try_normalized(), None);
+        let v = Vector {
+            x: 0.0,
+            y: 0.0,
+            z: RFloat::EPSILON / 2.0,
+        };
+        assert_eq!(v.try_normalized(), None);
+
+        let unit = Vector {
+            x: 0.0,
+            y: 1.0,
+            z: 0.0,
+        };
+        assert_eq!(unit.try_normalized(), Some(unit));
+        assert_eq!(unit.mulfed(3.0).try_normalized(), Some(unit));
+    }
+
+    #[test]
+    fn len_squared() {
+        let v: Vector = Vector {
+            x: 1.0,
+            y: -2.5,
+            z: 3.0,
+        };
+        assert!((v.len_squared() - v.len() * v.len()).abs() < 1e-5);
+        assert_eq!(v.distance_squared(&Vector::ZERO), v.len_squared());
+        assert_eq!(v.distance_squared(&v), 0.0);
+    }
+
+    const NUM_ITERATIONS: usize = 10000;
+
+    fn bench_vectors() -> Vec<Vector> {
+        (0..NUM_ITERATIONS)
+            .map(|i| {
+                Vector {
+                    x: i as RFloat,
+                    y: 1.0,
+                    z: -0.5,
+                }
+            })
+            .collect()
+    }
+
+    #[bench]
+    fn bench_len(b: &mut test::Bencher) {
+        let vectors = bench_vectors();
+        b.iter(|| vectors.iter().map(|v| test::black_box(v).len()).sum::<RFloat>());
+        b.bytes = NUM_ITERATIONS as u64;
+    }
 
+    #[bench]
+    fn bench_len_squared(b: &mut test::Bencher) {
+        let vectors = bench_vectors();
+        b.iter(|| vectors.iter().map(|v| test::black_box(v).len_squared()).sum::<RFloat>());
+        b.bytes = NUM_ITERATIONS as u64;
     }
 }